@@ -0,0 +1,269 @@
+//! picking a game off the card
+//!
+//! directories are never read into memory as a whole. the listing is walked again whenever it's
+//! drawn, which only has to get as far as the page the cursor is on
+
+use core::fmt::{self, Write};
+
+use ape_fatfs::dir::Dir;
+use ape_fatfs::dir_entry::{DirEntry, FileAttributes};
+use ape_fatfs::error::Error;
+use ape_fatfs::fs::{OemCpConverter, ReadWriteSeek};
+use ape_fatfs::time::TimeProvider;
+use log::warn;
+
+use crate::emulator::Emulator;
+use crate::halfwidth::{COLUMNS, ROWS};
+use crate::multiboot;
+use crate::path::PathBuf;
+
+/// a long name is up to 255 ucs-2 units, each of which can be 3 bytes of utf-8
+pub const MAX_NAME: usize = 768;
+/// the path goes on the first row and the keys on the last, with the listing between
+const PAGE: usize = ROWS - 2;
+/// how many directories deep we remember the cursor for, to put it back when going up
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    /// go into a directory, or pick a game
+    Open,
+    /// go up a directory
+    Back,
+}
+
+/// the game that was picked, and its path from the root
+pub struct Selected<'a, IO: ReadWriteSeek, TP, OCC> {
+    pub entry: DirEntry<'a, IO, TP, OCC>,
+    pub path: PathBuf,
+}
+
+pub struct Browser<'a, IO: ReadWriteSeek, TP, OCC> {
+    root: Dir<'a, IO, TP, OCC>,
+    dir: Dir<'a, IO, TP, OCC>,
+    /// the directory we're in, which is empty for the root
+    path: PathBuf,
+    /// where the cursor was in each directory above this one
+    parents: [usize; MAX_DEPTH],
+    depth: usize,
+    cursor: usize,
+    /// how many entries are shown in this directory
+    len: usize,
+    /// what went wrong with the last action, shown in place of the keys until the next one
+    problem: Option<&'static str>,
+}
+
+impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Browser<'a, IO, TP, OCC> {
+    pub fn new(root: Dir<'a, IO, TP, OCC>) -> Self {
+        let mut browser = Self {
+            dir: root.clone(),
+            root,
+            path: PathBuf::new(),
+            parents: [0; MAX_DEPTH],
+            depth: 0,
+            cursor: 0,
+            len: 0,
+            problem: None,
+        };
+        browser.len = browser.entries().count();
+        browser
+    }
+
+    /// move the cursor or change directory, returning the game if one was picked
+    pub fn handle(&mut self, action: Action) -> Option<Selected<'a, IO, TP, OCC>> {
+        let last = self.len.saturating_sub(1);
+        self.problem = None;
+        match action {
+            Action::Up => self.cursor = self.cursor.checked_sub(1).unwrap_or(last),
+            Action::Down => {
+                self.cursor = if self.cursor < last {
+                    self.cursor + 1
+                } else {
+                    0
+                }
+            }
+            Action::PageUp => self.cursor = self.cursor.saturating_sub(PAGE),
+            Action::PageDown => self.cursor = (self.cursor + PAGE).min(last),
+            Action::Open => {
+                let entry = self.entries().nth(self.cursor)?;
+                let mut name = [0; MAX_NAME];
+                let mut path = self.path.clone();
+                if path.push(entry_name(&entry, &mut name)).is_none() {
+                    warn!("path is too long");
+                    self.problem = Some("path is too long");
+                    return None;
+                }
+
+                if !entry.is_dir() {
+                    return Some(Selected { entry, path });
+                }
+                if let Err(e) = self.enter(path) {
+                    warn!("couldn't open directory: {:?}", e);
+                    self.problem = Some("couldn't open directory");
+                }
+            }
+            Action::Back => self.leave(),
+        }
+        None
+    }
+
+    /// the path of the game under the cursor, unless it's on a directory
+    pub fn highlighted(&self) -> Option<PathBuf> {
+        let entry = self.entries().nth(self.cursor)?;
+        if entry.is_dir() {
+            return None;
+        }
+        let mut name = [0; MAX_NAME];
+        let mut path = self.path.clone();
+        path.push(entry_name(&entry, &mut name))?;
+        Some(path)
+    }
+
+    fn enter(&mut self, path: PathBuf) -> Result<(), Error<IO::Error>> {
+        self.dir = self.root.open_dir(path.as_str())?;
+        self.path = path;
+        if let Some(parent) = self.parents.get_mut(self.depth) {
+            *parent = self.cursor;
+        }
+        self.depth += 1;
+        self.cursor = 0;
+        self.len = self.entries().count();
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        let mut path = self.path.clone();
+        if !path.pop() {
+            return;
+        }
+        let dir = if path.as_str().is_empty() {
+            Ok(self.root.clone())
+        } else {
+            self.root.open_dir(path.as_str())
+        };
+        match dir {
+            Ok(dir) => {
+                self.dir = dir;
+                self.path = path;
+                self.depth -= 1;
+                self.len = self.entries().count();
+                self.cursor = self.parents.get(self.depth).copied().unwrap_or(0);
+                self.cursor = self.cursor.min(self.len.saturating_sub(1));
+            }
+            Err(e) => {
+                warn!("couldn't open directory: {:?}", e);
+                self.problem = Some("couldn't open directory");
+            }
+        }
+    }
+
+    /// directories and anything we know how to run
+    fn entries(&self) -> impl Iterator<Item = DirEntry<'a, IO, TP, OCC>> {
+        self.dir.iter().filter_map(Result::ok).filter(|entry| {
+            let short = entry.short_file_name_as_bytes();
+            if short == b"."
+                || short == b".."
+                || entry.attributes().contains(FileAttributes::HIDDEN)
+            {
+                return false;
+            }
+            let mut name = [0; MAX_NAME];
+            entry.is_dir() || is_game(entry_name(entry, &mut name))
+        })
+    }
+
+    /// draw the whole screen, which should be blank to start with
+    pub fn draw<W: Write>(&self, out: &mut W) -> fmt::Result {
+        // bright white on blue
+        write!(out, "\x1b[97;44m/")?;
+        write_padded(out, self.path.as_str(), COLUMNS - 1)?;
+        writeln!(out, "\x1b[m")?;
+
+        let top = self.cursor - self.cursor % PAGE;
+        let mut rows = 0;
+        let mut name = [0; MAX_NAME];
+        for (i, entry) in self.entries().enumerate().skip(top).take(PAGE) {
+            if i == self.cursor {
+                // black on white
+                write!(out, "\x1b[30;47m")?;
+            }
+            let name = entry_name(&entry, &mut name);
+            if entry.is_dir() {
+                let shown = write_truncated(out, name, COLUMNS - 1)?;
+                write!(out, "/")?;
+                write_padded(out, "", COLUMNS - 1 - shown)?;
+            } else {
+                write_padded(out, name, COLUMNS)?;
+            }
+            writeln!(out, "\x1b[m")?;
+            rows += 1;
+        }
+        if self.len == 0 {
+            writeln!(out, "\x1b[90mnothing to run here\x1b[m")?;
+            rows += 1;
+        }
+        for _ in rows..PAGE {
+            writeln!(out)?;
+        }
+
+        // no newline so nothing scrolls. a problem goes here, since the warning it logged is cleared
+        if let Some(problem) = self.problem {
+            // bright yellow, like a warning in the log
+            return write!(out, "\x1b[93m{}, right: log\x1b[m", problem);
+        }
+        // bright black
+        write!(
+            out,
+            "\x1b[90m{}/{} a:open b:back l/r:page st:clock sel:cheats right:log\x1b[m",
+            (self.cursor + 1).min(self.len),
+            self.len
+        )
+    }
+}
+
+/// whether `name` is a game, multiboot image or something an emulator can run
+pub fn is_game(name: &str) -> bool {
+    let is_gba = name
+        .rsplit_once('.')
+        .is_some_and(|(_, ext)| ext.eq_ignore_ascii_case("gba"));
+    is_gba || multiboot::is_multiboot(name) || Emulator::for_path(name).is_some()
+}
+
+/// the entry's long name if it has one, or its short name
+pub fn entry_name<'b, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter>(
+    entry: &DirEntry<IO, TP, OCC>,
+    buf: &'b mut [u8; MAX_NAME],
+) -> &'b str {
+    let len = entry
+        .long_file_name_as_ucs2_units()
+        .and_then(|units| ucs2::decode(units, buf).ok())
+        .unwrap_or_else(|| {
+            let short = entry.short_file_name_as_bytes();
+            buf[..short.len()].copy_from_slice(short);
+            short.len()
+        });
+    core::str::from_utf8(&buf[..len]).unwrap_or("?")
+}
+
+/// write at most `width` chars of `text`, returning how many were written
+fn write_truncated<W: Write>(out: &mut W, text: &str, width: usize) -> Result<usize, fmt::Error> {
+    let mut shown = 0;
+    for c in text.chars().take(width) {
+        out.write_char(c)?;
+        shown += 1;
+    }
+    Ok(shown)
+}
+
+/// write `text` cut down or padded out to exactly `width` chars
+pub fn write_padded<W: Write>(out: &mut W, text: &str, width: usize) -> fmt::Result {
+    let shown = write_truncated(out, text, width)?;
+    for _ in shown..width {
+        out.write_char(' ')?;
+    }
+    Ok(())
+}
@@ -0,0 +1,739 @@
+//! action replay and codebreaker cheats, from a retroarch style `.cht` next to the game
+//!
+//! each cheat is a `cheatN_desc`, `cheatN_code` and `cheatN_enable` line, and which ones are
+//! enabled is kept in the same file. the codes of the enabled ones are turned into a list of
+//! simple ops, which the interrupt hook runs through every vblank
+//!
+//! codebreaker v7 encrypts every line after a `9` code, with keys worked out from that code. the
+//! keys carry on into the cheats after it, so disabled cheats are still read for their `9` codes
+
+use ape_fatfs::dir::Dir;
+use ape_fatfs::error::Error;
+use ape_fatfs::fs::{OemCpConverter, ReadWriteSeek};
+use ape_fatfs::time::TimeProvider;
+use embedded_io::blocking::{Read, Write};
+use log::warn;
+
+use crate::path::PathBuf;
+
+pub const MAX_CHEATS: usize = 32;
+pub const MAX_OPS: usize = 128;
+/// how much of a cheat's name is kept
+const NAME_LEN: usize = 24;
+/// longest line in a `.cht`, which is usually a code
+const MAX_LINE: usize = 512;
+
+/// how big an op is in the game
+pub const OP_LEN: usize = 8;
+
+/// action replay v3 encrypts every line with tea, using these as the key
+const AR_SEEDS: [u32; 4] = [0x7aa9648f, 0x7fae6994, 0xc0efaad5, 0x42712c57];
+const TEA_DELTA: u32 = 0x9e3779b9;
+/// bits in a codebreaker line, which its encryption swaps around
+const CB_BITS: usize = 48;
+
+#[derive(Debug)]
+pub enum CheatError<E> {
+    Fs(Error<E>),
+    /// a line is longer than we can hold
+    LineTooLong,
+    TooManyCheats,
+    PathTooLong,
+}
+
+impl<E> From<Error<E>> for CheatError<E> {
+    fn from(value: Error<E>) -> Self {
+        Self::Fs(value)
+    }
+}
+
+/// why a cheat's code couldn't be used, which only leaves that cheat out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeError {
+    /// not hex, or not split up like either kind of code
+    Malformed,
+    /// a kind of code we don't run, with its first half
+    Unsupported(u32),
+    TooManyOps,
+}
+
+/// what an op does with its address and value, which the hook checks in this order
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    End = 0,
+    Write8,
+    Write16,
+    Write32,
+    Or16,
+    And16,
+    /// skip the next op unless the value matches
+    IfEq8,
+    IfEq16,
+    IfEq32,
+    /// skip the next op if the value matches
+    IfNe8,
+    IfNe16,
+    IfNe32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Op {
+    pub kind: OpKind,
+    /// below 0x10000000, since the kind goes in the top nibble
+    pub addr: u32,
+    pub value: u32,
+}
+
+impl Op {
+    pub const END: Self = Self {
+        kind: OpKind::End,
+        addr: 0,
+        value: 0,
+    };
+
+    pub fn encode(&self) -> [u8; OP_LEN] {
+        let mut bytes = [0; OP_LEN];
+        bytes[..4].copy_from_slice(&((self.kind as u32) << 28 | self.addr).to_le_bytes());
+        bytes[4..].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+
+    fn is_condition(&self) -> bool {
+        self.kind as u32 >= OpKind::IfEq8 as u32
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Cheat {
+    name: [u8; NAME_LEN],
+    name_len: usize,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    const EMPTY: Self = Self {
+        name: [0; NAME_LEN],
+        name_len: 0,
+        enabled: false,
+    };
+
+    pub fn name(&self) -> &str {
+        // only ever cut on a char boundary
+        unsafe { core::str::from_utf8_unchecked(&self.name[..self.name_len]) }
+    }
+
+    fn set_name(&mut self, name: &str) {
+        let mut len = name.len().min(NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        self.name_len = len;
+    }
+}
+
+/// a game's cheats, and the ops for the enabled ones
+pub struct Cheats {
+    cheats: [Cheat; MAX_CHEATS],
+    len: usize,
+    ops: [Op; MAX_OPS],
+    ops_len: usize,
+    /// what the codebreaker lines are encrypted with, once there's been a `9` code
+    cb_keys: Option<CbKeys>,
+}
+
+impl Cheats {
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats[..self.len].iter()
+    }
+
+    /// changing whether one is enabled doesn't change the ops, which are only worked out on load
+    #[cfg(not(feature = "host"))]
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Cheat> {
+        self.cheats[..self.len].iter_mut()
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops[..self.ops_len]
+    }
+
+    /// add the ops for `code`, or none of them if any of it can't be used
+    fn compile(&mut self, code: &str) -> Result<(), CodeError> {
+        let start = self.ops_len;
+        let result = self.compile_lines(code);
+        if result.is_err() {
+            self.ops_len = start;
+        }
+        result
+    }
+
+    fn compile_lines(&mut self, code: &str) -> Result<(), CodeError> {
+        let first_op = self.ops_len;
+        let mut tokens = code
+            .split(|c: char| c == '+' || c == ':' || c == ',' || c.is_ascii_whitespace())
+            .filter(|token| !token.is_empty());
+        while let Some(token) = tokens.next() {
+            let (first, second) = match token.len() {
+                8 => (token, tokens.next().ok_or(CodeError::Malformed)?),
+                12 | 16 => token.split_at(8),
+                _ => return Err(CodeError::Malformed),
+            };
+            let first = hex(first)?;
+            let second_len = second.len();
+            let second = hex(second)?;
+
+            // the condition only covers the line after it, which has to turn into exactly one op
+            // that isn't another condition, since skipping that would leave the op after it to run
+            let condition = self.ops_len > first_op && self.ops[self.ops_len - 1].is_condition();
+            let start = self.ops_len;
+            match second_len {
+                8 => {
+                    let (addr, value) = decrypt_ar(first, second);
+                    self.action_replay(addr, value)?;
+                }
+                4 => {
+                    let (code, value) = match &self.cb_keys {
+                        Some(keys) => keys.decrypt(first, second),
+                        None => (first, second),
+                    };
+                    self.codebreaker(code, value)?;
+                }
+                _ => return Err(CodeError::Malformed),
+            }
+            if condition && (self.ops_len - start != 1 || self.ops[start].is_condition()) {
+                return Err(CodeError::Unsupported(first));
+            }
+        }
+
+        // a condition at the end would skip the next cheat's first op, or the end of the list
+        if self.ops_len > first_op && self.ops[self.ops_len - 1].is_condition() {
+            return Err(CodeError::Malformed);
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, kind: OpKind, addr: u32, value: u32) -> Result<(), CodeError> {
+        if addr >> 28 != 0 {
+            return Err(CodeError::Unsupported(addr));
+        }
+        let op = self
+            .ops
+            .get_mut(self.ops_len)
+            .ok_or(CodeError::TooManyOps)?;
+        *op = Op { kind, addr, value };
+        self.ops_len += 1;
+        Ok(())
+    }
+
+    /// a decrypted action replay v3 line, with the kind of code in the top byte
+    fn action_replay(&mut self, code: u32, value: u32) -> Result<(), CodeError> {
+        // the region goes in the middle nibble of the address
+        let addr = (code & 0x00f00000) << 4 | (code & 0x0003ffff);
+        match code >> 24 {
+            // padding
+            0x00 if code == 0 && value == 0 => Ok(()),
+            // the master code, which says where the device would hook the game
+            0xc4 | 0xc5 => Ok(()),
+            // writes fill as many more bytes or halfwords as the top of the value says
+            0x00 => {
+                (0..=value >> 8).try_for_each(|i| self.push(OpKind::Write8, addr + i, value & 0xff))
+            }
+            0x02 => (0..=value >> 16)
+                .try_for_each(|i| self.push(OpKind::Write16, addr + i * 2, value & 0xffff)),
+            0x04 => self.push(OpKind::Write32, addr, value),
+            0x08 => self.push(OpKind::IfEq8, addr, value & 0xff),
+            0x0a => self.push(OpKind::IfEq16, addr, value & 0xffff),
+            0x0c => self.push(OpKind::IfEq32, addr, value),
+            0x10 => self.push(OpKind::IfNe8, addr, value & 0xff),
+            0x12 => self.push(OpKind::IfNe16, addr, value & 0xffff),
+            0x14 => self.push(OpKind::IfNe32, addr, value),
+            _ => Err(CodeError::Unsupported(code)),
+        }
+    }
+
+    /// a codebreaker line, with the kind of code in the top nibble
+    fn codebreaker(&mut self, code: u32, value: u32) -> Result<(), CodeError> {
+        let addr = code & 0x0fffffff;
+        match code >> 28 {
+            // the master codes, which identify the game and say where to hook it
+            0x0 | 0x1 => Ok(()),
+            0x2 => self.push(OpKind::Or16, addr, value),
+            0x3 => self.push(OpKind::Write8, addr, value & 0xff),
+            0x6 => self.push(OpKind::And16, addr, value),
+            0x7 => self.push(OpKind::IfEq16, addr, value),
+            0x8 => self.push(OpKind::Write16, addr, value),
+            // the lines after this one are encrypted
+            0x9 => {
+                self.cb_keys = Some(CbKeys::new(code, value));
+                Ok(())
+            }
+            0xa => self.push(OpKind::IfNe16, addr, value),
+            _ => Err(CodeError::Unsupported(code)),
+        }
+    }
+}
+
+/// undo action replay v3's encryption of a line
+fn decrypt_ar(mut addr: u32, mut value: u32) -> (u32, u32) {
+    let mut sum = TEA_DELTA.wrapping_mul(32);
+    for _ in 0..32 {
+        value = value.wrapping_sub(
+            (addr << 4).wrapping_add(AR_SEEDS[2])
+                ^ addr.wrapping_add(sum)
+                ^ (addr >> 5).wrapping_add(AR_SEEDS[3]),
+        );
+        addr = addr.wrapping_sub(
+            (value << 4).wrapping_add(AR_SEEDS[0])
+                ^ value.wrapping_add(sum)
+                ^ (value >> 5).wrapping_add(AR_SEEDS[1]),
+        );
+        sum = sum.wrapping_sub(TEA_DELTA);
+    }
+    (addr, value)
+}
+
+/// codebreaker v7's encryption, which swaps the bits of each line around and xors them with
+/// numbers from its random number generator, seeded from the `9` code
+#[derive(Clone, Copy)]
+struct CbKeys {
+    /// each bit, from the last down, is swapped with the one this says
+    swaps: [u8; CB_BITS],
+    /// xored with the line before and after the bits are mixed into each other
+    seeds: [u32; 4],
+    /// the `9` code's first half, which the bits are mixed with
+    master: u32,
+}
+
+impl CbKeys {
+    fn new(code: u32, value: u32) -> Self {
+        let mut state = value & 0xff ^ 0x1111;
+        let mut swaps = [0; CB_BITS];
+        for (i, swap) in swaps.iter_mut().enumerate() {
+            *swap = i as u8;
+        }
+        for _ in 0..0x50 {
+            let a = cb_random(&mut state) as usize % CB_BITS;
+            let b = cb_random(&mut state) as usize % CB_BITS;
+            swaps.swap(a, b);
+        }
+
+        // the generator is run on from its own output, rather than just its state
+        let mut seeds = [0; 4];
+        let mut state = 0x4efad1c3;
+        for _ in 0..code >> 24 & 0xf {
+            state = cb_random(&mut state);
+        }
+        seeds[2] = cb_random(&mut state);
+        seeds[3] = cb_random(&mut state);
+        let mut state = value >> 8 & 0xff ^ 0xf254;
+        for _ in 0..value >> 8 & 0xff {
+            state = cb_random(&mut state);
+        }
+        seeds[0] = cb_random(&mut state);
+        seeds[1] = cb_random(&mut state);
+
+        Self {
+            swaps,
+            seeds,
+            master: code,
+        }
+    }
+
+    /// undo the encryption of a line, which is 48 bits of code then value, big endian
+    fn decrypt(&self, code: u32, value: u32) -> (u32, u32) {
+        let mut line = cb_line(code, value);
+        for i in (0..CB_BITS).rev() {
+            let j = self.swaps[i] as usize;
+            let (a, b) = (line[i / 8] >> (i % 8) & 1, line[j / 8] >> (j % 8) & 1);
+            line[i / 8] = line[i / 8] & !(1 << (i % 8)) | b << (i % 8);
+            line[j / 8] = line[j / 8] & !(1 << (j % 8)) | a << (j % 8);
+        }
+        let (code, value) = cb_split(line);
+
+        let mut line = cb_line(code ^ self.seeds[0], value ^ self.seeds[1]);
+        let [_, _, high, low] = self.master.to_be_bytes();
+        for i in 0..line.len() - 1 {
+            line[i] ^= high ^ line[i + 1];
+        }
+        line[5] ^= high;
+        for i in (0..line.len()).rev() {
+            line[i] ^= low ^ if i > 0 { line[i - 1] } else { 0 };
+        }
+        let (code, value) = cb_split(line);
+
+        (code ^ self.seeds[2], (value ^ self.seeds[3]) & 0xffff)
+    }
+}
+
+/// codebreaker's random number generator, which takes 32 bits from three steps of an lcg
+fn cb_random(state: &mut u32) -> u32 {
+    let mut next = || {
+        *state = state.wrapping_mul(0x41c64e6d).wrapping_add(0x3039);
+        *state >> 16
+    };
+    let (a, b, c) = (next(), next(), next());
+    a << 30 | (b & 0x7fff) << 15 | c & 0x7fff
+}
+
+fn cb_line(code: u32, value: u32) -> [u8; 6] {
+    let mut line = [0; 6];
+    line[..4].copy_from_slice(&code.to_be_bytes());
+    line[4..].copy_from_slice(&(value as u16).to_be_bytes());
+    line
+}
+
+fn cb_split(line: [u8; 6]) -> (u32, u32) {
+    (
+        u32::from_be_bytes([line[0], line[1], line[2], line[3]]),
+        u16::from_be_bytes([line[4], line[5]]) as u32,
+    )
+}
+
+fn hex(digits: &str) -> Result<u32, CodeError> {
+    u32::from_str_radix(digits, 16).map_err(|_| CodeError::Malformed)
+}
+
+/// which cheat a line is about and what about it, with the quotes taken off the value
+fn parse_line(line: &str) -> Option<(usize, &str, &str)> {
+    let (key, value) = line.split_once('=')?;
+    let (index, field) = key.trim().strip_prefix("cheat")?.split_once('_')?;
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+    Some((index.parse().ok()?, field, value))
+}
+
+/// call `f` with each line of `file`, without the line ending
+fn for_each_line<R: Read, E>(
+    file: &mut R,
+    mut f: impl FnMut(&str) -> Result<(), CheatError<E>>,
+) -> Result<(), CheatError<E>>
+where
+    CheatError<E>: From<R::Error>,
+{
+    let mut buf = [0u8; 512];
+    let mut line = [0u8; MAX_LINE];
+    let mut len = 0;
+    loop {
+        let read = file.read(&mut buf)?;
+        for &byte in &buf[..read] {
+            if byte == b'\n' {
+                f(line_str(&line[..len]))?;
+                len = 0;
+            } else if len < line.len() {
+                line[len] = byte;
+                len += 1;
+            } else {
+                return Err(CheatError::LineTooLong);
+            }
+        }
+        if read == 0 {
+            break;
+        }
+    }
+
+    if len > 0 {
+        f(line_str(&line[..len]))?;
+    }
+    Ok(())
+}
+
+/// anything that isn't utf-8 can't be a line we care about
+fn line_str(line: &[u8]) -> &str {
+    core::str::from_utf8(line)
+        .unwrap_or("")
+        .trim_end_matches('\r')
+}
+
+/// read the cheats for `rom_path`, if it has any
+pub fn load<IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter>(
+    root: &Dir<IO, TP, OCC>,
+    rom_path: &str,
+) -> Result<Option<Cheats>, CheatError<IO::Error>> {
+    let path = PathBuf::for_rom(rom_path, "cht").ok_or(CheatError::PathTooLong)?;
+    let mut file = match root.open_file(path.as_str()) {
+        Ok(file) => file,
+        Err(Error::NotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut cheats = Cheats {
+        cheats: [Cheat::EMPTY; MAX_CHEATS],
+        len: 0,
+        ops: [Op::END; MAX_OPS],
+        ops_len: 0,
+        cb_keys: None,
+    };
+
+    // the codes can come before the lines saying whether they're enabled, so look at those first
+    for_each_line(&mut file, |line| {
+        let Some((index, field, value)) = parse_line(line) else {
+            return Ok(());
+        };
+        let cheat = cheats
+            .cheats
+            .get_mut(index)
+            .ok_or(CheatError::TooManyCheats)?;
+        match field {
+            "desc" => cheat.set_name(value),
+            "enable" => {
+                cheat.enabled = value == "true";
+                // a leftover enable line doesn't make a cheat
+                return Ok(());
+            }
+            "code" => (),
+            _ => return Ok(()),
+        }
+        cheats.len = cheats.len.max(index + 1);
+        Ok(())
+    })?;
+
+    let mut file = root.open_file(path.as_str())?;
+    for_each_line(&mut file, |line| {
+        if let Some((index, "code", code)) = parse_line(line) {
+            let cheat = cheats.cheats[index];
+            // a disabled cheat's ops are dropped, but a `9` code in it still sets up the keys
+            let start = cheats.ops_len;
+            let result = cheats.compile(code);
+            if !cheat.enabled {
+                cheats.ops_len = start;
+            } else if let Err(e) = result {
+                warn!("leaving out cheat {}: {:?}", cheat.name(), e);
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(Some(cheats))
+}
+
+/// turn a cheat on or off in the `.cht` for `rom_path`, so it stays that way next time
+pub fn set_enabled<IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter>(
+    root: &Dir<IO, TP, OCC>,
+    rom_path: &str,
+    index: usize,
+    enabled: bool,
+) -> Result<(), CheatError<IO::Error>> {
+    let path = PathBuf::for_rom(rom_path, "cht").ok_or(CheatError::PathTooLong)?;
+    let temp = PathBuf::for_rom(rom_path, "cht.tmp").ok_or(CheatError::PathTooLong)?;
+    let value: &[u8] = if enabled { b"true" } else { b"false" };
+
+    // write out a copy with the one line changed, then swap it in
+    let mut file = root.open_file(path.as_str())?;
+    let mut copy = root.create_file(temp.as_str())?;
+    copy.truncate()?;
+    let mut found = false;
+    for_each_line(&mut file, |line| {
+        match parse_line(line) {
+            Some((i, "enable", _)) if i == index => {
+                write_enable(&mut copy, index, value)?;
+                found = true;
+            }
+            _ => {
+                copy.write_all(line.as_bytes())?;
+                copy.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    })?;
+    if !found {
+        write_enable(&mut copy, index, value)?;
+    }
+    copy.flush()?;
+    drop((file, copy));
+
+    root.remove(path.as_str())?;
+    root.rename(temp.as_str(), root, path.as_str())?;
+    Ok(())
+}
+
+fn write_enable<W: Write>(file: &mut W, index: usize, value: &[u8]) -> Result<(), W::Error> {
+    file.write_all(b"cheat")?;
+    file.write_all(itoa::Buffer::new().format(index).as_bytes())?;
+    file.write_all(b"_enable = ")?;
+    file.write_all(value)?;
+    file.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use ape_fatfs::fs::{FileSystem, FsOptions};
+    use embedded_io::blocking::Write;
+
+    use super::{
+        cb_random, load, set_enabled, CbKeys, Cheat, Cheats, CodeError, Op, OpKind, MAX_CHEATS,
+        MAX_OPS,
+    };
+    use crate::disk::RamDisk;
+    use crate::fixture::fat_image;
+    use crate::fs::{BufferedIo, Pages};
+    use crate::partition::PartitionTable;
+
+    fn cheats() -> Cheats {
+        Cheats {
+            cheats: [Cheat::EMPTY; MAX_CHEATS],
+            len: 0,
+            ops: [Op::END; MAX_OPS],
+            ops_len: 0,
+            cb_keys: None,
+        }
+    }
+
+    #[test]
+    fn condition_covers_one_op() {
+        let mut cheats = cheats();
+        cheats.compile("72000000 0001\n82000002 0002").unwrap();
+        assert_eq!(
+            cheats
+                .ops()
+                .iter()
+                .map(|op| op.kind)
+                .collect::<std::vec::Vec<_>>(),
+            [OpKind::IfEq16, OpKind::Write16]
+        );
+    }
+
+    #[test]
+    fn trailing_condition_is_left_out() {
+        let mut cheats = cheats();
+        cheats.compile("82000000 0001").unwrap();
+        assert_eq!(
+            cheats.compile("82000002 0002\n72000000 0001"),
+            Err(CodeError::Malformed)
+        );
+        // which leaves nothing of it behind for the next cheat's first op to be skipped by
+        assert_eq!(cheats.ops().len(), 1);
+        cheats.compile("82000004 0003").unwrap();
+        assert_eq!(cheats.ops().len(), 2);
+    }
+
+    #[test]
+    fn conditions_dont_nest() {
+        let mut cheats = cheats();
+        assert_eq!(
+            cheats.compile("72000000 0001\na2000002 0002\n82000004 0003"),
+            Err(CodeError::Unsupported(0xa2000002))
+        );
+        assert!(cheats.ops().is_empty());
+    }
+
+    // worked out with a line by line port of vba-m's decryption, rather than from a real device
+    #[test]
+    fn codebreaker_keys() {
+        let mut state = 0;
+        let random = [(); 3].map(|()| cb_random(&mut state));
+        assert_eq!(random, [0x29ee2704, 0x46d5421f, 0x668eaf5a]);
+
+        let keys = CbKeys::new(0x9f3c2a10, 0x07c5);
+        assert_eq!(
+            keys.swaps,
+            [
+                36, 4, 2, 5, 33, 19, 37, 26, 15, 0, 20, 16, 31, 13, 17, 32, 3, 23, 38, 43, 10, 25,
+                34, 8, 42, 1, 29, 6, 9, 47, 45, 14, 40, 35, 22, 21, 39, 30, 27, 44, 11, 12, 24, 41,
+                7, 28, 46, 18
+            ]
+        );
+        assert_eq!(keys.seeds, [0x59fd1921, 0xf25397fa, 0xf4555f6d, 0xffe54cb9]);
+        assert_eq!(keys.decrypt(0x1234abcd, 0x5678), (0xacc192eb, 0x6880));
+        assert_eq!(keys.decrypt(0, 0), (0x6a0593f3, 0x873e));
+        assert_eq!(keys.decrypt(0xdeadbeef, 0xcafe), (0x586cd13e, 0xf445));
+    }
+
+    #[test]
+    fn codebreaker_encrypted_lines() {
+        let mut cheats = cheats();
+        cheats
+            .compile("82000000 0001\n9f3c2a10 07c5\n36164741 0a1b\na1d9a6b8 07e6")
+            .unwrap();
+        assert_eq!(
+            cheats.ops(),
+            [
+                Op {
+                    kind: OpKind::Write16,
+                    addr: 0x2000000,
+                    value: 0x0001
+                },
+                Op {
+                    kind: OpKind::Write16,
+                    addr: 0x2000010,
+                    value: 0x1234
+                },
+                Op {
+                    kind: OpKind::Write8,
+                    addr: 0x2000020,
+                    value: 0x63
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn load_and_enable() {
+        let mut data = fat_image();
+        let mut disk = RamDisk::new(&mut data);
+        let info = *PartitionTable::read(&mut disk)
+            .unwrap()
+            .default_partition()
+            .unwrap();
+        let mut pages = Pages::new();
+        let io = BufferedIo::<512, 2048, 4, _>::new(info.open(&mut disk), &mut pages);
+        let fs = FileSystem::new(io, FsOptions::new()).unwrap();
+        let root = fs.root_dir();
+
+        // the master code is off, but the codes after it are still encrypted with its keys
+        let mut file = root.create_file("GAME.cht").unwrap();
+        file.write_all(
+            b"cheats = 3\r\n\
+              cheat0_desc = \"Master Code\"\r\n\
+              cheat0_code = \"9f3c2a10+07c5\"\r\n\
+              cheat0_enable = false\r\n\
+              cheat1_desc = \"Max Money\"\r\n\
+              cheat1_code = \"36164741+0a1b\"\r\n\
+              cheat1_enable = true\r\n\
+              cheat2_desc = \"Infinite Lives\"\r\n\
+              cheat2_code = \"a1d9a6b8+07e6\"\r\n",
+        )
+        .unwrap();
+        file.flush().unwrap();
+        drop(file);
+
+        let cheats = load(&root, "GAME.gba").unwrap().unwrap();
+        let names: std::vec::Vec<_> = cheats
+            .iter()
+            .map(|cheat| (cheat.name(), cheat.enabled))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("Master Code", false),
+                ("Max Money", true),
+                ("Infinite Lives", false)
+            ]
+        );
+        assert_eq!(
+            cheats.ops(),
+            [Op {
+                kind: OpKind::Write16,
+                addr: 0x2000010,
+                value: 0x1234
+            }]
+        );
+
+        // one without an enable line gets one
+        set_enabled(&root, "GAME.gba", 2, true).unwrap();
+        set_enabled(&root, "GAME.gba", 1, false).unwrap();
+        let cheats = load(&root, "GAME.gba").unwrap().unwrap();
+        assert_eq!(
+            cheats.ops(),
+            [Op {
+                kind: OpKind::Write8,
+                addr: 0x2000020,
+                value: 0x63
+            }]
+        );
+        assert!(load(&root, "OTHER.gba").unwrap().is_none());
+    }
+}
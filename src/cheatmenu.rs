@@ -0,0 +1,118 @@
+//! turning a game's cheats on and off from the menu
+//!
+//! each change goes into the game's `.cht` straight away, so it sticks whether or not the game
+//! is booted afterwards
+
+use core::fmt::{self, Write};
+
+use crate::browser::{write_padded, Action};
+use crate::cheat::Cheats;
+use crate::halfwidth::{COLUMNS, ROWS};
+
+/// the title goes on the first row and the keys on the last, with the list between
+const PAGE: usize = ROWS - 2;
+
+pub struct CheatMenu {
+    cheats: Option<Cheats>,
+    cursor: usize,
+    /// what went wrong with the last action, shown in place of the keys until the next one
+    problem: Option<&'static str>,
+}
+
+impl CheatMenu {
+    pub fn new(cheats: Option<Cheats>) -> Self {
+        Self {
+            cheats,
+            cursor: 0,
+            problem: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.cheats
+            .as_ref()
+            .map_or(0, |cheats| cheats.iter().count())
+    }
+
+    /// move the cursor, or return which cheat to turn on or off and what to set it to
+    pub fn handle(&mut self, action: Action) -> Option<(usize, bool)> {
+        let last = self.len().saturating_sub(1);
+        self.problem = None;
+        match action {
+            Action::Up => self.cursor = self.cursor.checked_sub(1).unwrap_or(last),
+            Action::Down => {
+                self.cursor = if self.cursor < last {
+                    self.cursor + 1
+                } else {
+                    0
+                }
+            }
+            Action::PageUp => self.cursor = self.cursor.saturating_sub(PAGE),
+            Action::PageDown => self.cursor = (self.cursor + PAGE).min(last),
+            Action::Open => {
+                let cheat = self.cheats.as_ref()?.iter().nth(self.cursor)?;
+                return Some((self.cursor, !cheat.enabled));
+            }
+            Action::Back => (),
+        }
+        None
+    }
+
+    /// remember that a cheat was turned on or off, once that's been saved
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+        let cheat = self
+            .cheats
+            .as_mut()
+            .and_then(|cheats| cheats.iter_mut().nth(index));
+        if let Some(cheat) = cheat {
+            cheat.enabled = enabled;
+        }
+    }
+
+    /// show what went wrong with the last action, until the next one
+    pub fn set_problem(&mut self, problem: &'static str) {
+        self.problem = Some(problem);
+    }
+
+    /// draw the whole screen, which should be blank to start with
+    pub fn draw<W: Write>(&self, out: &mut W, rom_path: &str) -> fmt::Result {
+        // bright white on blue, like the browser
+        write!(out, "\x1b[97;44mcheats: ")?;
+        write_padded(out, rom_path, COLUMNS - 8)?;
+        writeln!(out, "\x1b[m")?;
+
+        let top = self.cursor - self.cursor % PAGE;
+        let mut rows = 0;
+        let cheats = self.cheats.iter().flat_map(|cheats| cheats.iter());
+        for (i, cheat) in cheats.enumerate().skip(top).take(PAGE) {
+            if i == self.cursor {
+                // black on white
+                write!(out, "\x1b[30;47m")?;
+            }
+            write!(out, "{} ", if cheat.enabled { "[x]" } else { "[ ]" })?;
+            write_padded(out, cheat.name(), COLUMNS - 4)?;
+            writeln!(out, "\x1b[m")?;
+            rows += 1;
+        }
+        if self.len() == 0 {
+            writeln!(out, "\x1b[90mno cheats for this game\x1b[m")?;
+            rows += 1;
+        }
+        for _ in rows..PAGE {
+            writeln!(out)?;
+        }
+
+        // no newline so nothing scrolls. a problem goes here, since the warning it logged is cleared
+        if let Some(problem) = self.problem {
+            // bright yellow, like a warning in the log
+            return write!(out, "\x1b[93m{}\x1b[m", problem);
+        }
+        // bright black
+        write!(
+            out,
+            "\x1b[90m{}/{}  a: on/off  b: back  l/r: page\x1b[m",
+            (self.cursor + 1).min(self.len()),
+            self.len()
+        )
+    }
+}
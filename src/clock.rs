@@ -0,0 +1,175 @@
+//! showing and setting the clock from the menu
+
+#[cfg(not(feature = "host"))]
+use core::fmt::{self, Write};
+
+#[cfg(not(feature = "host"))]
+use crate::browser::write_padded;
+#[cfg(not(feature = "host"))]
+use crate::halfwidth::COLUMNS;
+use crate::rtc::{DateTime, RtcError};
+
+/// year, month, day, hour, minute and second
+const FIELDS: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// move to the field before
+    Left,
+    Right,
+    /// count the field up
+    Up,
+    Down,
+}
+
+pub struct ClockEditor {
+    time: DateTime,
+    /// why the clock couldn't be read, if it couldn't
+    #[cfg(not(feature = "host"))]
+    error: Option<RtcError>,
+    field: usize,
+}
+
+impl ClockEditor {
+    /// start from what the clock says, or from the default if it doesn't know
+    pub fn new(now: Result<DateTime, RtcError>) -> Self {
+        Self {
+            time: now.unwrap_or(DateTime::DEFAULT),
+            #[cfg(not(feature = "host"))]
+            error: now.err(),
+            field: 0,
+        }
+    }
+
+    pub fn time(&self) -> DateTime {
+        self.time
+    }
+
+    pub fn handle(&mut self, action: Action) {
+        match action {
+            Action::Left => self.field = (self.field + FIELDS - 1) % FIELDS,
+            Action::Right => self.field = (self.field + 1) % FIELDS,
+            Action::Up => self.step(true),
+            Action::Down => self.step(false),
+        }
+    }
+
+    /// count the field up or down, wrapping around at either end
+    fn step(&mut self, up: bool) {
+        let time = &mut self.time;
+        match self.field {
+            0 => {
+                let mut year = (time.year - 2000) as u8;
+                step(&mut year, 0, 99, up);
+                time.year = 2000 + year as u16;
+            }
+            1 => step(&mut time.month, 1, 12, up),
+            2 => {
+                let last = days_in_month(time.year, time.month);
+                step(&mut time.day, 1, last, up)
+            }
+            3 => step(&mut time.hour, 0, 23, up),
+            4 => step(&mut time.minute, 0, 59, up),
+            _ => step(&mut time.second, 0, 59, up),
+        }
+        // a shorter month, or a year that isn't a leap year, pulls the day back into range
+        time.day = time.day.min(days_in_month(time.year, time.month));
+    }
+
+    #[cfg(not(feature = "host"))]
+    pub fn draw<W: Write>(&self, out: &mut W) -> fmt::Result {
+        // bright white on blue, like the browser
+        write!(out, "\x1b[97;44m")?;
+        write_padded(out, "clock", COLUMNS)?;
+        writeln!(out, "\x1b[m")?;
+        writeln!(out)?;
+
+        let time = &self.time;
+        let fields = [
+            time.year,
+            time.month as u16,
+            time.day as u16,
+            time.hour as u16,
+            time.minute as u16,
+            time.second as u16,
+        ];
+        for (i, value) in fields.into_iter().enumerate() {
+            write!(out, "{}", ["", "-", "-", " ", ":", ":"][i])?;
+            if i == self.field {
+                // black on white
+                write!(out, "\x1b[30;47m")?;
+            }
+            write!(out, "{:02}\x1b[m", value)?;
+        }
+        writeln!(out)?;
+        writeln!(out)?;
+
+        match self.error {
+            Some(RtcError::Stopped) => writeln!(out, "the clock lost power, so it needs setting")?,
+            Some(RtcError::Invalid) => writeln!(out, "couldn't read the clock")?,
+            None => (),
+        }
+
+        // bright black
+        write!(
+            out,
+            "\x1b[90mleft/right: pick  up/down: change  a: set  b: cancel\x1b[m"
+        )
+    }
+}
+
+fn step(value: &mut u8, min: u8, max: u8, up: bool) {
+    *value = match (up, *value) {
+        (true, value) if value >= max => min,
+        (true, value) => value + 1,
+        (false, value) if value <= min => max,
+        (false, value) => value - 1,
+    };
+}
+
+/// the clock only goes from 2000 to 2099, where every fourth year is a leap year
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, ClockEditor};
+    use crate::rtc::{DateTime, RtcError};
+
+    #[test]
+    fn wraps_and_keeps_the_day_in_the_month() {
+        let mut editor = ClockEditor::new(Ok(DateTime {
+            year: 2024,
+            month: 1,
+            day: 31,
+            ..DateTime::DEFAULT
+        }));
+
+        // up a month from the 31st of january
+        editor.handle(Action::Right);
+        editor.handle(Action::Up);
+        assert_eq!((editor.time().month, editor.time().day), (2, 29));
+
+        // and 2025 isn't a leap year
+        editor.handle(Action::Left);
+        editor.handle(Action::Up);
+        assert_eq!((editor.time().year, editor.time().day), (2025, 28));
+
+        // the seconds wrap around backwards
+        editor.handle(Action::Left);
+        editor.handle(Action::Down);
+        assert_eq!(editor.time().second, 59);
+    }
+
+    #[test]
+    fn starts_from_the_default_without_a_clock() {
+        let editor = ClockEditor::new(Err(RtcError::Stopped));
+        assert_eq!(editor.time(), DateTime::DEFAULT);
+    }
+}
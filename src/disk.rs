@@ -0,0 +1,233 @@
+use core::ops::Range;
+
+use crate::sd::{BlockIo, BlockIoError, Lba};
+
+/// a disk backed by a byte slice, for running the block layer without a card
+pub struct RamDisk<'a> {
+    data: &'a mut [u8],
+}
+
+impl<'a> RamDisk<'a> {
+    /// any trailing partial block is ignored
+    // the firmware only reads the card itself so far, leaving this to the tests
+    #[allow(dead_code)]
+    pub fn new(data: &'a mut [u8]) -> Self {
+        Self { data }
+    }
+
+    fn range(&self, start_lba: Lba, len: usize) -> Result<Range<usize>, BlockIoError> {
+        if len % 512 != 0 {
+            return Err(BlockIoError::Misaligned);
+        }
+
+        let start = start_lba as usize * 512;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() / 512 * 512 => Ok(start..end),
+            _ => Err(BlockIoError::OutOfRange {
+                lba: start_lba,
+                count: (len / 512) as u32,
+            }),
+        }
+    }
+}
+
+impl BlockIo<512> for RamDisk<'_> {
+    type Error = BlockIoError;
+
+    fn block_count(&self) -> Option<Lba> {
+        Some((self.data.len() / 512) as Lba)
+    }
+
+    fn read_blocks(&mut self, start_lba: Lba, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let range = self.range(start_lba, buffer.len())?;
+        buffer.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start_lba: Lba, buffer: &[u8]) -> Result<(), Self::Error> {
+        let range = self.range(start_lba, buffer.len())?;
+        self.data[range].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+pub use image::ImageFile;
+
+#[cfg(feature = "std")]
+mod image {
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    use crate::sd::{BlockIo, BlockIoError, Lba};
+
+    #[derive(Debug)]
+    pub enum ImageError {
+        Io(io::Error),
+        BlockIo(BlockIoError),
+    }
+
+    impl From<io::Error> for ImageError {
+        fn from(value: io::Error) -> Self {
+            Self::Io(value)
+        }
+    }
+
+    impl From<BlockIoError> for ImageError {
+        fn from(value: BlockIoError) -> Self {
+            Self::BlockIo(value)
+        }
+    }
+
+    /// a disk backed by an image file on the host
+    pub struct ImageFile {
+        file: File,
+        blocks: Lba,
+    }
+
+    impl ImageFile {
+        pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+            let file = OpenOptions::new().read(true).write(true).open(path)?;
+            let blocks = (file.metadata()?.len() / 512).min(Lba::MAX as u64) as Lba;
+            Ok(Self { file, blocks })
+        }
+
+        fn seek_to(&mut self, start_lba: Lba, len: usize) -> Result<(), ImageError> {
+            if len % 512 != 0 {
+                return Err(BlockIoError::Misaligned.into());
+            }
+
+            let count = (len / 512) as u32;
+            if !start_lba
+                .checked_add(count)
+                .is_some_and(|end| end <= self.blocks)
+            {
+                return Err(BlockIoError::OutOfRange {
+                    lba: start_lba,
+                    count,
+                }
+                .into());
+            }
+
+            self.file.seek(SeekFrom::Start(start_lba as u64 * 512))?;
+            Ok(())
+        }
+    }
+
+    impl BlockIo<512> for ImageFile {
+        type Error = ImageError;
+
+        fn block_count(&self) -> Option<Lba> {
+            Some(self.blocks)
+        }
+
+        fn read_blocks(&mut self, start_lba: Lba, buffer: &mut [u8]) -> Result<(), Self::Error> {
+            self.seek_to(start_lba, buffer.len())?;
+            self.file.read_exact(buffer)?;
+            Ok(())
+        }
+
+        fn write_blocks(&mut self, start_lba: Lba, buffer: &[u8]) -> Result<(), Self::Error> {
+            self.seek_to(start_lba, buffer.len())?;
+            self.file.write_all(buffer)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ape_fatfs::fs::{FileSystem, FsOptions};
+    use embedded_io::blocking::{Read, Write};
+    use std::{vec, vec::Vec};
+
+    use super::RamDisk;
+    use crate::fixture::{fat_image, BLOCKS, START};
+    use crate::fs::{BufferedIo, Pages};
+    use crate::partition::PartitionTable;
+    use crate::sd::{BlockIo, BlockIoError};
+
+    /// enough to go through every page of the cache a few times over
+    fn contents() -> Vec<u8> {
+        (0..20_000u32).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn ram_disk_bounds() {
+        let mut data = vec![0; 4 * 512 + 100];
+        let mut disk = RamDisk::new(&mut data);
+        assert_eq!(disk.block_count(), Some(4));
+
+        let mut block = [0xa5; 512];
+        disk.write_blocks(3, &block).unwrap();
+        block.fill(0);
+        disk.read_blocks(3, &mut block).unwrap();
+        assert_eq!(block, [0xa5; 512]);
+
+        assert!(matches!(
+            disk.read_blocks(4, &mut block),
+            Err(BlockIoError::OutOfRange { lba: 4, count: 1 })
+        ));
+        assert!(matches!(
+            disk.read_blocks(0, &mut block[..100]),
+            Err(BlockIoError::Misaligned)
+        ));
+    }
+
+    #[test]
+    fn mount_through_partition_table() {
+        let mut data = fat_image();
+        let mut disk = RamDisk::new(&mut data);
+
+        let table = PartitionTable::read(&mut disk).unwrap();
+        let info = *table.default_partition().unwrap();
+        assert_eq!((info.start, info.end), (START, BLOCKS));
+
+        let mut pages = Pages::new();
+
+        let io = BufferedIo::<512, 2048, 4, _>::new(info.open(&mut disk), &mut pages);
+        let fs = FileSystem::new(io, FsOptions::new()).unwrap();
+        assert_eq!(fs.root_dir().iter().count(), 0);
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let mut data = fat_image();
+        let expected = contents();
+
+        {
+            let mut disk = RamDisk::new(&mut data);
+            let info = *PartitionTable::read(&mut disk)
+                .unwrap()
+                .default_partition()
+                .unwrap();
+            let mut pages = Pages::new();
+            let mut io = BufferedIo::<512, 2048, 4, _>::new(info.open(&mut disk), &mut pages);
+            let fs = FileSystem::new(&mut io, FsOptions::new()).unwrap();
+            let mut file = fs.root_dir().create_file("TEST.BIN").unwrap();
+            file.write_all(&expected).unwrap();
+            file.flush().unwrap();
+            drop(file);
+            fs.unmount().unwrap();
+
+            let stats = io.stats();
+            assert!(stats.hits > 0 && stats.misses > 0);
+        }
+
+        // a fresh mount, with nothing left over in a cache
+        let mut disk = RamDisk::new(&mut data);
+        let info = *PartitionTable::read(&mut disk)
+            .unwrap()
+            .default_partition()
+            .unwrap();
+        let mut pages = Pages::new();
+        let io = BufferedIo::<512, 2048, 4, _>::new(info.open(&mut disk), &mut pages);
+        let fs = FileSystem::new(io, FsOptions::new()).unwrap();
+        let mut file = fs.root_dir().open_file("TEST.BIN").unwrap();
+        let mut actual = vec![0; expected.len()];
+        file.read_exact(&mut actual).unwrap();
+        assert!(actual == expected);
+        assert_eq!(file.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,130 @@
+//! running game boy and nes games through goomba color and pocketnes
+//!
+//! both emulators look for their games after the end of their own rom, so the emulator is loaded
+//! as the game with the real game appended to it
+
+/// where the emulators are kept on the card
+#[cfg(not(feature = "host"))]
+const GOOMBA: &str = "EZFODE/EMU/GOOMBA.GBA";
+#[cfg(not(feature = "host"))]
+const POCKETNES: &str = "EZFODE/EMU/POCKETNES.GBA";
+
+/// pocketnes wants a name, the size and some settings in front of each game
+pub const MAX_HEADER_LEN: usize = 48;
+const POCKETNES_NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emulator {
+    /// goomba color, which runs game boy and game boy color games
+    Goomba,
+    PocketNes,
+}
+
+impl Emulator {
+    /// which emulator runs `path`, going by its extension
+    pub fn for_path(path: &str) -> Option<Self> {
+        let (_, ext) = path.rsplit_once('.')?;
+        if ext.eq_ignore_ascii_case("gb") || ext.eq_ignore_ascii_case("gbc") {
+            Some(Self::Goomba)
+        } else if ext.eq_ignore_ascii_case("nes") {
+            Some(Self::PocketNes)
+        } else {
+            None
+        }
+    }
+
+    /// where the emulator's rom is on the card
+    #[cfg(not(feature = "host"))]
+    pub fn path(&self) -> &'static str {
+        match self {
+            Self::Goomba => GOOMBA,
+            Self::PocketNes => POCKETNES,
+        }
+    }
+
+    /// what the game has to start on, counting from the start of the emulator
+    pub fn alignment(&self) -> usize {
+        match self {
+            Self::Goomba => 1,
+            Self::PocketNes => 4,
+        }
+    }
+
+    /// fill in what goes between the emulator and the game, and return how long it is
+    ///
+    /// goomba finds games by their own headers, so it doesn't need anything
+    pub fn game_header(&self, path: &str, size: u32, header: &mut [u8; MAX_HEADER_LEN]) -> usize {
+        match self {
+            Self::Goomba => 0,
+            Self::PocketNes => {
+                let name_start = path.rfind('/').map_or(0, |i| i + 1);
+                let name = path[name_start..]
+                    .rsplit_once('.')
+                    .map_or(&path[name_start..], |(stem, _)| stem);
+                // the name has to end with a nul
+                let len = name.len().min(POCKETNES_NAME_LEN - 1);
+
+                header.fill(0);
+                header[..len].copy_from_slice(&name.as_bytes()[..len]);
+                // then the size, and flags and sprite follow settings which are left at none
+                header[POCKETNES_NAME_LEN..POCKETNES_NAME_LEN + 4]
+                    .copy_from_slice(&size.to_le_bytes());
+                MAX_HEADER_LEN
+            }
+        }
+    }
+}
+
+/// an emulator and a game to load into it
+#[cfg(not(feature = "host"))]
+pub struct Emulated<'r, R> {
+    pub emulator: Emulator,
+    pub binary: &'r mut R,
+    pub binary_size: u64,
+    pub game: &'r mut R,
+    pub game_size: u64,
+    /// the game's path, which pocketnes shows
+    pub path: &'r str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Emulator, MAX_HEADER_LEN};
+
+    #[test]
+    fn pocketnes_header() {
+        let path = "ROMS/NES/Zelda II.nes";
+        let emulator = Emulator::for_path(path).unwrap();
+        assert_eq!(emulator, Emulator::PocketNes);
+
+        let mut header = [0xff; MAX_HEADER_LEN];
+        assert_eq!(emulator.game_header(path, 0x40010, &mut header), 48);
+        let mut expected = [0; 48];
+        expected[..8].copy_from_slice(b"Zelda II");
+        expected[32..36].copy_from_slice(&[0x10, 0x00, 0x04, 0x00]);
+        assert_eq!(header, expected);
+
+        // the game goes on the next word after an emulator that doesn't end on one
+        assert_eq!(0x1e6a2usize.next_multiple_of(emulator.alignment()), 0x1e6a4);
+    }
+
+    #[test]
+    fn pocketnes_name_keeps_its_nul() {
+        let path = "a name much too long to fit in the header.nes";
+        let mut header = [0xff; MAX_HEADER_LEN];
+        Emulator::PocketNes.game_header(path, 1, &mut header);
+        assert_eq!(&header[..31], &path.as_bytes()[..31]);
+        assert_eq!(header[31], 0);
+    }
+
+    #[test]
+    fn goomba_needs_no_header() {
+        let path = "ROMS/GB/Tetris.GBC";
+        let emulator = Emulator::for_path(path).unwrap();
+        assert_eq!(emulator, Emulator::Goomba);
+
+        let mut header = [0xff; MAX_HEADER_LEN];
+        assert_eq!(emulator.game_header(path, 0x8000, &mut header), 0);
+        assert_eq!(0x1e6a2usize.next_multiple_of(emulator.alignment()), 0x1e6a2);
+    }
+}
@@ -1,15 +1,60 @@
-const MAGIC_1: *mut u16 = 0x9fe0000 as *mut u16;
-const MAGIC_2: *mut u16 = 0x8000000 as *mut u16;
-const MAGIC_3: *mut u16 = 0x8020000 as *mut u16;
-const MAGIC_4: *mut u16 = 0x8040000 as *mut u16;
+use crate::delay;
+
+const MAGIC_1: usize = 0x9fe0000;
+const MAGIC_2: usize = 0x8000000;
+const MAGIC_3: usize = 0x8020000;
+const MAGIC_4: usize = 0x8040000;
 // finalize txn
-const MAGIC_5: *mut u16 = 0x9fc0000 as *mut u16;
+const MAGIC_5: usize = 0x9fc0000;
+
+pub const ROMPAGE: usize = 0x9880000;
+pub const PSRAMPAGE: usize = 0x9860000;
+pub const RAMPAGE: usize = 0x9c00000;
+pub const LED_CTRL: usize = 0x96e0000;
+/// 1 puts the clock on the gpio port
+pub const RTC_CTRL: usize = 0x96a0000;
+pub const SD_CTRL: usize = 0x9400000;
+pub const SD_BUF: usize = 0x9e00000;
+pub const SD_ADDR_L: usize = 0x9600000;
+pub const SD_ADDR_H: usize = 0x9620000;
+// bit 15 set means write
+pub const SD_BLOCKS: usize = 0x9640000;
+
+/// rompage where the boot flash is mapped, needed to reach the sd card and the psram window
+pub const ROMPAGE_OS: u16 = 0x8000;
+/// rompage where psram is mapped as the cart, which is where we run from too
+pub const ROMPAGE_PSRAM: u16 = 0x200;
+
+/// where the psram page shows up in os mode
+pub const PSRAM_WINDOW: usize = 0x8800000;
+pub const PSRAM_WINDOW_SIZE: usize = 0x800000;
+pub const PSRAM_SIZE: usize = 0x2000000;
+/// psram pages are counted in 64k steps
+pub const PSRAM_PAGE_SHIFT: u32 = 16;
+
+/// nor flash shows up in place of the cart when the rompage is below psram, in 128k steps
+pub const NOR_WINDOW: usize = 0x8000000;
+/// kept clear of the registers in the top half of the cart
+pub const NOR_WINDOW_SIZE: usize = 0x1000000;
+pub const NOR_SIZE: usize = 0x4000000;
+pub const NOR_PAGE_SHIFT: u32 = 17;
+/// nor can only be erased a whole sector at a time
+pub const NOR_SECTOR_SIZE: usize = 0x20000;
 
-const ROMPAGE: *mut u16 = 0x9880000 as *mut u16;
-const PSRAMPAGE: *mut u16 = 0x9860000 as *mut u16;
-const LED_CTRL: *mut u16 = 0x96e0000 as *mut u16;
-const SD_CTRL: *mut u16 = 0x9400000 as *mut u16;
-const SD_BUF: *mut u16 = 0x9e00000 as *mut u16;
+/// the battery backed sram, which shows up one 64k page at a time
+pub const SRAM_WINDOW: usize = 0xe000000;
+pub const SRAM_WINDOW_SIZE: usize = 0x10000;
+pub const SRAM_SIZE: usize = 0x20000;
+
+/// the unlock sequence that has to come before every register write
+pub const UNLOCK: [(usize, u16); 4] = [
+    (MAGIC_1, 0xd200),
+    (MAGIC_2, 0x1500),
+    (MAGIC_3, 0xd200),
+    (MAGIC_4, 0x1500),
+];
+/// the write that applies everything written since the unlock
+pub const FINISH: (usize, u16) = (MAGIC_5, 0x1500);
 
 #[repr(u16)]
 pub enum SdControl {
@@ -18,71 +63,341 @@ pub enum SdControl {
     ReadState = 3,
 }
 
+/// access to the cartridge bus, so the driver can run against a simulation
+pub trait Bus {
+    unsafe fn write(&mut self, addr: usize, value: u16);
+    unsafe fn read(&mut self, addr: usize) -> u16;
+    /// copy out of the sd buffer window
+    unsafe fn read_sd_buffer(&mut self, dst: &mut [u8]);
+    /// stage data in the sd buffer window
+    unsafe fn write_sd_buffer(&mut self, src: &[u8]);
+    /// copy into the psram window at `addr`, at most 64k at a time
+    unsafe fn write_psram(&mut self, addr: usize, src: &[u8]);
+    /// copy out of the psram window at `addr`, at most 64k at a time
+    unsafe fn read_psram(&mut self, addr: usize, dst: &mut [u8]);
+    /// copy out of the sram window at `addr`
+    unsafe fn read_sram(&mut self, addr: usize, dst: &mut [u8]);
+    /// copy into the sram window at `addr`
+    unsafe fn write_sram(&mut self, addr: usize, src: &[u8]);
+}
+
+impl<B: Bus> Bus for &mut B {
+    #[inline(always)]
+    unsafe fn write(&mut self, addr: usize, value: u16) {
+        (**self).write(addr, value)
+    }
+
+    #[inline(always)]
+    unsafe fn read(&mut self, addr: usize) -> u16 {
+        (**self).read(addr)
+    }
+
+    #[inline(always)]
+    unsafe fn read_sd_buffer(&mut self, dst: &mut [u8]) {
+        (**self).read_sd_buffer(dst)
+    }
+
+    #[inline(always)]
+    unsafe fn write_sd_buffer(&mut self, src: &[u8]) {
+        (**self).write_sd_buffer(src)
+    }
+
+    #[inline(always)]
+    unsafe fn write_psram(&mut self, addr: usize, src: &[u8]) {
+        (**self).write_psram(addr, src)
+    }
+
+    #[inline(always)]
+    unsafe fn read_psram(&mut self, addr: usize, dst: &mut [u8]) {
+        (**self).read_psram(addr, dst)
+    }
+
+    #[inline(always)]
+    unsafe fn read_sram(&mut self, addr: usize, dst: &mut [u8]) {
+        (**self).read_sram(addr, dst)
+    }
+
+    #[inline(always)]
+    unsafe fn write_sram(&mut self, addr: usize, src: &[u8]) {
+        (**self).write_sram(addr, src)
+    }
+}
+
+/// the real cartridge
+#[cfg(not(feature = "host"))]
+pub struct Hardware;
+
+#[cfg(not(feature = "host"))]
+impl Bus for Hardware {
+    #[inline(always)]
+    unsafe fn write(&mut self, addr: usize, value: u16) {
+        (addr as *mut u16).write_volatile(value);
+    }
+
+    #[inline(always)]
+    unsafe fn read(&mut self, addr: usize) -> u16 {
+        (addr as *mut u16).read_volatile()
+    }
+
+    #[inline(always)]
+    unsafe fn read_sd_buffer(&mut self, dst: &mut [u8]) {
+        use core::ffi::c_void;
+        crate::dma::dma_copy(
+            SD_BUF as *mut c_void,
+            dst.as_mut_ptr() as *mut c_void,
+            dst.len() as u32,
+        );
+    }
+
+    #[inline(always)]
+    unsafe fn write_sd_buffer(&mut self, src: &[u8]) {
+        use core::ffi::c_void;
+        crate::dma::dma_copy(
+            src.as_ptr() as *mut c_void,
+            SD_BUF as *mut c_void,
+            src.len() as u32,
+        );
+    }
+
+    #[inline(always)]
+    unsafe fn write_psram(&mut self, addr: usize, src: &[u8]) {
+        use core::ffi::c_void;
+        crate::dma::dma_copy(
+            src.as_ptr() as *mut c_void,
+            addr as *mut c_void,
+            src.len() as u32,
+        );
+    }
+
+    #[inline(always)]
+    unsafe fn read_psram(&mut self, addr: usize, dst: &mut [u8]) {
+        use core::ffi::c_void;
+        crate::dma::dma_copy(
+            addr as *mut c_void,
+            dst.as_mut_ptr() as *mut c_void,
+            dst.len() as u32,
+        );
+    }
+
+    // sram is on an 8 bit bus, so it can't be copied with dma or anything wider than a byte
+
+    unsafe fn read_sram(&mut self, addr: usize, dst: &mut [u8]) {
+        for (i, byte) in dst.iter_mut().enumerate() {
+            *byte = ((addr + i) as *const u8).read_volatile();
+        }
+    }
+
+    unsafe fn write_sram(&mut self, addr: usize, src: &[u8]) {
+        for (i, byte) in src.iter().enumerate() {
+            ((addr + i) as *mut u8).write_volatile(*byte);
+        }
+    }
+}
+
 #[link_section = ".iwram"]
-unsafe fn start_txn() {
-    MAGIC_1.write_volatile(0xd200);
-    MAGIC_2.write_volatile(0x1500);
-    MAGIC_3.write_volatile(0xd200);
-    MAGIC_4.write_volatile(0x1500);
+unsafe fn start_txn<B: Bus>(bus: &mut B) {
+    for (addr, value) in UNLOCK {
+        bus.write(addr, value);
+    }
 }
 
 #[link_section = ".iwram"]
-unsafe fn finish_txn() {
-    MAGIC_5.write_volatile(0x1500);
+unsafe fn finish_txn<B: Bus>(bus: &mut B) {
+    bus.write(FINISH.0, FINISH.1);
+}
+
+#[link_section = ".iwram"]
+pub unsafe fn set_rompage<B: Bus>(bus: &mut B, page: u16) {
+    start_txn(bus);
+    bus.write(ROMPAGE, page); //C4
+    finish_txn(bus);
+}
+
+#[cfg(any(test, not(feature = "host")))]
+#[link_section = ".iwram"]
+pub unsafe fn set_psrampage<B: Bus>(bus: &mut B, page: u16) {
+    start_txn(bus);
+    bus.write(PSRAMPAGE, page); // C3
+    finish_txn(bus);
+}
+
+/// copy `src` into psram starting at `offset`, paging the window as needed
+///
+/// this leaves the cart in psram mode, so it mustn't be called halfway through an sd transfer
+#[cfg(any(test, not(feature = "host")))]
+#[link_section = ".iwram"]
+pub unsafe fn write_psram<B: Bus>(bus: &mut B, offset: usize, src: &[u8]) {
+    set_rompage(bus, ROMPAGE_OS);
+
+    let mut pos = 0;
+    while pos < src.len() {
+        let addr = offset + pos;
+        let window = addr & !(PSRAM_WINDOW_SIZE - 1);
+        let len = (src.len() - pos)
+            .min(window + PSRAM_WINDOW_SIZE - addr)
+            .min(0x10000);
+
+        set_psrampage(bus, (window >> PSRAM_PAGE_SHIFT) as u16);
+        bus.write_psram(PSRAM_WINDOW + addr - window, &src[pos..pos + len]);
+        pos += len;
+    }
+
+    set_rompage(bus, ROMPAGE_PSRAM);
 }
 
+/// copy out of psram starting at `offset`, paging the window as needed
+#[cfg(any(test, not(feature = "host")))]
 #[link_section = ".iwram"]
-pub unsafe fn set_rompage(page: u16) {
-    start_txn();
-    ROMPAGE.write_volatile(page); //C4
-    finish_txn();
+pub unsafe fn read_psram<B: Bus>(bus: &mut B, offset: usize, dst: &mut [u8]) {
+    set_rompage(bus, ROMPAGE_OS);
+
+    let mut pos = 0;
+    while pos < dst.len() {
+        let addr = offset + pos;
+        let window = addr & !(PSRAM_WINDOW_SIZE - 1);
+        let len = (dst.len() - pos)
+            .min(window + PSRAM_WINDOW_SIZE - addr)
+            .min(0x10000);
+
+        set_psrampage(bus, (window >> PSRAM_PAGE_SHIFT) as u16);
+        bus.read_psram(PSRAM_WINDOW + addr - window, &mut dst[pos..pos + len]);
+        pos += len;
+    }
+
+    set_rompage(bus, ROMPAGE_PSRAM);
 }
 
+#[cfg(any(test, not(feature = "host")))]
 #[link_section = ".iwram"]
-pub unsafe fn set_psrampage(page: u16) {
-    start_txn();
-    PSRAMPAGE.write_volatile(page); // C3
-    finish_txn();
+pub unsafe fn set_rampage<B: Bus>(bus: &mut B, page: u16) {
+    start_txn(bus);
+    bus.write(RAMPAGE, page);
+    finish_txn(bus);
 }
 
+/// split an sram access at `offset` into pieces that fit in one page
+#[cfg(any(test, not(feature = "host")))]
+unsafe fn sram_pages<B: Bus>(
+    bus: &mut B,
+    offset: usize,
+    len: usize,
+    mut f: impl FnMut(&mut B, usize, core::ops::Range<usize>),
+) {
+    assert!(offset + len <= SRAM_SIZE, "sram access past the end");
+
+    let mut pos = 0;
+    while pos < len {
+        let addr = offset + pos;
+        let page = addr / SRAM_WINDOW_SIZE;
+        let piece = (len - pos).min((page + 1) * SRAM_WINDOW_SIZE - addr);
+
+        set_rampage(bus, page as u16);
+        f(bus, SRAM_WINDOW + addr % SRAM_WINDOW_SIZE, pos..pos + piece);
+        pos += piece;
+    }
+
+    // games only know about the first page
+    set_rampage(bus, 0);
+}
+
+#[cfg(any(test, not(feature = "host")))]
+pub unsafe fn read_sram<B: Bus>(bus: &mut B, offset: usize, dst: &mut [u8]) {
+    sram_pages(bus, offset, dst.len(), |bus, addr, range| {
+        bus.read_sram(addr, &mut dst[range])
+    });
+}
+
+#[cfg(any(test, not(feature = "host")))]
+pub unsafe fn write_sram<B: Bus>(bus: &mut B, offset: usize, src: &[u8]) {
+    sram_pages(bus, offset, src.len(), |bus, addr, range| {
+        bus.write_sram(addr, &src[range])
+    });
+}
+
+#[cfg(not(feature = "host"))]
 #[link_section = ".iwram"]
-pub unsafe fn set_led_control(status: u16) {
-    start_txn();
-    LED_CTRL.write_volatile(status);
-    finish_txn();
+pub unsafe fn set_led_control<B: Bus>(bus: &mut B, status: u16) {
+    start_txn(bus);
+    bus.write(LED_CTRL, status);
+    finish_txn(bus);
 }
 
+#[cfg(any(test, not(feature = "host")))]
 #[link_section = ".iwram"]
-pub unsafe fn set_sd_control(control: SdControl) {
-    start_txn();
-    SD_CTRL.write_volatile(control as u16);
-    finish_txn();
+pub unsafe fn set_rtc_control<B: Bus>(bus: &mut B, enabled: bool) {
+    start_txn(bus);
+    bus.write(RTC_CTRL, enabled as u16);
+    finish_txn(bus);
 }
 
 #[link_section = ".iwram"]
-pub unsafe fn sd_enable() {
-    set_sd_control(SdControl::Enable);
+pub unsafe fn set_sd_control<B: Bus>(bus: &mut B, control: SdControl) {
+    start_txn(bus);
+    bus.write(SD_CTRL, control as u16);
+    finish_txn(bus);
 }
 
 #[link_section = ".iwram"]
-pub unsafe fn sd_disable() {
-    set_sd_control(SdControl::Disable);
+pub unsafe fn sd_enable<B: Bus>(bus: &mut B) {
+    set_sd_control(bus, SdControl::Enable);
 }
 
 #[link_section = ".iwram"]
-pub unsafe fn sd_read_state() {
-    set_sd_control(SdControl::ReadState);
+pub unsafe fn sd_disable<B: Bus>(bus: &mut B) {
+    set_sd_control(bus, SdControl::Disable);
 }
 
 #[link_section = ".iwram"]
-pub unsafe fn sd_response() -> u16 {
-    SD_BUF.read_volatile()
+pub unsafe fn sd_read_state<B: Bus>(bus: &mut B) {
+    set_sd_control(bus, SdControl::ReadState);
 }
 
+/// ask the card to transfer `blocks` blocks (at most 4) starting at `lba`
+/// through the sd buffer window
 #[link_section = ".iwram"]
-pub unsafe fn wait_sd_response() -> Result<(), ()> {
+unsafe fn sd_request<B: Bus>(bus: &mut B, lba: u32, blocks: u16) {
+    start_txn(bus);
+    bus.write(SD_ADDR_L, lba as u16);
+    bus.write(SD_ADDR_H, (lba >> 16) as u16);
+    bus.write(SD_BLOCKS, blocks);
+    finish_txn(bus);
+}
+
+#[link_section = ".iwram"]
+pub unsafe fn sd_read_request<B: Bus>(bus: &mut B, lba: u32, blocks: u16) {
+    sd_request(bus, lba, blocks);
+}
+
+/// the data must already be staged in the sd buffer window
+#[link_section = ".iwram"]
+pub unsafe fn sd_write_request<B: Bus>(bus: &mut B, lba: u32, blocks: u16) {
+    sd_request(bus, lba, 0x8000 | blocks);
+}
+
+#[link_section = ".iwram"]
+pub unsafe fn sd_response<B: Bus>(bus: &mut B) -> u16 {
+    bus.read(SD_BUF)
+}
+
+#[link_section = ".iwram"]
+pub unsafe fn wait_sd_response<B: Bus>(bus: &mut B) -> Result<(), ()> {
+    for _ in 0..100000 {
+        if sd_response(bus) != 0xeee1 {
+            return Ok(());
+        }
+    }
+
+    // timeout!
+    Err(())
+}
+
+#[link_section = ".iwram"]
+pub unsafe fn wait_sd_write_busy<B: Bus>(bus: &mut B) -> Result<(), ()> {
+    // the card doesn't report busy straight away
+    delay(500);
+
     for _ in 0..100000 {
-        if sd_response() != 0xeee1 {
+        if sd_response(bus) == 0 {
             return Ok(());
         }
     }
@@ -90,3 +405,181 @@ pub unsafe fn wait_sd_response() -> Result<(), ()> {
     // timeout!
     Err(())
 }
+
+/// the addresses nor commands are written to, in bytes from the start of the chip
+pub const NOR_CMD_1: usize = 0x555 * 2;
+pub const NOR_CMD_2: usize = 0x2aa * 2;
+pub const NOR_RESET: u16 = 0xf0;
+/// status bit 5, set when the chip gave up on an erase or a write
+#[cfg(any(test, not(feature = "host")))]
+const NOR_FAILED: u16 = 1 << 5;
+// how long to poll for, an erase can take a few seconds
+#[cfg(any(test, not(feature = "host")))]
+const NOR_ERASE_POLLS: u32 = 4000000;
+#[cfg(any(test, not(feature = "host")))]
+const NOR_PROGRAM_POLLS: u32 = 10000;
+
+#[cfg(any(test, not(feature = "host")))]
+#[derive(Debug)]
+pub enum NorError {
+    /// the chip didn't finish in time, or said it couldn't
+    Timeout { offset: usize },
+    /// what's in nor afterwards isn't what was written
+    Mismatch { offset: usize },
+}
+
+/// page in the part of nor that `offset` is in, returning the address it shows up at
+#[cfg(any(test, not(feature = "host")))]
+#[link_section = ".iwram"]
+unsafe fn map_nor<B: Bus>(bus: &mut B, offset: usize) -> usize {
+    let window = offset & !(NOR_WINDOW_SIZE - 1);
+    set_rompage(bus, (window >> NOR_PAGE_SHIFT) as u16);
+    NOR_WINDOW + offset - window
+}
+
+/// unlock and send a command to whichever chip is paged in
+#[cfg(any(test, not(feature = "host")))]
+#[link_section = ".iwram"]
+unsafe fn nor_command<B: Bus>(bus: &mut B, command: u16) {
+    bus.write(NOR_WINDOW + NOR_CMD_1, 0xaa);
+    bus.write(NOR_WINDOW + NOR_CMD_2, 0x55);
+    bus.write(NOR_WINDOW + NOR_CMD_1, command);
+}
+
+/// poll until `addr` reads back as `expected`, which is when the chip is done with it
+#[cfg(any(test, not(feature = "host")))]
+#[link_section = ".iwram"]
+unsafe fn nor_wait<B: Bus>(bus: &mut B, addr: usize, expected: u16, polls: u32) -> Result<(), ()> {
+    for _ in 0..polls {
+        let status = bus.read(addr);
+        if status == expected {
+            return Ok(());
+        }
+        // it can finish just as it says it failed, so look once more
+        if status & NOR_FAILED != 0 {
+            if bus.read(addr) == expected {
+                return Ok(());
+            }
+            break;
+        }
+    }
+
+    bus.write(NOR_WINDOW, NOR_RESET);
+    Err(())
+}
+
+/// the manufacturer and device ids of the first chip
+#[cfg(not(feature = "host"))]
+#[link_section = ".iwram"]
+pub unsafe fn nor_id<B: Bus>(bus: &mut B) -> (u16, u16) {
+    map_nor(bus, 0);
+    nor_command(bus, 0x90);
+    let id = (bus.read(NOR_WINDOW), bus.read(NOR_WINDOW + 2));
+    bus.write(NOR_WINDOW, NOR_RESET);
+
+    set_rompage(bus, ROMPAGE_PSRAM);
+    id
+}
+
+/// erase every sector that `len` bytes from `offset` touch
+#[cfg(any(test, not(feature = "host")))]
+#[link_section = ".iwram"]
+pub unsafe fn erase_nor<B: Bus>(bus: &mut B, offset: usize, len: usize) -> Result<(), NorError> {
+    assert!(offset + len <= NOR_SIZE, "nor erase past the end");
+
+    let mut result = Ok(());
+    let mut sector = offset & !(NOR_SECTOR_SIZE - 1);
+    while sector < offset + len {
+        let addr = map_nor(bus, sector);
+        nor_command(bus, 0x80);
+        bus.write(NOR_WINDOW + NOR_CMD_1, 0xaa);
+        bus.write(NOR_WINDOW + NOR_CMD_2, 0x55);
+        bus.write(addr, 0x30);
+        if nor_wait(bus, addr, 0xffff, NOR_ERASE_POLLS).is_err() {
+            result = Err(NorError::Timeout { offset: sector });
+            break;
+        }
+        sector += NOR_SECTOR_SIZE;
+    }
+
+    set_rompage(bus, ROMPAGE_PSRAM);
+    result
+}
+
+/// write `src` to erased nor at `offset` a halfword at a time
+#[cfg(any(test, not(feature = "host")))]
+#[link_section = ".iwram"]
+pub unsafe fn program_nor<B: Bus>(bus: &mut B, offset: usize, src: &[u8]) -> Result<(), NorError> {
+    assert!(offset & 1 == 0, "nor writes have to be halfword aligned");
+    assert!(offset + src.len() <= NOR_SIZE, "nor write past the end");
+
+    let mut result = Ok(());
+    let mut mapped = None;
+    for (i, pair) in src.chunks(2).enumerate() {
+        let pos = offset + i * 2;
+        let window = pos & !(NOR_WINDOW_SIZE - 1);
+        if mapped != Some(window) {
+            map_nor(bus, window);
+            mapped = Some(window);
+        }
+
+        // a trailing odd byte leaves the other half erased
+        let value = u16::from_le_bytes([pair[0], *pair.get(1).unwrap_or(&0xff)]);
+        if value == 0xffff {
+            continue;
+        }
+
+        let addr = NOR_WINDOW + pos - window;
+        nor_command(bus, 0xa0);
+        bus.write(addr, value);
+        if nor_wait(bus, addr, value, NOR_PROGRAM_POLLS).is_err() {
+            result = Err(NorError::Timeout { offset: pos });
+            break;
+        }
+    }
+
+    set_rompage(bus, ROMPAGE_PSRAM);
+    result
+}
+
+/// copy out of nor starting at `offset`
+#[cfg(any(test, not(feature = "host")))]
+#[link_section = ".iwram"]
+pub unsafe fn read_nor<B: Bus>(bus: &mut B, offset: usize, dst: &mut [u8]) {
+    assert!(offset & 1 == 0, "nor reads have to be halfword aligned");
+    assert!(offset + dst.len() <= NOR_SIZE, "nor read past the end");
+
+    let mut mapped = None;
+    for (i, pair) in dst.chunks_mut(2).enumerate() {
+        let pos = offset + i * 2;
+        let window = pos & !(NOR_WINDOW_SIZE - 1);
+        if mapped != Some(window) {
+            map_nor(bus, window);
+            mapped = Some(window);
+        }
+
+        let value = bus.read(NOR_WINDOW + pos - window).to_le_bytes();
+        // byte by byte, so nothing gets called out of rom while it's paged out
+        for (byte, value) in pair.iter_mut().zip(value) {
+            *byte = value;
+        }
+    }
+
+    set_rompage(bus, ROMPAGE_PSRAM);
+}
+
+/// check that nor at `offset` holds `src`
+#[cfg(any(test, not(feature = "host")))]
+pub unsafe fn verify_nor<B: Bus>(bus: &mut B, offset: usize, src: &[u8]) -> Result<(), NorError> {
+    let mut piece = [0u8; 512];
+    for (i, expected) in src.chunks(piece.len()).enumerate() {
+        let pos = offset + i * piece.len();
+        let read = &mut piece[..expected.len()];
+        read_nor(bus, pos, read);
+        if let Some(at) = read.iter().zip(expected).position(|(a, b)| a != b) {
+            return Err(NorError::Mismatch { offset: pos + at });
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+//! disks for the tests to run against
+
+use ape_fatfs::fs::{format_volume, FormatVolumeOptions};
+use embedded_io::blocking::Write;
+use std::{vec, vec::Vec};
+
+use crate::disk::RamDisk;
+use crate::fs::{BufferedIo, Pages};
+use crate::sd::Partition;
+
+/// a 4m card
+pub const BLOCKS: u32 = 8192;
+/// where the partition on `fat_image` starts
+pub const START: u32 = 64;
+
+/// an mbr with one formatted fat16 partition from `START` to the end of the disk
+pub fn fat_image() -> Vec<u8> {
+    let mut data = vec![0; BLOCKS as usize * 512];
+    let record = &mut data[0x1be..0x1ce];
+    record[4] = 0x06;
+    record[8..12].copy_from_slice(&START.to_le_bytes());
+    record[12..16].copy_from_slice(&(BLOCKS - START).to_le_bytes());
+    data[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+    let mut disk = RamDisk::new(&mut data);
+    let mut pages = Pages::new();
+    let mut io =
+        BufferedIo::<512, 2048, 4, _>::new(Partition::new(&mut disk, START, BLOCKS), &mut pages);
+    format_volume(&mut io, FormatVolumeOptions::new()).unwrap();
+    io.flush().unwrap();
+    drop(io);
+    data
+}
@@ -2,106 +2,264 @@ use embedded_io::blocking::{Read, ReadExactError, Seek, Write};
 use embedded_io::SeekFrom;
 use embedded_io::{Error, Io};
 
+use core::fmt;
+
 use crate::sd::{BlockIo, Lba};
 
 #[derive(Debug)]
-pub enum ErrorKind {
+pub enum ErrorKind<E> {
     ReadExactError,
+    /// tried to seek before the start of the device
+    InvalidSeek,
+    /// the underlying block device failed
+    BlockIo(E),
 }
 
-impl Error for ErrorKind {
+impl<E: fmt::Debug> Error for ErrorKind<E> {
     fn kind(&self) -> embedded_io::ErrorKind {
         embedded_io::ErrorKind::Other
     }
 }
 
-impl<E> From<ReadExactError<E>> for ErrorKind {
-    fn from(value: ReadExactError<E>) -> Self {
-        Self::ReadExactError
+impl<E> From<ReadExactError<ErrorKind<E>>> for ErrorKind<E> {
+    fn from(value: ReadExactError<ErrorKind<E>>) -> Self {
+        match value {
+            ReadExactError::UnexpectedEof => Self::ReadExactError,
+            ReadExactError::Other(e) => e,
+        }
     }
 }
 
-/// BS: Block Size, PS: Page Size
+/// where a cached page came from, and whether it differs from what's on the disk
+struct Page {
+    lba: Lba,
+    /// how much of the page is actually on the device
+    len: usize,
+    dirty: bool,
+    /// value of the access counter when this page was last touched
+    last_used: u32,
+}
+
+/// the memory for `N` cached pages of `PS` bytes, borrowed by a `BufferedIo` so it doesn't have to
+/// be on the stack
+// aligned for dma
+#[repr(C, align(4))]
+pub struct Pages<const PS: usize, const N: usize>([[u8; PS]; N]);
+
+impl<const PS: usize, const N: usize> Pages<PS, N> {
+    pub const fn new() -> Self {
+        Self([[0; PS]; N])
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u32,
+    pub misses: u32,
+}
+
+/// BS: Block Size, PS: Page Size, N: Number of cached pages
 /// optimally PS would be in terms of BS, but const generics don't allow that yet
 /// BS and PS must be powers of two, and PS must be as large or larger than BS
-pub struct BufferedIo<const BS: usize, const PS: usize, IO: BlockIo<BS>> {
+pub struct BufferedIo<'p, const BS: usize, const PS: usize, const N: usize, IO: BlockIo<BS>> {
     io: IO,
     /// current stream position
-    pos: usize,
-    /// track the current page in the buffer
-    page: Option<(Lba, [u8; PS])>,
+    pos: u64,
+    /// what's in each of `data`, evicted least recently used first
+    pages: [Option<Page>; N],
+    data: &'p mut Pages<PS, N>,
+    /// incremented on every page access
+    tick: u32,
+    stats: CacheStats,
 }
 
-impl<const BS: usize, const PS: usize, IO: BlockIo<BS>> BufferedIo<BS, PS, IO> {
-    pub fn new(io: IO) -> Self {
+impl<'p, const BS: usize, const PS: usize, const N: usize, IO: BlockIo<BS>>
+    BufferedIo<'p, BS, PS, N, IO>
+{
+    pub fn new(io: IO, data: &'p mut Pages<PS, N>) -> Self {
         Self {
             io,
             pos: 0,
-            page: None,
+            pages: core::array::from_fn(|_| None),
+            data,
+            tick: 0,
+            stats: CacheStats::default(),
         }
     }
 
+    #[cfg(feature = "host")]
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
     fn lba(&self) -> Lba {
         // floor to PS, but in terms of BS
-        (self.pos / PS * PS / BS) as Lba
+        (self.pos / PS as u64 * PS as u64 / BS as u64) as Lba
+    }
+
+    /// size of the device in bytes, or as far as an lba reaches if the device doesn't know
+    fn len(&self) -> u64 {
+        self.io
+            .block_count()
+            .map_or(Lba::MAX as u64 + 1, |count| count as u64)
+            * BS as u64
+    }
+
+    /// ensure the page at self.pos is loaded, evicting the least recently used page if needed
+    /// if `overwrite` is set the caller is about to replace the whole page, so don't bother reading it
+    fn load_page(&mut self, overwrite: bool) -> Result<(&mut Page, &mut [u8; PS]), IO::Error> {
+        let lba = self.lba();
+        self.tick = self.tick.wrapping_add(1);
+
+        let index = match self
+            .pages
+            .iter()
+            .position(|page| matches!(page, Some(page) if page.lba == lba))
+        {
+            Some(index) => {
+                self.stats.hits += 1;
+                index
+            }
+            None => {
+                self.stats.misses += 1;
+
+                // empty slots count as used at tick 0, so they get filled first
+                let (index, _) = self
+                    .pages
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, page)| page.as_ref().map_or(0, |page| page.last_used))
+                    .unwrap();
+                self.write_back(index)?;
+
+                // the last page might hang off the end of the device
+                let len = self
+                    .io
+                    .block_count()
+                    .map_or(usize::MAX, |count| (count - lba) as usize)
+                    .saturating_mul(BS)
+                    .min(PS);
+                // read into the page where it'll stay, since a temporary on the stack might not
+                // be aligned well enough for the card's dma
+                self.pages[index] = Some(Page {
+                    lba,
+                    len,
+                    dirty: false,
+                    last_used: 0,
+                });
+                if !overwrite {
+                    if let Err(e) = self.io.read_blocks(lba, &mut self.data.0[index][..len]) {
+                        self.pages[index] = None;
+                        return Err(e);
+                    }
+                }
+
+                index
+            }
+        };
+
+        let page = self.pages[index].as_mut().unwrap();
+        page.last_used = self.tick;
+        Ok((page, &mut self.data.0[index]))
+    }
+
+    /// write a cached page to the disk if it's dirty
+    fn write_back(&mut self, index: usize) -> Result<(), IO::Error> {
+        if let Some(page) = self.pages[index].as_mut().filter(|page| page.dirty) {
+            self.io
+                .write_blocks(page.lba, &self.data.0[index][..page.len])?;
+            page.dirty = false;
+        }
+
+        Ok(())
+    }
+
+    fn write_back_all(&mut self) -> Result<(), IO::Error> {
+        for index in 0..N {
+            self.write_back(index)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<const BS: usize, const PS: usize, const N: usize, IO: BlockIo<BS>> Drop
+    for BufferedIo<'_, BS, PS, N, IO>
+{
+    fn drop(&mut self) {
+        // nowhere to report errors here, call flush first to see them
+        let _ = self.write_back_all();
     }
 }
 
-impl<const BS: usize, const PS: usize, IO: BlockIo<BS>> Io for BufferedIo<BS, PS, IO> {
-    type Error = ErrorKind;
+impl<const BS: usize, const PS: usize, const N: usize, IO: BlockIo<BS>> Io
+    for BufferedIo<'_, BS, PS, N, IO>
+{
+    type Error = ErrorKind<IO::Error>;
 }
 
-impl<const BS: usize, const PS: usize, IO: BlockIo<BS>> Read for BufferedIo<BS, PS, IO> {
+impl<const BS: usize, const PS: usize, const N: usize, IO: BlockIo<BS>> Read
+    for BufferedIo<'_, BS, PS, N, IO>
+{
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        // ensure the page at self.pos is loaded
-        let (lba, page) = self
-            .page
-            .filter(|(lba, _)| *lba == self.lba())
-            .unwrap_or_else(|| {
-                let lba = self.lba();
-                let mut buf = [0; PS];
-                self.io.read_blocks(lba, &mut buf).unwrap();
-                (lba, buf)
-            });
-        self.page = Some((lba, page));
+        let pos = self.pos;
+        if pos >= self.len() {
+            return Ok(0);
+        }
+        let (page, data) = self.load_page(false).map_err(ErrorKind::BlockIo)?;
 
         // offset inside page
-        let offset = self.pos - (lba as usize * BS);
-        let end = PS.min(offset + buf.len());
+        let offset = (pos - page.lba as u64 * BS as u64) as usize;
+        let end = page.len.min(offset + buf.len());
         let len = end - offset;
 
-        buf[..len].copy_from_slice(&page[offset..end]);
-        self.pos += len;
+        buf[..len].copy_from_slice(&data[offset..end]);
+        self.pos += len as u64;
 
         Ok(len)
     }
 }
 
-impl<const BS: usize, const PS: usize, IO: BlockIo<BS>> Write for BufferedIo<BS, PS, IO> {
+impl<const BS: usize, const PS: usize, const N: usize, IO: BlockIo<BS>> Write
+    for BufferedIo<'_, BS, PS, N, IO>
+{
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        self.pos += buf.len();
-        Ok(buf.len())
+        let pos = self.pos;
+        let size = self.len();
+        if pos >= size {
+            return Ok(0);
+        }
+        // writing a whole page doesn't need the old contents, anything less is read-modify-write
+        let overwrite = pos % PS as u64 == 0 && buf.len() as u64 >= (size - pos).min(PS as u64);
+        let (page, data) = self.load_page(overwrite).map_err(ErrorKind::BlockIo)?;
+
+        // offset inside page
+        let offset = (pos - page.lba as u64 * BS as u64) as usize;
+        let end = page.len.min(offset + buf.len());
+        let len = end - offset;
+
+        data[offset..end].copy_from_slice(&buf[..len]);
+        page.dirty = true;
+        self.pos += len as u64;
+
+        Ok(len)
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+        self.write_back_all().map_err(ErrorKind::BlockIo)
     }
 }
+impl<const BS: usize, const PS: usize, const N: usize, IO: BlockIo<BS>> Seek
+    for BufferedIo<'_, BS, PS, N, IO>
+{
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let pos = match pos {
+            SeekFrom::Start(addr) => Some(addr),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or(ErrorKind::InvalidSeek)?;
 
-impl<const BS: usize, const PS: usize, IO: BlockIo<BS>> Seek for BufferedIo<BS, PS, IO> {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64, ErrorKind> {
-        match pos {
-            SeekFrom::Start(addr) => {
-                self.pos = addr as usize;
-            }
-            SeekFrom::End(addr) => {
-                self.pos = usize::MAX - addr as usize;
-            }
-            SeekFrom::Current(addr) => {
-                self.pos += addr as usize;
-            }
-        }
-
-        Ok(self.pos as u64)
+        Ok(self.pos)
     }
 }
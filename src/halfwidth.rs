@@ -1,41 +1,341 @@
 use anstyle_parse::{DefaultCharAccumulator, Params, Parser, Perform};
 use core::fmt::Write;
+use core::ops::Range;
+use core::ptr::{addr_of, addr_of_mut};
+#[cfg(not(feature = "host"))]
 use gba::prelude::*;
 
 const TAB_CHARS: usize = 4;
 
-const ROWS: usize = 26;
-const COLUMNS: usize = 60;
+pub const ROWS: usize = 26;
+pub const COLUMNS: usize = 60;
+/// rows in a screenblock, which the display wraps around
+const LINES: usize = 32;
+/// screenblock entries in a row that hold a column
+const ENTRIES: usize = COLUMNS / 2;
+/// rows kept after they scroll off the top
+const HISTORY_ROWS: usize = 128;
 
 const DEFAULT_FG: u16 = 15;
 const DEFAULT_BG: u16 = 0;
 
+/// a character and its colors, as tile | fg << 8 | bg << 12
+type Cell = u16;
+
+/// rows that scrolled off, oldest first from `history_head - history_len`
+#[link_section = ".ewram_bss"]
+static mut HISTORY: [[Cell; COLUMNS]; HISTORY_ROWS] = [[0; COLUMNS]; HISTORY_ROWS];
+/// what was on the screen before looking back through the history
+#[link_section = ".ewram_bss"]
+static mut LIVE: [[Cell; COLUMNS]; ROWS] = [[0; COLUMNS]; ROWS];
+
 // 96 printable ascii chars, each using half of a 8x8 4bpp tile
+#[cfg(not(feature = "host"))]
 const LIFONT: &'static [u8] = &include_aligned_bytes!("lifont-3x5-as-8x8.img.lz77").0;
 
 // TODO: support for drawing to a region of the charblock/screenblock
 // (i.e. not necessarily taking over the whole display)
 pub struct TextPainter {
+    /// the screenblock row that's at the top of the screen, which moves down to scroll
+    top: usize,
     row: usize,
     col: usize,
     fg: u16,
     bg: u16,
+    /// the rows newlines scroll, which is the whole screen unless DECSTBM says otherwise
+    scroll_top: usize,
+    scroll_bottom: usize,
+    /// cursor and colors put away by SCP or DECSC
+    saved: (usize, usize, u16, u16),
+    history_head: usize,
+    history_len: usize,
+    /// how many rows back through the history we're looking, or 0 for the live screen
+    view: usize,
+    /// stands in for screenblocks 16 to 19, as (tile, palbank)
+    #[cfg(feature = "host")]
+    vram: [[[(u16, u16); 32]; 32]; 4],
 }
 
 impl TextPainter {
     pub const fn new() -> Self {
         Self {
+            top: 0,
             row: 0,
             col: 0,
             fg: DEFAULT_FG,
             bg: DEFAULT_BG,
+            scroll_top: 0,
+            scroll_bottom: ROWS,
+            saved: (0, 0, DEFAULT_FG, DEFAULT_BG),
+            history_head: 0,
+            history_len: 0,
+            view: 0,
+            #[cfg(feature = "host")]
+            vram: [[[(0, 0); 32]; 32]; 4],
         }
     }
 
+    #[cfg(not(feature = "host"))]
     pub fn setup_display(&mut self) {
+        #[cfg(not(feature = "host"))]
+        Self::setup_hardware();
+
+        self.clear();
+    }
+
+    /// blank the screen and go back to the top left
+    #[cfg(any(test, not(feature = "host")))]
+    pub fn clear(&mut self) {
+        self.top = 0;
         self.row = 0;
         self.col = 0;
+        self.scroll_top = 0;
+        self.scroll_bottom = ROWS;
+        self.view = 0;
+
+        // clear screenblocks
+        for screenblock in 16..=19 {
+            for r in 0..32 {
+                for c in 0..32 {
+                    self.put_entry(screenblock, r, c, 0, 0);
+                }
+            }
+        }
+    }
+
+    /// forget the rows that scrolled off, so they can't be looked back at
+    #[cfg(not(feature = "host"))]
+    pub fn clear_history(&mut self) {
+        self.history_len = 0;
+        self.view = 0;
+    }
+
+    /// how far down the backgrounds have to be scrolled, on top of the offset for each row
+    #[cfg(not(feature = "host"))]
+    pub fn scroll_offset(&self) -> u16 {
+        (self.top * 8) as u16
+    }
+
+    /// move the scrolling region up a row, blanking the one that comes in at the bottom
+    ///
+    /// the whole screen scrolls by moving the backgrounds, but anything less has to be copied
+    fn scroll(&mut self) {
+        if self.scroll_top == 0 && self.scroll_bottom == ROWS {
+            self.remember();
+            self.top = (self.top + 1) % LINES;
+            // the row that's just come into view, and the one that's just gone off the top,
+            // which keeps every line outside the screen blank. the last few scanlines show the
+            // top of the line under the bottom row
+            for row in [ROWS - 1, LINES - 1] {
+                let line = self.line(row);
+                for screenblock in 16..=19 {
+                    for c in 0..32 {
+                        self.put_entry(screenblock, line, c, 0, 0);
+                    }
+                }
+            }
+            return;
+        }
+
+        for row in self.scroll_top..self.scroll_bottom - 1 {
+            let (from, to) = (self.line(row + 1), self.line(row));
+            for screenblock in 16..=19 {
+                for c in 0..ENTRIES {
+                    let (tile, palbank) = self.get_entry(screenblock, from, c);
+                    self.put_entry(screenblock, to, c, tile, palbank);
+                }
+            }
+        }
+        self.erase(self.scroll_bottom - 1, 0..COLUMNS);
+    }
+
+    /// keep the top row in the history before it goes
+    fn remember(&mut self) {
+        self.read_row(0, unsafe {
+            &mut (*addr_of_mut!(HISTORY))[self.history_head]
+        });
+        self.history_head = (self.history_head + 1) % HISTORY_ROWS;
+        self.history_len = (self.history_len + 1).min(HISTORY_ROWS);
+    }
+
+    /// look `rows` further back through the history, or forward if it's negative
+    ///
+    /// the live screen is put back once the view gets to the bottom, or as soon as anything
+    /// new is written
+    pub fn scroll_view(&mut self, rows: isize) {
+        let view = self.view.saturating_add_signed(rows).min(self.history_len);
+        if view == self.view {
+            return;
+        }
+        if self.view == 0 {
+            for (row, cells) in unsafe { (*addr_of_mut!(LIVE)).iter_mut() }.enumerate() {
+                self.read_row(row, cells);
+            }
+        }
+        self.view = view;
+        if view == 0 {
+            for (row, cells) in unsafe { (*addr_of!(LIVE)).iter() }.enumerate() {
+                self.write_row(row, cells);
+            }
+            return;
+        }
+
+        // the history and then the live screen, as one long scroll
+        let start = self.history_len - view;
+        for row in 0..ROWS {
+            let i = start + row;
+            let cells = if i < self.history_len {
+                let oldest = self.history_head + HISTORY_ROWS - self.history_len;
+                unsafe { &(*addr_of!(HISTORY))[(oldest + i) % HISTORY_ROWS] }
+            } else {
+                unsafe { &(*addr_of!(LIVE))[i - self.history_len] }
+            };
+            self.write_row(row, cells);
+        }
+
+        // black on white in the top right, so it's clear this isn't the live screen
+        let mut status = [0; COLUMNS];
+        let mut out = CellWriter {
+            cells: &mut status,
+            len: 0,
+            colors: 7 << 12,
+        };
+        let _ = write!(out, " history -{}/{} l/r ", view, self.history_len);
+        let len = out.len;
+        let line = self.line(0);
+        for (i, cell) in status[..len].iter().enumerate() {
+            self.put_cell(line, COLUMNS - len + i, *cell);
+        }
+    }
+
+    /// go back to the live screen if we're looking through the history
+    pub fn show_live(&mut self) {
+        self.scroll_view(-(self.view as isize));
+    }
+
+    fn read_row(&self, row: usize, cells: &mut [Cell; COLUMNS]) {
+        let line = self.line(row);
+        for (col, cell) in cells.iter_mut().enumerate() {
+            let (tile, fg) = self.get_entry(16 + (col & 1), line, col >> 1);
+            let (_, bg) = self.get_entry(18 + (col & 1), line, col >> 1);
+            *cell = tile | fg << 8 | bg << 12;
+        }
+    }
+
+    fn write_row(&mut self, row: usize, cells: &[Cell; COLUMNS]) {
+        let line = self.line(row);
+        for (col, cell) in cells.iter().enumerate() {
+            self.put_cell(line, col, *cell);
+        }
+    }
+
+    fn put_cell(&mut self, line: usize, col: usize, cell: Cell) {
+        self.put_entry(16 + (col & 1), line, col >> 1, cell & 0xff, cell >> 8 & 0xf);
+        self.put_entry(18 + (col & 1), line, col >> 1, 0, cell >> 12);
+    }
+
+    /// blank `cols` of `row`, leaving the current background color
+    fn erase(&mut self, row: usize, cols: Range<usize>) {
+        let line = self.line(row);
+        for col in cols.start..cols.end.min(COLUMNS) {
+            self.put_entry(16 + (col & 1), line, col >> 1, 0, 0);
+            self.put_entry(18 + (col & 1), line, col >> 1, 0, self.bg);
+        }
+    }
+
+    /// the screenblock row that `row` of the screen is on
+    fn line(&self, row: usize) -> usize {
+        (self.top + row) % LINES
+    }
+
+    fn newline(&mut self) {
+        if self.row + 1 == self.scroll_bottom {
+            self.scroll();
+        } else if self.row + 1 < ROWS {
+            self.row += 1;
+        }
+        self.col = 0;
+    }
+
+    /// ED, for 0 below the cursor, 1 above it and 2 the whole screen
+    fn erase_display(&mut self, mode: u16) {
+        let (rows, cols) = match mode {
+            0 => (self.row + 1..ROWS, self.col..COLUMNS),
+            1 => (0..self.row, 0..self.col + 1),
+            2 => (0..ROWS, 0..COLUMNS),
+            _ => return,
+        };
+        for row in rows {
+            self.erase(row, 0..COLUMNS);
+        }
+        self.erase(self.row, cols);
+    }
+
+    /// EL, for 0 right of the cursor, 1 left of it and 2 the whole line
+    fn erase_line(&mut self, mode: u16) {
+        let cols = match mode {
+            0 => self.col..COLUMNS,
+            1 => 0..self.col + 1,
+            2 => 0..COLUMNS,
+            _ => return,
+        };
+        self.erase(self.row, cols);
+    }
 
+    /// put the cursor at a 1-based `row` and `col`, kept on the screen
+    fn move_to(&mut self, row: u16, col: u16) {
+        self.row = (row.max(1) as usize - 1).min(ROWS - 1);
+        self.col = (col.max(1) as usize - 1).min(COLUMNS - 1);
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = (self.row, self.col, self.fg, self.bg);
+    }
+
+    fn restore_cursor(&mut self) {
+        (self.row, self.col, self.fg, self.bg) = self.saved;
+    }
+
+    /// read back one entry of a text screenblock, as (tile, palbank)
+    #[cfg(not(feature = "host"))]
+    fn get_entry(&self, screenblock: usize, row: usize, col: usize) -> (u16, u16) {
+        let entry = TEXT_SCREENBLOCKS
+            .get_frame(screenblock)
+            .unwrap()
+            .get_row(row)
+            .unwrap()
+            .get(col)
+            .unwrap()
+            .read();
+        (entry.tile(), entry.palbank())
+    }
+
+    #[cfg(feature = "host")]
+    fn get_entry(&self, screenblock: usize, row: usize, col: usize) -> (u16, u16) {
+        self.vram[screenblock - 16][row][col]
+    }
+
+    /// write one entry of a text screenblock
+    #[cfg(not(feature = "host"))]
+    fn put_entry(&mut self, screenblock: usize, row: usize, col: usize, tile: u16, palbank: u16) {
+        TEXT_SCREENBLOCKS
+            .get_frame(screenblock)
+            .unwrap()
+            .get_row(row)
+            .unwrap()
+            .get(col)
+            .unwrap()
+            .write(TextEntry::new().with_tile(tile).with_palbank(palbank));
+    }
+
+    #[cfg(feature = "host")]
+    fn put_entry(&mut self, screenblock: usize, row: usize, col: usize, tile: u16, palbank: u16) {
+        self.vram[screenblock - 16][row][col] = (tile, palbank);
+    }
+
+    /// backgrounds, font and palette
+    #[cfg(not(feature = "host"))]
+    fn setup_hardware() {
         DISPCNT.write(
             DisplayControl::new()
                 .with_show_bg0(true)
@@ -87,17 +387,6 @@ impl TextPainter {
         // one tile background
         CHARBLOCK1_4BPP.get(0).unwrap().write([0x00001111; 8]);
 
-        // clear screenblocks
-        for screenblock in 16..=19 {
-            let frame = TEXT_SCREENBLOCKS.get_frame(screenblock).unwrap();
-            for r in 0..32 {
-                let row = frame.get_row(r).unwrap();
-                for cell in row.iter() {
-                    cell.write(TextEntry::new());
-                }
-            }
-        }
-
         const COLORS: [Color; 16] = [
             Color::from_rgb(00, 00, 00), // black
             Color::from_rgb(20, 00, 00), // red
@@ -131,6 +420,7 @@ impl TextPainter {
 
 impl Write for TextPainter {
     fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        self.show_live();
         let mut state = Parser::<DefaultCharAccumulator>::new();
 
         for c in text
@@ -147,30 +437,18 @@ impl Write for TextPainter {
 impl Perform for TextPainter {
     fn print(&mut self, c: char) {
         if self.col >= COLUMNS {
-            self.row += 1;
-            self.col = 0;
+            self.newline();
         }
 
-        let fg_cell = TEXT_SCREENBLOCKS.get_frame(16 + (self.col & 1)).unwrap();
-        let bg_cell = TEXT_SCREENBLOCKS.get_frame(18 + (self.col & 1)).unwrap();
-
-        fg_cell
-            .get_row(self.row)
-            .unwrap()
-            .get(self.col >> 1)
-            .unwrap()
-            .write(
-                TextEntry::new()
-                    .with_tile(c as u16 - 0x20)
-                    .with_palbank(self.fg),
-            );
-
-        bg_cell
-            .get_row(self.row)
-            .unwrap()
-            .get(self.col >> 1)
-            .unwrap()
-            .write(TextEntry::new().with_tile(0).with_palbank(self.bg));
+        let line = self.line(self.row);
+        self.put_entry(
+            16 + (self.col & 1),
+            line,
+            self.col >> 1,
+            c as u16 - 0x20,
+            self.fg,
+        );
+        self.put_entry(18 + (self.col & 1), line, self.col >> 1, 0, self.bg);
 
         self.col += 1;
     }
@@ -184,10 +462,7 @@ impl Perform for TextPainter {
             b'\t' => {
                 self.col = (self.col + 1).next_multiple_of(TAB_CHARS);
             }
-            b'\n' => {
-                self.row += 1;
-                self.col = 0;
-            }
+            b'\n' => self.newline(),
             b'\r' => {
                 self.col = 0;
             }
@@ -204,7 +479,46 @@ impl Perform for TextPainter {
     fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {}
 
     fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: u8) {
+        // private sequences like `CSI ? 25 l` aren't for us
+        if ignore || !intermediates.is_empty() {
+            return;
+        }
+
+        // missing parameters and 0 both mean the default
+        let param = |i: usize, default: u16| match params.iter().nth(i) {
+            Some([n, ..]) if *n != 0 => *n,
+            _ => default,
+        };
+        let (row, col) = (self.row as u16 + 1, self.col as u16 + 1);
+
         match action {
+            // CUU, CUD, CUF and CUB
+            b'A' => self.move_to(row.saturating_sub(param(0, 1)), col),
+            b'B' => self.move_to(row.saturating_add(param(0, 1)), col),
+            b'C' => self.move_to(row, col.saturating_add(param(0, 1))),
+            b'D' => self.move_to(row, col.saturating_sub(param(0, 1))),
+            // CUP and HVP
+            b'H' | b'f' => self.move_to(param(0, 1), param(1, 1)),
+            // CHA
+            b'G' => self.move_to(row, param(0, 1)),
+            // VPA
+            b'd' => self.move_to(param(0, 1), col),
+            // ED and EL, where 0 is a mode rather than the default
+            b'J' => self.erase_display(params.iter().next().map_or(0, |p| p[0])),
+            b'K' => self.erase_line(params.iter().next().map_or(0, |p| p[0])),
+            // SCP and RCP
+            b's' => self.save_cursor(),
+            b'u' => self.restore_cursor(),
+            // DECSTBM, which also homes the cursor
+            b'r' => {
+                let top = param(0, 1) as usize;
+                let bottom = (param(1, ROWS as u16) as usize).min(ROWS);
+                if top < bottom {
+                    self.scroll_top = top - 1;
+                    self.scroll_bottom = bottom;
+                    self.move_to(1, 1);
+                }
+            }
             b'm' => {
                 for param in params.iter() {
                     match param {
@@ -226,5 +540,65 @@ impl Perform for TextPainter {
         }
     }
 
-    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {}
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+        if ignore || !intermediates.is_empty() {
+            return;
+        }
+
+        match byte {
+            // DECSC and DECRC
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            _ => (),
+        }
+    }
+}
+
+/// lays out text as cells, for drawing over the screen without moving the cursor
+struct CellWriter<'a> {
+    cells: &'a mut [Cell; COLUMNS],
+    len: usize,
+    colors: Cell,
+}
+
+impl Write for CellWriter<'_> {
+    fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        for byte in text.bytes() {
+            let cell = self.cells.get_mut(self.len).ok_or(core::fmt::Error)?;
+            *cell = (byte as Cell).saturating_sub(0x20) | self.colors;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use super::{TextPainter, LINES, ROWS};
+
+    #[test]
+    fn scrolling_leaves_the_lines_below_blank() {
+        let mut painter = TextPainter::new();
+        painter.clear();
+        // enough to go all the way around the backgrounds
+        for i in 0..LINES * 2 {
+            writeln!(painter, "line {} with some text on it", i).unwrap();
+        }
+
+        for row in ROWS..LINES {
+            let line = painter.line(row);
+            for screenblock in 16..=19 {
+                for c in 0..32 {
+                    assert_eq!(
+                        painter.get_entry(screenblock, line, c),
+                        (0, 0),
+                        "row {}",
+                        row
+                    );
+                }
+            }
+        }
+    }
 }
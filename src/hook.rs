@@ -0,0 +1,345 @@
+//! hooking the game's interrupt handler, for the reset hotkey and cheats
+//!
+//! the game's handler gets moved to a word the bios doesn't use, so a hook can go in front of it.
+//! on every interrupt the hook looks at the keys, and on l+r+start+select it pages the boot flash
+//! back in and resets into it. on vblank it also runs through the cheats' ops
+
+use log::{info, warn};
+
+use crate::cheat::{Op, OP_LEN};
+use crate::ezflash::{Bus, PSRAM_SIZE};
+use crate::loader::Target;
+use crate::patch::PatchError;
+use crate::rom::{Header, HEADER_LEN};
+use crate::savepatch::{ldr_literal, scan, word, List};
+
+/// where the game's rom shows up
+const ROM: u32 = 0x8000000;
+
+/// where the bios looks for the interrupt handler, and the mirror of it some games use instead
+const IRQ_VECTOR: u32 = 0x3007ffc;
+const IRQ_VECTOR_MIRROR: u32 = 0x3fffffc;
+/// where the game's handler goes instead. the bios only uses it while showing the logo
+const IRQ_HANDLER_SLOT: u32 = 0x3007ff4;
+
+/// how far an arm `ldr rd, [pc, #imm]` can reach either way, which is further than thumb's
+const LDR_RANGE: usize = 4096;
+const MAX_LITERALS: usize = 16;
+
+// the literals in ROUTINES that get filled in
+const ENTRY: usize = 0x10;
+const OPS: usize = 0xec;
+const HANDLER_SLOT: usize = 0xf0;
+/// the branch to the reset, which is left out if the hotkey isn't wanted
+const RESET_CHECK: usize = 0x2c;
+/// `mov r0, r0`
+const NOP: u32 = 0xe1a00000;
+
+/// what to hook into the game
+pub struct Hooks<'a> {
+    pub soft_reset: bool,
+    pub cheats: &'a [Op],
+}
+
+impl Hooks<'_> {
+    #[cfg(not(feature = "host"))]
+    pub fn is_empty(&self) -> bool {
+        !self.soft_reset && self.cheats.is_empty()
+    }
+}
+
+/// arm code that goes at the end of the game, with the entry point branching to the start and
+/// the cheats' ops after it
+///
+/// soft reset clears the interrupt vector, so the hook has to be put back every time the game
+/// starts. resetting copies a trampoline to iwram first, since the game goes away under it
+#[rustfmt::skip]
+const ROUTINES: [u32; 97] = [
+    // start, put the hook in and carry on to the game
+    0xe59f000c, // ldr r0, =0x3007ffc
+    0xe28f100c, // adr r1, hook
+    0xe5801000, // str r1, [r0]
+    0xe51ff004, // ldr pc, =entry
+    0x00000000, // .word entry
+    0x03007ffc, // .word 0x3007ffc
+    // hook, with r0 = 0x4000000 from the bios
+    0xe2801c01, // add r1, r0, #0x100
+    0xe1d113b0, // ldrh r1, [r1, #0x30]
+    0xe1e01001, // mvn r1, r1
+    0xe2011fc3, // and r1, r1, #0x30c
+    0xe3510fc3, // cmp r1, #0x30c
+    0x0a000030, // beq reset
+    0xe2801c02, // add r1, r0, #0x200
+    0xe1d110b2, // ldrh r1, [r1, #2]
+    0xe3110001, // tst r1, #1
+    0x0a000025, // beq chain
+    0xe59fc0a4, // ldr r12, =ops, on vblank
+    // next, an op and its value
+    0xe8bc0006, // ldmia r12!, {r1, r2}
+    0xe1b03e21, // movs r3, r1, lsr #28
+    0x0a000020, // beq done
+    0xe3c1120f, // bic r1, r1, #0xf0000000
+    0xe3530001, // cmp r3, #1
+    0x05c12000, // strbeq r2, [r1]
+    0xe3530002, // cmp r3, #2
+    0x01c120b0, // strheq r2, [r1]
+    0xe3530003, // cmp r3, #3
+    0x05812000, // streq r2, [r1]
+    0xe3530004, // cmp r3, #4
+    0x01d100b0, // ldrheq r0, [r1]
+    0x01800002, // orreq r0, r0, r2
+    0x01c100b0, // strheq r0, [r1]
+    0xe3530005, // cmp r3, #5
+    0x01d100b0, // ldrheq r0, [r1]
+    0x00000002, // andeq r0, r0, r2
+    0x01c100b0, // strheq r0, [r1]
+    0xe3530006, // cmp r3, #6
+    0xbaffffeb, // blt next
+    // the rest skip the next op unless the value matches, or unless it doesn't
+    0x13530009, // cmpne r3, #9
+    0x05d10000, // ldrbeq r0, [r1]
+    0xe3530007, // cmp r3, #7
+    0x1353000a, // cmpne r3, #10
+    0x01d100b0, // ldrheq r0, [r1]
+    0xe3530008, // cmp r3, #8
+    0x1353000b, // cmpne r3, #11
+    0x05910000, // ldreq r0, [r1]
+    0xe1500002, // cmp r0, r2
+    0x1a000002, // bne unequal
+    0xe3530009, // cmp r3, #9
+    0xa28cc008, // addge r12, r12, #8
+    0xeaffffde, // b next
+    // unequal
+    0xe3530009, // cmp r3, #9
+    0xb28cc008, // addlt r12, r12, #8
+    0xeaffffdb, // b next
+    // done
+    0xe3a00301, // mov r0, #0x4000000
+    // chain, to the game's handler if it has one yet
+    0xe59f1010, // ldr r1, =handler_slot
+    0xe5911000, // ldr r1, [r1]
+    0xe3510000, // cmp r1, #0
+    0x112fff11, // bxne r1
+    0xe12fff1e, // bx lr
+    0x00000000, // .word ops
+    0x00000000, // .word handler_slot
+    // reset
+    0xe3a01000, // mov r1, #0
+    0xe5801208, // str r1, [r0, #0x208]
+    0xe28f101c, // adr r1, trampoline
+    0xe3a02403, // mov r2, #0x3000000
+    0xe3a03019, // mov r3, #25
+    0xe491c004, // 1: ldr r12, [r1], #4
+    0xe482c004, // str r12, [r2], #4
+    0xe2533001, // subs r3, r3, #1
+    0x1afffffb, // bne 1b
+    0xe3a02403, // mov r2, #0x3000000
+    0xe12fff12, // bx r2
+    // trampoline, run from iwram
+    0xe59f004c, // ldr r0, =0x9fe0000
+    0xe3a01cd2, // mov r1, #0xd200
+    0xe1c010b0, // strh r1, [r0]
+    0xe3a00302, // mov r0, #0x8000000
+    0xe3a02c15, // mov r2, #0x1500
+    0xe1c020b0, // strh r2, [r0]
+    0xe2800802, // add r0, r0, #0x20000
+    0xe1c010b0, // strh r1, [r0]
+    0xe2800802, // add r0, r0, #0x20000
+    0xe1c020b0, // strh r2, [r0]
+    0xe59f0028, // ldr r0, =0x9880000
+    0xe3a01902, // mov r1, #0x8000
+    0xe1c010b0, // strh r1, [r0]
+    0xe59f0020, // ldr r0, =0x9fc0000
+    0xe1c020b0, // strh r2, [r0]
+    0xe3a000fd, // mov r0, #0xfd
+    0xef010000, // swi #0x010000 (RegisterRamReset everything except iwram)
+    0xe59f0014, // ldr r0, =0x3007ffa
+    0xe3a01000, // mov r1, #0
+    0xe5c01000, // strb r1, [r0]
+    0xef000000, // swi #0x000000 (SoftReset)
+    0x09fe0000, // .word 0x9fe0000
+    0x09880000, // .word 0x9880000
+    0x09fc0000, // .word 0x9fc0000
+    0x03007ffa, // .word 0x3007ffa
+];
+
+/// hook the game's interrupts, so it can be reset back to us or have cheats applied
+pub fn apply<B: Bus>(target: &mut Target<B>, hooks: &Hooks) -> Result<(), PatchError> {
+    if target.size() < HEADER_LEN {
+        return Ok(());
+    }
+    let mut bytes = [0; HEADER_LEN];
+    target.read(0, &mut bytes);
+    let Some(entry) = Header::from_bytes(bytes).entry_point() else {
+        warn!("the game doesn't start with a branch, so it can't be hooked");
+        return Ok(());
+    };
+
+    let base = (target.size() + 3) & !3;
+    let ops = base + ROUTINES.len() * 4;
+    // the ops end with an empty one
+    if ops + (hooks.cheats.len() + 1) * OP_LEN > PSRAM_SIZE {
+        warn!("no room after the game for the reset hotkey or cheats");
+        return Ok(());
+    }
+
+    // everything that sets the handler loads the vector's address from a literal pool, but the
+    // same value can turn up in data too, so only move the ones something loads
+    let mut literals = List::<MAX_LITERALS>::new();
+    let size = target.size() & !3;
+    scan(target, 0..size, 4, 0, |pos, bytes| {
+        if bytes.len() >= 4 && matches!(word(bytes, 0), IRQ_VECTOR | IRQ_VECTOR_MIRROR) {
+            literals.push(pos);
+        }
+    });
+    let mut moved = 0;
+    for literal in literals.iter() {
+        if is_loaded(target, literal) {
+            target.write(literal, &IRQ_HANDLER_SLOT.to_le_bytes())?;
+            moved += 1;
+        }
+    }
+    if moved == 0 {
+        warn!("couldn't find where the game sets its interrupt handler, so it can't be hooked");
+        return Ok(());
+    }
+
+    let mut routines = [0u8; ROUTINES.len() * 4];
+    for (bytes, op) in routines.as_chunks_mut::<4>().0.iter_mut().zip(ROUTINES) {
+        *bytes = op.to_le_bytes();
+    }
+    let mut fill =
+        |at: usize, value: u32| routines[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    fill(ENTRY, entry);
+    fill(OPS, ROM + ops as u32);
+    fill(HANDLER_SLOT, IRQ_HANDLER_SLOT);
+    if !hooks.soft_reset {
+        fill(RESET_CHECK, NOP);
+    }
+    target.write(base, &routines)?;
+
+    for (i, op) in hooks.cheats.iter().chain([&Op::END]).enumerate() {
+        target.write(ops + i * OP_LEN, &op.encode())?;
+    }
+
+    // b start, from two instructions ahead of the header
+    let branch = 0xea000000 | ((base as u32 - 8) >> 2 & 0xffffff);
+    target.write(0, &branch.to_le_bytes())?;
+
+    info!(
+        "hooked the game's interrupts{}, with {} cheat ops",
+        if hooks.soft_reset {
+            ", l+r+start+select resets"
+        } else {
+            ""
+        },
+        hooks.cheats.len()
+    );
+    Ok(())
+}
+
+/// whether an arm or thumb `ldr rd, [pc, #imm]` near `literal` loads it
+fn is_loaded<B: Bus>(target: &mut Target<B>, literal: usize) -> bool {
+    let nearby = literal.saturating_sub(LDR_RANGE)..(literal + LDR_RANGE).min(target.size() & !1);
+    let mut found = false;
+    scan(target, nearby, 2, 2, |pos, bytes| {
+        found |= ldr_literal(pos, bytes) == Some(literal)
+            || pos & 3 == 0 && arm_ldr_literal(pos, bytes) == Some(literal);
+    });
+    found
+}
+
+/// where the literal loaded by an arm `ldr rd, [pc, #imm]` at `pos` is, if that's what's there
+fn arm_ldr_literal(pos: usize, bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    // any condition but the unconditional space, and the offset can go either way
+    let op = word(bytes, 0);
+    if op >> 28 == 0xf || op & 0x0f7f0000 != 0x051f0000 {
+        return None;
+    }
+
+    let offset = op as usize & 0xfff;
+    if op & 1 << 23 != 0 {
+        Some(pos + 8 + offset)
+    } else {
+        (pos + 8).checked_sub(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::{
+        apply, Hooks, ENTRY, HANDLER_SLOT, IRQ_HANDLER_SLOT, IRQ_VECTOR, IRQ_VECTOR_MIRROR, NOP,
+        OPS, RESET_CHECK, ROM, ROUTINES,
+    };
+    use crate::cheat::{Op, OP_LEN};
+    use crate::disk::RamDisk;
+    use crate::loader::Target;
+    use crate::sim::Simulated;
+
+    const GAME_LEN: usize = 0x400;
+
+    fn word(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    /// hook a game that sets its handler from thumb and from arm, and has the vector in some data
+    fn hooked(soft_reset: bool) -> Vec<u8> {
+        let mut game = vec![0; GAME_LEN];
+        game[..4].copy_from_slice(&0xea00002eu32.to_le_bytes());
+        // ldr r0, [pc, #4]
+        game[0x100..0x102].copy_from_slice(&0x4801u16.to_le_bytes());
+        game[0x108..0x10c].copy_from_slice(&IRQ_VECTOR.to_le_bytes());
+        // ldr r1, [pc, #0x10]
+        game[0x200..0x204].copy_from_slice(&0xe59f1010u32.to_le_bytes());
+        game[0x218..0x21c].copy_from_slice(&IRQ_VECTOR_MIRROR.to_le_bytes());
+        game[0x300..0x304].copy_from_slice(&IRQ_VECTOR.to_le_bytes());
+
+        let mut sim = Simulated::new(RamDisk::new(&mut []));
+        let mut target = Target::new(&mut sim);
+        target.write(0, &game).unwrap();
+        let hooks = Hooks {
+            soft_reset,
+            cheats: &[],
+        };
+        apply(&mut target, &hooks).unwrap();
+
+        let mut out = vec![0; target.size()];
+        target.read(0, &mut out);
+        out
+    }
+
+    #[test]
+    fn hook_layout() {
+        let out = hooked(false);
+        let ops = GAME_LEN + ROUTINES.len() * 4;
+        assert_eq!(out.len(), ops + OP_LEN);
+
+        // b start, from two instructions ahead
+        assert_eq!(word(&out, 0), 0xea000000 | (GAME_LEN as u32 - 8) >> 2);
+        assert_eq!(word(&out, GAME_LEN + ENTRY), ROM + 0xc0);
+        assert_eq!(word(&out, GAME_LEN + OPS), ROM + ops as u32);
+        assert_eq!(word(&out, GAME_LEN + HANDLER_SLOT), IRQ_HANDLER_SLOT);
+        assert_eq!(word(&out, GAME_LEN + RESET_CHECK), NOP);
+        assert_eq!(word(&out, GAME_LEN), ROUTINES[0]);
+        assert_eq!(out[ops..], Op::END.encode());
+
+        // only the literals something loads are moved
+        assert_eq!(word(&out, 0x108), IRQ_HANDLER_SLOT);
+        assert_eq!(word(&out, 0x218), IRQ_HANDLER_SLOT);
+        assert_eq!(word(&out, 0x300), IRQ_VECTOR);
+    }
+
+    #[test]
+    fn reset_check_kept_for_the_hotkey() {
+        let out = hooked(true);
+        assert_eq!(
+            word(&out, GAME_LEN + RESET_CHECK),
+            ROUTINES[RESET_CHECK / 4]
+        );
+    }
+}
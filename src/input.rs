@@ -0,0 +1,246 @@
+//! the keypad, sampled once a frame from the vblank interrupt
+//!
+//! the interrupt turns what the keys did into events on a queue, which the menu takes them off
+//! whenever it gets around to it, so nothing is missed while it's busy reading the card
+
+use core::ops::{BitAnd, BitOr};
+
+#[cfg(not(feature = "host"))]
+use gba::prelude::*;
+
+/// how many events are kept before new ones get dropped
+const QUEUE_LEN: usize = 32;
+/// how many hotkeys can be listened for at once
+const MAX_CHORDS: usize = 4;
+
+/// a set of keys, with a bit set for each one that's down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keys(u16);
+
+impl Keys {
+    pub const NONE: Self = Self(0);
+    pub const A: Self = Self(1 << 0);
+    pub const B: Self = Self(1 << 1);
+    pub const SELECT: Self = Self(1 << 2);
+    pub const START: Self = Self(1 << 3);
+    pub const RIGHT: Self = Self(1 << 4);
+    pub const LEFT: Self = Self(1 << 5);
+    pub const UP: Self = Self(1 << 6);
+    pub const DOWN: Self = Self(1 << 7);
+    pub const R: Self = Self(1 << 8);
+    pub const L: Self = Self(1 << 9);
+    pub const ALL: Self = Self(0x3ff);
+    pub const DPAD: Self = Self::UP
+        .union(Self::DOWN)
+        .union(Self::LEFT)
+        .union(Self::RIGHT);
+
+    /// the keys that are down in a KEYINPUT reading, where a clear bit is a pressed key
+    pub const fn from_keyinput(bits: u16) -> Self {
+        Self(!bits & Self::ALL.0)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// the keys in `self` but not `other`
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// the lowest key in the set, on its own
+    const fn first(self) -> Self {
+        Self(self.0 & self.0.wrapping_neg())
+    }
+}
+
+impl BitOr for Keys {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl BitAnd for Keys {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// a key went down
+    Press(Keys),
+    /// a key has been held long enough to count as pressed again
+    Repeat(Keys),
+    /// a key came back up
+    Release(Keys),
+    /// every key of a hotkey is down now, which comes after the presses that made it
+    Chord(Keys),
+}
+
+/// which keys repeat when held, and how fast, in frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repeat {
+    pub keys: Keys,
+    /// before the first repeat
+    pub delay: u8,
+    /// between the ones after that
+    pub interval: u8,
+}
+
+impl Repeat {
+    pub const NONE: Self = Self {
+        keys: Keys::NONE,
+        delay: 0,
+        interval: 0,
+    };
+    /// what feels right for moving through a list
+    pub const MENU: Self = Self {
+        keys: Keys::DPAD.union(Keys::L).union(Keys::R),
+        delay: 20,
+        interval: 4,
+    };
+}
+
+pub struct Input {
+    /// last frame's reading, which a key has to agree with before it counts
+    raw: Keys,
+    held: Keys,
+    repeat: Repeat,
+    /// only the last key pressed repeats, like a keyboard
+    repeating: Keys,
+    countdown: u8,
+    chords: [Keys; MAX_CHORDS],
+    chords_len: usize,
+    queue: [Event; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl Input {
+    pub const fn new() -> Self {
+        Self {
+            raw: Keys::NONE,
+            held: Keys::NONE,
+            repeat: Repeat::NONE,
+            repeating: Keys::NONE,
+            countdown: 0,
+            chords: [Keys::NONE; MAX_CHORDS],
+            chords_len: 0,
+            queue: [Event::Press(Keys::NONE); QUEUE_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+        self.repeating = Keys::NONE;
+    }
+
+    /// report an `Event::Chord` whenever all of `keys` are down, or give up if there are
+    /// already too many
+    pub fn add_chord(&mut self, keys: Keys) -> Option<()> {
+        *self.chords.get_mut(self.chords_len)? = keys;
+        self.chords_len += 1;
+        Some(())
+    }
+
+    /// the keys that are down, as of the last frame
+    pub fn held(&self) -> Keys {
+        self.held
+    }
+
+    /// the oldest event that hasn't been looked at yet
+    pub fn next(&mut self) -> Option<Event> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.queue[self.head];
+        self.head = (self.head + 1) % QUEUE_LEN;
+        self.len -= 1;
+        Some(event)
+    }
+
+    /// forget about any events that haven't been looked at yet
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// take this frame's KEYINPUT reading and queue up whatever changed
+    ///
+    /// a key only changes once two frames in a row agree, which rides out switch bounce
+    #[link_section = ".iwram"]
+    pub fn sample(&mut self, keyinput: u16) {
+        let raw = Keys::from_keyinput(keyinput);
+        let stable = Keys(!(raw.0 ^ self.raw.0));
+        self.raw = raw;
+
+        let last = self.held;
+        self.held = (last & Keys(!stable.0)) | (raw & stable);
+        let pressed = self.held.without(last);
+        let released = last.without(self.held);
+
+        for bit in 0..10 {
+            let key = Keys(1 << bit);
+            if pressed.contains(key) {
+                self.push(Event::Press(key));
+            } else if released.contains(key) {
+                self.push(Event::Release(key));
+            }
+        }
+
+        for i in 0..self.chords_len {
+            let chord = self.chords[i];
+            if self.held.contains(chord) && !last.contains(chord) {
+                self.push(Event::Chord(chord));
+            }
+        }
+
+        let repeatable = pressed & self.repeat.keys;
+        if !repeatable.is_empty() {
+            self.repeating = repeatable.first();
+            self.countdown = self.repeat.delay;
+        } else if !self.held.contains(self.repeating) {
+            self.repeating = Keys::NONE;
+        } else if !self.repeating.is_empty() {
+            self.countdown = self.countdown.saturating_sub(1);
+            if self.countdown == 0 {
+                self.push(Event::Repeat(self.repeating));
+                self.countdown = self.repeat.interval;
+            }
+        }
+    }
+
+    #[link_section = ".iwram"]
+    fn push(&mut self, event: Event) {
+        if self.len == QUEUE_LEN {
+            return;
+        }
+        self.queue[(self.head + self.len) % QUEUE_LEN] = event;
+        self.len += 1;
+    }
+}
+
+/// run `f` without the interrupt getting in, for touching anything it also uses
+#[cfg(not(feature = "host"))]
+pub fn without_irqs<T>(f: impl FnOnce() -> T) -> T {
+    let enabled = IME.read();
+    IME.write(false);
+    let result = f();
+    IME.write(enabled);
+    result
+}
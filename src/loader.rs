@@ -0,0 +1,449 @@
+//! loading a game from the sd card into psram and booting it
+
+#[cfg(not(feature = "host"))]
+use core::{arch::asm, ptr::addr_of};
+
+use embedded_io::blocking::ReadExactError;
+#[cfg(not(feature = "host"))]
+use embedded_io::blocking::{Read, Seek};
+#[cfg(not(feature = "host"))]
+use gba::prelude::*;
+#[cfg(not(feature = "host"))]
+use log::warn;
+
+#[cfg(not(feature = "host"))]
+use crate::emulator::{Emulated, MAX_HEADER_LEN};
+#[cfg(not(feature = "host"))]
+use crate::ezflash::{
+    erase_nor, program_nor, set_rompage, verify_nor, Hardware, NorError, NOR_PAGE_SHIFT,
+    NOR_SECTOR_SIZE, NOR_SIZE,
+};
+use crate::ezflash::{read_psram, write_psram, Bus, PSRAM_SIZE};
+#[cfg(not(feature = "host"))]
+use crate::hook::{self, Hooks};
+use crate::patch::PatchError;
+#[cfg(not(feature = "host"))]
+use crate::patch::{self, PatchKind};
+#[cfg(not(feature = "host"))]
+use crate::rom::{Header, HEADER_LEN};
+#[cfg(not(feature = "host"))]
+use crate::savepatch;
+#[cfg(not(feature = "host"))]
+use crate::savetype::{SaveType, Scanner};
+
+/// the start of the game, which overlaps the part of our rom image we still use so it's copied
+/// in last
+const HEAD_SIZE: usize = 0x10000;
+/// how much of the game is read from the card at a time
+const CHUNK_SIZE: usize = 0x4000;
+
+/// aligned for dma
+#[repr(C, align(4))]
+struct Buffer<const N: usize>([u8; N]);
+
+#[link_section = ".ewram_bss"]
+static mut HEAD: Buffer<HEAD_SIZE> = Buffer([0; HEAD_SIZE]);
+#[link_section = ".ewram_bss"]
+static mut CHUNK: Buffer<CHUNK_SIZE> = Buffer([0; CHUNK_SIZE]);
+
+#[cfg(not(feature = "host"))]
+extern "C" {
+    /// defined by the linker script
+    static __rom_live_end: u8;
+}
+
+#[derive(Debug)]
+pub enum LoadError<E> {
+    Io(E),
+    /// the file ended before its reported size
+    UnexpectedEof,
+    #[cfg(not(feature = "host"))]
+    Empty,
+    /// the game doesn't fit in psram
+    #[cfg(not(feature = "host"))]
+    TooLarge {
+        size: u64,
+    },
+    Patch(PatchError),
+}
+
+impl<E> From<ReadExactError<E>> for LoadError<E> {
+    fn from(value: ReadExactError<E>) -> Self {
+        match value {
+            ReadExactError::UnexpectedEof => Self::UnexpectedEof,
+            ReadExactError::Other(e) => Self::Io(e),
+        }
+    }
+}
+
+impl<E> From<PatchError> for LoadError<E> {
+    fn from(value: PatchError) -> Self {
+        Self::Patch(value)
+    }
+}
+
+/// where the game ends up, which is the head buffer for the start of it and psram for the rest
+pub struct Target<'b, B: Bus> {
+    bus: &'b mut B,
+    /// how much of the game has been written
+    len: usize,
+    /// there's only the one head buffer, so tests running in parallel take turns with it
+    #[cfg(test)]
+    _head: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+static HEAD_USERS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+impl<'b, B: Bus> Target<'b, B> {
+    pub fn new(bus: &'b mut B) -> Self {
+        #[cfg(not(feature = "host"))]
+        {
+            let live_len = unsafe { addr_of!(__rom_live_end) as usize } - 0x8000000;
+            assert!(
+                live_len <= HEAD_SIZE,
+                "rom image is bigger than the head buffer"
+            );
+        }
+
+        Self {
+            bus,
+            len: 0,
+            // a test that panicked while holding it doesn't matter to the next one
+            #[cfg(test)]
+            _head: HEAD_USERS.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    /// cut the game short, or pad it out with zeroes
+    pub fn resize(&mut self, len: usize) -> Result<(), PatchError> {
+        if len > self.len {
+            let zeroes = Buffer([0; 512]);
+            while self.len < len {
+                let piece = (len - self.len).min(zeroes.0.len());
+                self.write(self.len, &zeroes.0[..piece])?;
+            }
+        }
+
+        self.len = len;
+        Ok(())
+    }
+
+    /// write anywhere in the game, padding any gap before `pos` with zeroes
+    pub fn write(&mut self, pos: usize, data: &[u8]) -> Result<(), PatchError> {
+        let end = pos + data.len();
+        if end > PSRAM_SIZE {
+            return Err(PatchError::TooLarge);
+        }
+        if pos > self.len {
+            self.resize(pos)?;
+        }
+
+        let split = data.len().min(HEAD_SIZE.saturating_sub(pos));
+        if split > 0 {
+            unsafe { HEAD.0[pos..pos + split].copy_from_slice(&data[..split]) };
+        }
+        if split < data.len() {
+            self.write_psram(pos + split, &data[split..]);
+        }
+
+        self.len = self.len.max(end);
+        Ok(())
+    }
+
+    /// dma works in halfwords, so unaligned writes have to merge with what's already there
+    fn write_psram(&mut self, pos: usize, data: &[u8]) {
+        let mut done = 0;
+        if pos & 1 == 0 && data.as_ptr() as usize & 1 == 0 {
+            done = data.len() & !1;
+            unsafe { write_psram(self.bus, pos, &data[..done]) };
+        }
+
+        let mut pair = Buffer([0; 2]);
+        let mut piece = Buffer([0; 512]);
+        while done < data.len() {
+            let at = pos + done;
+            let start = at & !1;
+            let len = (data.len() - done).min(piece.0.len() - 2);
+            let end = (at + len + 1) & !1;
+
+            unsafe {
+                if at != start {
+                    read_psram(self.bus, start, &mut pair.0);
+                    piece.0[0] = pair.0[0];
+                }
+                if at + len != end {
+                    read_psram(self.bus, end - 2, &mut pair.0);
+                    piece.0[end - start - 1] = pair.0[1];
+                }
+                piece.0[at - start..at - start + len].copy_from_slice(&data[done..done + len]);
+                write_psram(self.bus, start, &piece.0[..end - start]);
+            }
+            done += len;
+        }
+    }
+
+    /// read back part of what's been written
+    pub fn read(&mut self, pos: usize, buf: &mut [u8]) {
+        let split = buf.len().min(HEAD_SIZE.saturating_sub(pos));
+        if split > 0 {
+            unsafe { buf[..split].copy_from_slice(&HEAD.0[pos..pos + split]) };
+        }
+
+        let mut piece = Buffer([0; 512]);
+        let mut done = split;
+        while done < buf.len() {
+            let at = pos + done;
+            let start = at & !1;
+            let len = (buf.len() - done).min(piece.0.len() - 2);
+            let end = (at + len + 1) & !1;
+
+            unsafe { read_psram(self.bus, start, &mut piece.0[..end - start]) };
+            buf[done..done + len].copy_from_slice(&piece.0[at - start..at - start + len]);
+            done += len;
+        }
+    }
+
+    /// read back the whole game a piece at a time
+    pub fn for_each_chunk(&mut self, mut f: impl FnMut(&[u8])) {
+        let mut pos = 0;
+        while pos < self.len {
+            let len = (self.len - pos).min(CHUNK_SIZE);
+            unsafe {
+                self.read(pos, &mut CHUNK.0[..len]);
+                f(&CHUNK.0[..len]);
+            }
+            pos += len;
+        }
+    }
+}
+
+/// a game that's in psram apart from its head
+#[cfg(not(feature = "host"))]
+pub struct LoadedRom {
+    head_len: usize,
+    /// the whole game, including anything patched on after the end
+    len: usize,
+    /// unless the game is too short to have one
+    pub header: Option<Header>,
+    pub save_type: SaveType,
+}
+
+/// stream `size` bytes of `rom` into psram, applying `patch` if there is one, and `hooks`
+///
+/// the part that would overwrite us is held back until boot
+#[cfg(not(feature = "host"))]
+pub fn load<B: Bus, R: Read + Seek>(
+    bus: &mut B,
+    rom: &mut R,
+    size: u64,
+    patch: Option<(PatchKind, &mut R)>,
+    hooks: &Hooks,
+) -> Result<LoadedRom, LoadError<R::Error>> {
+    if size == 0 {
+        return Err(LoadError::Empty);
+    }
+    if size > PSRAM_SIZE as u64 {
+        return Err(LoadError::TooLarge { size });
+    }
+
+    let size = size as usize;
+    let patched = patch.is_some();
+    let mut target = Target::new(bus);
+    let mut scanner = Scanner::new();
+    match patch {
+        // bps builds the game itself, picking pieces out of the original
+        Some((PatchKind::Bps, patch)) => patch::apply_bps(patch, rom, size, &mut target)?,
+        _ => {
+            stream(rom, size, &mut target, &mut scanner)?;
+
+            match patch {
+                Some((PatchKind::Ips, patch)) => patch::apply_ips(patch, &mut target)?,
+                Some((PatchKind::Ups, patch)) => patch::apply_ups(patch, &mut target)?,
+                _ => (),
+            }
+        }
+    }
+
+    if patched {
+        // the patch could have changed anything, so look at what we ended up with
+        scanner = Scanner::new();
+        target.for_each_chunk(|chunk| scanner.feed(chunk));
+    }
+
+    finish(target, scanner, hooks)
+}
+
+/// load an emulator with its game after it, the way the emulator expects to find it
+#[cfg(not(feature = "host"))]
+pub fn load_emulated<B: Bus, R: Read + Seek>(
+    bus: &mut B,
+    emulated: Emulated<R>,
+    hooks: &Hooks,
+) -> Result<LoadedRom, LoadError<R::Error>> {
+    if emulated.binary_size == 0 || emulated.game_size == 0 {
+        return Err(LoadError::Empty);
+    }
+
+    let mut header = [0; MAX_HEADER_LEN];
+    let header_len = emulated.emulator.game_header(
+        emulated.path,
+        emulated.game_size.min(u32::MAX as u64) as u32,
+        &mut header,
+    );
+    let game_start = emulated
+        .binary_size
+        .next_multiple_of(emulated.emulator.alignment() as u64);
+    let size = game_start + header_len as u64 + emulated.game_size;
+    if size > PSRAM_SIZE as u64 {
+        return Err(LoadError::TooLarge { size });
+    }
+
+    let mut target = Target::new(bus);
+    let mut scanner = Scanner::new();
+    stream(
+        emulated.binary,
+        emulated.binary_size as usize,
+        &mut target,
+        &mut scanner,
+    )?;
+    // the padding up to the game is zeroes
+    target.resize(game_start as usize)?;
+    target.write(target.size(), &header[..header_len])?;
+    stream(
+        emulated.game,
+        emulated.game_size as usize,
+        &mut target,
+        &mut scanner,
+    )?;
+
+    finish(target, scanner, hooks)
+}
+
+/// copy `size` bytes of `rom` to the end of `target`, letting `scanner` see them on the way
+#[cfg(not(feature = "host"))]
+fn stream<B: Bus, R: Read>(
+    rom: &mut R,
+    size: usize,
+    target: &mut Target<B>,
+    scanner: &mut Scanner,
+) -> Result<(), LoadError<R::Error>> {
+    let start = target.size();
+    let mut pos = 0;
+    while pos < size {
+        let len = (size - pos).min(CHUNK_SIZE);
+        unsafe {
+            rom.read_exact(&mut CHUNK.0[..len])?;
+            scanner.feed(&CHUNK.0[..len]);
+            target.write(start + pos, &CHUNK.0[..len])?;
+        }
+        pos += len;
+    }
+    Ok(())
+}
+
+/// patch up what's been loaded so it's ready to boot
+#[cfg(not(feature = "host"))]
+fn finish<B: Bus, E>(
+    mut target: Target<B>,
+    scanner: Scanner,
+    hooks: &Hooks,
+) -> Result<LoadedRom, LoadError<E>> {
+    // this can put routines after the end of the game, so the head is only known afterwards
+    let save_type = scanner.save_type();
+    savepatch::apply(&mut target, save_type)?;
+    if !hooks.is_empty() {
+        hook::apply(&mut target, hooks)?;
+    }
+
+    let mut header = None;
+    if target.size() >= HEADER_LEN {
+        let mut bytes = [0; HEADER_LEN];
+        target.read(0, &mut bytes);
+        let mut parsed = Header::from_bytes(bytes);
+        // patches tend to change the header without fixing this up
+        if parsed.complement != parsed.checksum() {
+            warn!("fixing the header's complement check");
+            parsed.fix_complement();
+            target.write(0, parsed.as_bytes())?;
+        }
+        header = Some(parsed);
+    }
+
+    Ok(LoadedRom {
+        head_len: (target.size().min(HEAD_SIZE) + 1) & !1,
+        len: target.size(),
+        header,
+        save_type,
+    })
+}
+
+#[cfg(not(feature = "host"))]
+impl LoadedRom {
+    /// copy the whole game into nor at `offset`, so it can be booted from there without the card
+    pub fn burn<B: Bus>(&self, bus: &mut B, offset: usize) -> Result<(), NorError> {
+        assert!(
+            offset & (NOR_SECTOR_SIZE - 1) == 0,
+            "nor burns have to start on a sector"
+        );
+        assert!(offset + self.len <= NOR_SIZE, "game doesn't fit in nor");
+
+        unsafe { erase_nor(bus, offset, self.len)? };
+
+        let mut target = Target::new(bus);
+        target.len = self.len;
+        let mut pos = 0;
+        while pos < self.len {
+            let len = (self.len - pos).min(CHUNK_SIZE);
+            unsafe {
+                let chunk = &mut CHUNK.0[..len];
+                target.read(pos, chunk);
+                program_nor(target.bus, offset + pos, chunk)?;
+                verify_nor(target.bus, offset + pos, chunk)?;
+            }
+            pos += len;
+        }
+
+        Ok(())
+    }
+
+    /// copy the head of the game over ourselves and reset into it
+    #[cfg(not(feature = "host"))]
+    #[link_section = ".iwram"]
+    pub unsafe fn boot(self) -> ! {
+        IME.write(false);
+
+        // from here on our rom image is gone, so nothing outside iwram can be touched
+        write_psram(&mut Hardware, 0, HEAD.0.get_unchecked(..self.head_len));
+
+        reset()
+    }
+}
+
+/// page in the game burned to nor at `offset` and reset into it
+#[cfg(not(feature = "host"))]
+#[link_section = ".iwram"]
+pub unsafe fn boot_nor(offset: usize) -> ! {
+    IME.write(false);
+
+    // this swaps our rom image for the game's, so again nothing outside iwram can be touched
+    set_rompage(&mut Hardware, (offset >> NOR_PAGE_SHIFT) as u16);
+
+    reset()
+}
+
+/// reset into whatever's on the cart now, which is the menu unless a game was paged in
+#[cfg(not(feature = "host"))]
+#[link_section = ".iwram"]
+pub unsafe fn reset() -> ! {
+    // RegisterRamReset everything except iwram, which we're still running from
+    asm!("swi #0x01", in("r0") 0b11111101, clobber_abi("C"));
+
+    // have SoftReset jump to the cart rather than ewram
+    (0x3007ffa as *mut u8).write_volatile(0);
+    SoftReset()
+}
@@ -0,0 +1,112 @@
+//! the lines logged so far, so they can be looked at again after a menu has drawn over them
+
+use core::fmt::{self, Write};
+#[cfg(any(test, not(feature = "host")))]
+use core::ptr::addr_of;
+use core::ptr::addr_of_mut;
+
+use log::Level;
+
+/// lines kept, after which the oldest go
+pub const LINES: usize = 64;
+/// bytes kept of each line, which is two rows of the screen
+const LINE_LEN: usize = 120;
+
+/// the text of each line, which only the one log book uses
+#[link_section = ".ewram_bss"]
+static mut TEXT: [[u8; LINE_LEN]; LINES] = [[0; LINE_LEN]; LINES];
+
+pub struct LogBook {
+    /// where the next line goes
+    head: usize,
+    len: usize,
+    /// how much of each line's text is used, and how bad it was
+    lines: [(u8, Level); LINES],
+}
+
+impl LogBook {
+    pub const fn new() -> Self {
+        Self {
+            head: 0,
+            len: 0,
+            lines: [(0, Level::Info); LINES],
+        }
+    }
+
+    /// keep a line, cut short if it's too long
+    pub fn push(&mut self, level: Level, args: fmt::Arguments) {
+        let mut out = LineWriter {
+            text: unsafe { &mut (*addr_of_mut!(TEXT))[self.head] },
+            len: 0,
+        };
+        // running out of room only cuts the line short
+        let _ = out.write_fmt(args);
+        self.lines[self.head] = (out.len as u8, level);
+        self.head = (self.head + 1) % LINES;
+        self.len = (self.len + 1).min(LINES);
+    }
+
+    /// every line kept, oldest first
+    #[cfg(any(test, not(feature = "host")))]
+    pub fn iter(&self) -> impl Iterator<Item = (Level, &str)> + '_ {
+        let oldest = self.head + LINES - self.len;
+        (0..self.len).map(move |i| {
+            let index = (oldest + i) % LINES;
+            let (len, level) = self.lines[index];
+            let text = unsafe { &(*addr_of!(TEXT))[index][..len as usize] };
+            (level, core::str::from_utf8(text).unwrap_or("?"))
+        })
+    }
+}
+
+struct LineWriter<'a> {
+    text: &'a mut [u8; LINE_LEN],
+    len: usize,
+}
+
+impl Write for LineWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut bytes = [0; 4];
+            let bytes = c.encode_utf8(&mut bytes).as_bytes();
+            let Some(dst) = self.text.get_mut(self.len..self.len + bytes.len()) else {
+                return Err(fmt::Error);
+            };
+            dst.copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use std::{format, vec::Vec};
+
+    use super::{LogBook, LINES, LINE_LEN};
+
+    #[test]
+    fn keeps_the_latest_lines() {
+        let mut book = LogBook::new();
+        assert_eq!(book.iter().count(), 0);
+
+        for i in 0..LINES + 3 {
+            book.push(Level::Info, format_args!("line {}", i));
+        }
+        book.push(Level::Warn, format_args!("{:é<1$}", "", LINE_LEN));
+
+        let lines: Vec<_> = book.iter().collect();
+        assert_eq!(lines.len(), LINES);
+        assert_eq!(lines[0], (Level::Info, "line 4"));
+        assert_eq!(
+            lines[LINES - 2],
+            (Level::Info, format!("line {}", LINES + 2).as_str())
+        );
+        // cut short on a char, rather than in the middle of one
+        assert_eq!(
+            lines[LINES - 1],
+            (Level::Warn, "é".repeat(LINE_LEN / 2).as_str())
+        );
+    }
+}
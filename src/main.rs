@@ -2,33 +2,128 @@
     ascii_char,
     const_slice_from_raw_parts_mut,
     int_roundings,
+    slice_as_chunks,
     generic_const_exprs,
     panic_info_message,
     exclusive_range_pattern,
     const_mut_refs
 )]
-#![no_std]
-#![no_main]
+// the host feature builds the driver against a simulated cartridge, for testing on a pc
+#![cfg_attr(not(feature = "host"), no_std)]
+#![cfg_attr(not(feature = "host"), no_main)]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(not(feature = "host"))]
+use ape_fatfs::dir::Dir;
 use ape_fatfs::fs::{FileSystem, FsOptions};
-use ape_mbr::{PartitionId, MBR};
-use core::{fmt::Write, str::from_utf8_unchecked};
-use ezflash::set_led_control;
-use fs::BufferedIo;
+#[cfg(not(feature = "host"))]
+use ape_fatfs::fs::{OemCpConverter, ReadWriteSeek};
+#[cfg(not(feature = "host"))]
+use ape_fatfs::time::TimeProvider;
+#[cfg(not(feature = "host"))]
+use browser::{Action, Browser, Selected};
+#[cfg(not(feature = "host"))]
+use cheatmenu::CheatMenu;
+#[cfg(not(feature = "host"))]
+use clock::ClockEditor;
+#[cfg(not(feature = "host"))]
+use core::fmt;
+use core::fmt::Write;
+#[cfg(not(feature = "host"))]
+use core::ptr::addr_of;
+use core::ptr::addr_of_mut;
+#[cfg(not(feature = "host"))]
+use embedded_io::{blocking::Seek, SeekFrom};
+#[cfg(not(feature = "host"))]
+use emulator::{Emulated, Emulator};
+#[cfg(not(feature = "host"))]
+use ezflash::{set_led_control, Hardware};
+use fs::{BufferedIo, Pages};
+#[cfg(not(feature = "host"))]
 use gba::prelude::*;
 use halfwidth::TextPainter;
-use log::{debug, error, info, trace, warn, Level, Log};
+#[cfg(not(feature = "host"))]
+use input::{without_irqs, Event, Input, Keys, Repeat};
+#[cfg(not(feature = "host"))]
+use log::{debug, error, info, trace, warn};
+use log::{Level, Log};
+use logbook::LogBook;
+use partition::PartitionTable;
 use sd::SdCard;
 
+#[cfg(not(feature = "host"))]
+mod browser;
+#[cfg(any(test, not(feature = "host")))]
+mod cheat;
+#[cfg(not(feature = "host"))]
+mod cheatmenu;
+#[cfg(any(test, not(feature = "host")))]
+mod clock;
+mod disk;
+#[cfg(not(feature = "host"))]
 mod dma;
+#[cfg(any(test, not(feature = "host")))]
+mod emulator;
 mod ezflash;
+#[cfg(test)]
+mod fixture;
 mod fs;
 mod halfwidth;
+#[cfg(any(test, not(feature = "host")))]
+mod hook;
+#[cfg(not(feature = "host"))]
+mod input;
+#[cfg(any(test, not(feature = "host")))]
+mod loader;
+mod logbook;
+#[cfg(any(test, not(feature = "host")))]
+mod multiboot;
+mod partition;
+#[cfg(any(test, not(feature = "host")))]
+mod patch;
+#[cfg(any(test, not(feature = "host")))]
+mod path;
+mod rom;
+mod rtc;
+#[cfg(any(test, not(feature = "host")))]
+mod save;
+#[cfg(any(test, not(feature = "host")))]
+mod savepatch;
+mod savetype;
 mod sd;
+#[cfg(feature = "host")]
+mod sim;
 
 static mut PAINTER: TextPainter = TextPainter::new();
+#[cfg(not(feature = "host"))]
 static LOGGER: ScreenLogger = ScreenLogger;
+/// everything logged, since the screen only keeps what hasn't been cleared
+static mut LOG_BOOK: LogBook = LogBook::new();
+#[cfg(not(feature = "host"))]
+static mut INPUT: Input = Input::new();
+/// the filesystem's cache, which is too big for the stack
+#[cfg(not(feature = "host"))]
+#[link_section = ".ewram_bss"]
+static mut PAGES: Pages<2048, 4> = Pages::new();
+
+/// the screen, for whatever does more than print to it
+///
+/// # Safety
+/// nothing else can be holding it, which the interrupt doesn't since it only reads the scroll
+#[cfg(not(feature = "host"))]
+unsafe fn painter() -> &'static mut TextPainter {
+    &mut *addr_of_mut!(PAINTER)
+}
 
+/// run `f` on the keypad, with the interrupt that fills its queue kept out
+#[cfg(not(feature = "host"))]
+fn with_input<T>(f: impl FnOnce(&mut Input) -> T) -> T {
+    without_irqs(|| f(unsafe { &mut *addr_of_mut!(INPUT) }))
+}
+
+#[cfg(not(feature = "host"))]
 macro_rules! print {
     ($($args:expr),*) => {
         unsafe { write!(PAINTER, $($args),*).unwrap() }
@@ -49,26 +144,32 @@ impl Log for ScreenLogger {
     }
 
     fn log(&self, record: &log::Record) {
-        let color = match record.level() {
-            Level::Error => "\x1b[91m", // bright red
-            Level::Warn => "\x1b[93m",  // bright yellow
-            Level::Info => "\x1b[94m",  // bright blue
-            Level::Debug => "\x1b[95m", // bright magenta
-            Level::Trace => "\x1b[37m", // white
-        };
-        println!("{}{}\x1b[m", color, record.args());
+        unsafe { (*addr_of_mut!(LOG_BOOK)).push(record.level(), *record.args()) };
+        println!("{}{}\x1b[m", color(record.level()), record.args());
     }
 
     fn flush(&self) {}
 }
 
+/// the escape that colors a line logged at `level`
+fn color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[91m", // bright red
+        Level::Warn => "\x1b[93m",  // bright yellow
+        Level::Info => "\x1b[94m",  // bright blue
+        Level::Debug => "\x1b[95m", // bright magenta
+        Level::Trace => "\x1b[37m", // white
+    }
+}
+
+#[cfg(not(feature = "host"))]
 #[allow(unused_must_use)]
 #[panic_handler]
 #[link_section = ".iwram"]
 fn panic_handler(info: &core::panic::PanicInfo) -> ! {
     unsafe {
         // red+green
-        set_led_control(0b10100000);
+        set_led_control(&mut Hardware, 0b10100000);
     }
 
     // black text on red background
@@ -91,6 +192,7 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
     }
 }
 
+#[cfg(not(feature = "host"))]
 #[link_section = ".iwram"]
 extern "C" fn irq_handler(irq: IrqBits) {
     // maximum value of VCOUNT is 227
@@ -111,15 +213,76 @@ extern "C" fn irq_handler(irq: IrqBits) {
         lut
     };
 
+    if irq.vblank() {
+        unsafe { (*addr_of_mut!(INPUT)).sample(KEYINPUT.read().to_u16()) };
+    }
+
     if irq.hblank() {
-        let offset = OFFSET_LUT[VCOUNT.read() as usize];
-        BG0VOFS.write(offset as u16);
-        BG1VOFS.write(offset as u16);
-        BG2VOFS.write(offset as u16);
-        BG3VOFS.write(offset as u16);
+        let offset = OFFSET_LUT[VCOUNT.read() as usize] as u16
+            + unsafe { (*addr_of!(PAINTER)).scroll_offset() };
+        BG0VOFS.write(offset);
+        BG1VOFS.write(offset);
+        BG2VOFS.write(offset);
+        BG3VOFS.write(offset);
+    }
+}
+
+/// mount a disk image through the simulated cartridge and list the root directory
+#[cfg(feature = "host")]
+fn main() {
+    let path = std::env::args().nth(1).expect("usage: ezfode <disk image>");
+    let image = disk::ImageFile::open(&path).expect("couldn't open disk image");
+    let mut card = SdCard::new(sim::Simulated::new(image));
+
+    let partitions = PartitionTable::read(&mut card).expect("couldn't read partition table");
+    for partition in partitions.iter() {
+        std::println!(
+            "partition {}..{} {:?}",
+            partition.start,
+            partition.end,
+            partition.kind
+        );
     }
+    let partition = partitions
+        .default_partition()
+        .expect("no FAT partition found")
+        .open(&mut card);
+    let mut pages = Pages::new();
+    // borrowed rather than handed over, so the cache can be asked how it did afterwards
+    let mut io = BufferedIo::<512, 2048, 4, _>::new(partition, &mut pages);
+    let fs = FileSystem::new(&mut io, FsOptions::new()).expect("couldn't mount filesystem");
+
+    for entry in fs.root_dir().iter() {
+        let entry = entry.expect("couldn't read directory");
+        let name = entry.short_file_name_as_bytes();
+        if name.ends_with(b".GBA") {
+            let save_type = savetype::detect(&mut entry.to_file()).expect("couldn't read game");
+            let header = rom::Header::read(&mut entry.to_file()).ok();
+            std::println!(
+                "{} {} ({:?} save{})",
+                std::string::String::from_utf8_lossy(name),
+                header.as_ref().and_then(|h| h.title()).unwrap_or(""),
+                save_type,
+                match header.map(|h| h.validate()) {
+                    Some(Ok(())) => "",
+                    _ => ", bad header",
+                }
+            );
+        } else {
+            std::println!(
+                "{}{}",
+                std::string::String::from_utf8_lossy(name),
+                if entry.is_dir() { "/" } else { "" }
+            );
+        }
+    }
+
+    fs.unmount().expect("couldn't unmount filesystem");
+    let stats = io.stats();
+    std::println!("cache: {} hits, {} misses", stats.hits, stats.misses);
 }
 
+#[cfg(not(feature = "host"))]
 #[no_mangle]
 extern "C" fn main() -> ! {
     RUST_IRQ_HANDLER.write(Some(irq_handler));
@@ -135,7 +298,7 @@ extern "C" fn main() -> ! {
 
     unsafe {
         // red+green + blue sd indicator
-        set_led_control(0b10110001);
+        set_led_control(&mut Hardware, 0b10110001);
 
         PAINTER.setup_display();
 
@@ -151,41 +314,433 @@ extern "C" fn main() -> ! {
     warn!("this is a warning message");
     error!("this is an error message");
 
-    let mut mbr = MBR::new(BufferedIo::<512, 2048, _>::new(SdCard)).unwrap();
-    let partition = mbr.get_partition(PartitionId::One).unwrap();
-    let fs = FileSystem::new(partition, FsOptions::new()).unwrap();
+    let (maker, device) = unsafe { ezflash::nor_id(&mut Hardware) };
+    debug!("nor flash {:04x}:{:04x}", maker, device);
+
+    let clock = rtc::Rtc::new(Hardware);
+    match clock.read() {
+        Ok(now) => info!("it's {}", now),
+        Err(rtc::RtcError::Stopped) => {
+            let now = rtc::DateTime::DEFAULT;
+            warn!("the clock lost power, setting it to {}", now);
+            clock
+                .set(&now)
+                .unwrap_or_else(|e| warn!("couldn't set the clock: {:?}", e));
+        }
+        Err(e) => warn!("couldn't read the clock: {:?}", e),
+    }
+
+    // without the card, there's still whatever was last burned to nor
+    let mut card = SdCard::new(Hardware);
+    let partitions = PartitionTable::read(&mut card).unwrap_or_else(|e| {
+        fall_back_to_nor(format_args!("couldn't read partition table: {:?}", e))
+    });
+    for partition in partitions.iter() {
+        debug!(
+            "partition {}..{} {:?}",
+            partition.start, partition.end, partition.kind
+        );
+    }
+    let partition = partitions
+        .default_partition()
+        .unwrap_or_else(|| fall_back_to_nor(format_args!("no FAT partition found")));
+    let partition_start = partition.start;
+    let partition = partition.open(&mut card);
+    let fs = FileSystem::new(
+        BufferedIo::<512, 2048, 4, _>::new(partition, unsafe { &mut *addr_of_mut!(PAGES) }),
+        FsOptions::new().time_provider(clock),
+    )
+    .unwrap_or_else(|e| fall_back_to_nor(format_args!("couldn't mount filesystem: {:?}", e)));
 
     unsafe {
         // green + blue sd indicator
-        set_led_control(0b10010001);
-    }
-
-    {
-        let mut pos = 0;
-        let mut bytes = [0u8; 512];
-        for entry in fs.root_dir().iter() {
-            let entry = entry.unwrap();
-            if let Some(name) = entry.long_file_name_as_ucs2_units() {
-                pos += ucs2::decode(name, &mut bytes[pos..]).unwrap()
-            } else {
-                let name = entry.short_file_name_as_bytes();
-                bytes[pos..pos + name.len()].copy_from_slice(&name);
-                pos += name.len();
+        set_led_control(&mut Hardware, 0b10010001);
+    }
+
+    // sram still holds whatever the last game saved
+    save::backup_pending(&fs.root_dir(), &mut Hardware)
+        .unwrap_or_else(|e| fatal(format_args!("couldn't back up save: {:?}", e)));
+
+    // the browser takes over the screen until a game is picked
+    let Selected { entry, path } = {
+        let mut browser = Browser::new(fs.root_dir());
+        // keys still held from booting don't count as presses
+        with_input(|input| {
+            input.set_repeat(Repeat::MENU);
+            input.clear();
+        });
+        let mut redraw = true;
+        'browse: loop {
+            if redraw {
+                let painter = unsafe { painter() };
+                painter.clear();
+                browser.draw(painter).unwrap();
+            }
+            VBlankIntrWait();
+
+            redraw = false;
+            while let Some(event) = with_input(|input| input.next()) {
+                let (Event::Press(key) | Event::Repeat(key)) = event else {
+                    continue;
+                };
+                let action = match key {
+                    Keys::UP => Action::Up,
+                    Keys::DOWN => Action::Down,
+                    Keys::L => Action::PageUp,
+                    Keys::R => Action::PageDown,
+                    Keys::A => Action::Open,
+                    Keys::B => Action::Back,
+                    Keys::START => {
+                        set_clock();
+                        redraw = true;
+                        continue;
+                    }
+                    Keys::SELECT => {
+                        if let Some(path) = browser.highlighted() {
+                            edit_cheats(&fs.root_dir(), path.as_str());
+                        }
+                        redraw = true;
+                        continue;
+                    }
+                    Keys::RIGHT => {
+                        show_log();
+                        redraw = true;
+                        continue;
+                    }
+                    _ => continue,
+                };
+                redraw = true;
+                if let Some(selected) = browser.handle(action) {
+                    break 'browse selected;
+                }
+            }
+        }
+    };
+    unsafe { painter().clear() };
+    let name = path.as_str();
+    info!("loading {}", name);
+
+    // a multiboot image can be named like any other game, so those are looked inside too
+    let is_multiboot = multiboot::is_multiboot(name)
+        || (Emulator::for_path(name).is_none()
+            && multiboot::runs_from_ewram(&mut entry.to_file(), entry.len())
+                .unwrap_or_else(|e| fatal(format_args!("couldn't read game: {:?}", e))));
+    if is_multiboot {
+        let image = multiboot::Multiboot::find(&mut entry.to_file(), entry.len(), partition_start)
+            .unwrap_or_else(|e| fatal(format_args!("couldn't load multiboot image: {:?}", e)));
+        info!("booting {} byte multiboot image", image.size());
+        fs.unmount()
+            .unwrap_or_else(|e| fatal(format_args!("couldn't unmount filesystem: {:?}", e)));
+        unsafe { image.boot() }
+    }
+
+    // holding l burns the game to nor, so it can be played without the card, and holding r adds
+    // the reset hotkey
+    let keys = with_input(|input| input.held());
+    // burning replaces whatever game was there, so it's asked about first
+    let burn = keys.contains(Keys::L)
+        && confirm(format_args!(
+            "burn {} to nor flash, replacing what's there?",
+            name
+        ));
+
+    let rom = {
+        let root = fs.root_dir();
+        // patches for emulated games would be for the game rather than what ends up in psram
+        let emulator = Emulator::for_path(name);
+        let mut patch = match emulator {
+            Some(_) => None,
+            None => patch::find(&root, name)
+                .unwrap_or_else(|e| fatal(format_args!("couldn't look for a patch: {:?}", e))),
+        };
+        if let Some((kind, _)) = patch {
+            info!("applying {:?} patch", kind);
+        }
+
+        // a broken cheat file shouldn't stop the game from running
+        let cheats = cheat::load(&root, name).unwrap_or_else(|e| {
+            warn!("couldn't read cheats: {:?}", e);
+            None
+        });
+        for cheat in cheats.iter().flat_map(|cheats| cheats.iter()) {
+            info!(
+                "cheat {} {}",
+                if cheat.enabled { "on: " } else { "off:" },
+                cheat.name()
+            );
+        }
+        let hooks = hook::Hooks {
+            soft_reset: keys.contains(Keys::R),
+            cheats: cheats.as_ref().map_or(&[], |cheats| cheats.ops()),
+        };
+
+        let rom = match emulator {
+            Some(emulator) => {
+                let path = emulator.path();
+                info!("running it in {}", path);
+                let mut binary = root
+                    .open_file(path)
+                    .unwrap_or_else(|e| fatal(format_args!("couldn't open {}: {:?}", path, e)));
+                let binary_size = binary
+                    .seek(SeekFrom::End(0))
+                    .and_then(|size| binary.seek(SeekFrom::Start(0)).map(|_| size))
+                    .unwrap_or_else(|e| fatal(format_args!("couldn't read {}: {:?}", path, e)));
+                let emulated = Emulated {
+                    emulator,
+                    binary: &mut binary,
+                    binary_size,
+                    game: &mut entry.to_file(),
+                    game_size: entry.len(),
+                    path: name,
+                };
+                loader::load_emulated(&mut Hardware, emulated, &hooks)
             }
-            if entry.is_dir() {
-                bytes[pos..pos + 1].copy_from_slice("/".as_bytes());
-                pos += 1;
+            None => loader::load(
+                &mut Hardware,
+                &mut entry.to_file(),
+                entry.len(),
+                patch.as_mut().map(|(kind, file)| (*kind, file)),
+                &hooks,
+            ),
+        }
+        .unwrap_or_else(|e| fatal(format_args!("couldn't load game: {:?}", e)));
+        if let Some(header) = &rom.header {
+            info!(
+                "{} ({}) version {}",
+                header.title().unwrap_or("untitled"),
+                header.game_code().unwrap_or("????"),
+                header.version
+            );
+            if let Err(e) = header.validate() {
+                warn!("bad header: {:?}", e);
             }
-            bytes[pos..pos + 1].copy_from_slice("\n".as_bytes());
-            pos += 1;
         }
+        info!("save type: {:?}", rom.save_type);
 
-        info!("{}", unsafe { from_utf8_unchecked(&bytes) });
+        save::restore(&root, &mut Hardware, name, rom.save_type)
+            .unwrap_or_else(|e| fatal(format_args!("couldn't restore save: {:?}", e)));
+        rom
+    };
+
+    if burn {
+        info!("burning to nor flash");
+        rom.burn(&mut Hardware, 0)
+            .unwrap_or_else(|e| fatal(format_args!("couldn't burn game: {:?}", e)));
     }
 
-    loop {}
+    // make sure the save record is written out
+    fs.unmount()
+        .unwrap_or_else(|e| fatal(format_args!("couldn't unmount filesystem: {:?}", e)));
+
+    unsafe {
+        if burn {
+            loader::boot_nor(0)
+        } else {
+            rom.boot()
+        }
+    }
 }
 
+/// show the clock and let it be set, until it's set or left alone with b
+#[cfg(not(feature = "host"))]
+fn set_clock() {
+    let clock = rtc::Rtc::new(Hardware);
+    let mut editor = ClockEditor::new(clock.read());
+    with_input(|input| input.clear());
+    let mut redraw = true;
+    loop {
+        if redraw {
+            let painter = unsafe { painter() };
+            painter.clear();
+            editor.draw(painter).unwrap();
+        }
+        VBlankIntrWait();
+
+        redraw = false;
+        while let Some(event) = with_input(|input| input.next()) {
+            let (Event::Press(key) | Event::Repeat(key)) = event else {
+                continue;
+            };
+            let action = match key {
+                Keys::LEFT => clock::Action::Left,
+                Keys::RIGHT => clock::Action::Right,
+                Keys::UP => clock::Action::Up,
+                Keys::DOWN => clock::Action::Down,
+                Keys::A => {
+                    let time = editor.time();
+                    match clock.set(&time) {
+                        Ok(()) => info!("set the clock to {}", time),
+                        Err(e) => warn!("couldn't set the clock: {:?}", e),
+                    }
+                    return;
+                }
+                Keys::B => return,
+                _ => continue,
+            };
+            editor.handle(action);
+            redraw = true;
+        }
+    }
+}
+
+/// let the cheats for `rom_path` be turned on and off, until b goes back
+#[cfg(not(feature = "host"))]
+fn edit_cheats<IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter>(
+    root: &Dir<IO, TP, OCC>,
+    rom_path: &str,
+) {
+    let cheats = cheat::load(root, rom_path).unwrap_or_else(|e| {
+        warn!("couldn't read cheats: {:?}", e);
+        None
+    });
+    let mut menu = CheatMenu::new(cheats);
+    with_input(|input| input.clear());
+    let mut redraw = true;
+    loop {
+        if redraw {
+            let painter = unsafe { painter() };
+            painter.clear();
+            menu.draw(painter, rom_path).unwrap();
+        }
+        VBlankIntrWait();
+
+        redraw = false;
+        while let Some(event) = with_input(|input| input.next()) {
+            let (Event::Press(key) | Event::Repeat(key)) = event else {
+                continue;
+            };
+            let action = match key {
+                Keys::UP => Action::Up,
+                Keys::DOWN => Action::Down,
+                Keys::L => Action::PageUp,
+                Keys::R => Action::PageDown,
+                Keys::A => Action::Open,
+                Keys::B => return,
+                _ => continue,
+            };
+            redraw = true;
+            if let Some((index, enabled)) = menu.handle(action) {
+                match cheat::set_enabled(root, rom_path, index, enabled) {
+                    Ok(()) => menu.set_enabled(index, enabled),
+                    Err(e) => {
+                        warn!("couldn't save cheat: {:?}", e);
+                        menu.set_problem("couldn't save cheat");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// look back through everything logged so far, until b goes back
+#[cfg(not(feature = "host"))]
+fn show_log() {
+    unsafe {
+        painter().clear();
+        // only the log is scrolled back through, not earlier replays of it
+        painter().clear_history();
+    }
+    for (level, line) in unsafe { (*addr_of!(LOG_BOOK)).iter() } {
+        println!("{}{}\x1b[m", color(level), line);
+    }
+
+    with_input(|input| input.clear());
+    const HALF_PAGE: isize = halfwidth::ROWS as isize / 2;
+    loop {
+        VBlankIntrWait();
+        while let Some(event) = with_input(|input| input.next()) {
+            match event {
+                Event::Press(Keys::L) | Event::Repeat(Keys::L) => unsafe {
+                    painter().scroll_view(HALF_PAGE)
+                },
+                Event::Press(Keys::R) | Event::Repeat(Keys::R) => unsafe {
+                    painter().scroll_view(-HALF_PAGE)
+                },
+                Event::Press(Keys::B) => return,
+                _ => (),
+            }
+        }
+    }
+}
+
+/// offer to boot the game in nor instead, since the card can't be used
+#[cfg(not(feature = "host"))]
+fn fall_back_to_nor(args: fmt::Arguments) -> ! {
+    let mut bytes = [0; rom::HEADER_LEN];
+    unsafe { ezflash::read_nor(&mut Hardware, 0, &mut bytes) };
+    let header = rom::Header::from_bytes(bytes);
+    if header.validate().is_err() {
+        fatal(args);
+    }
+
+    error!("{}", args);
+    let title = header.title().unwrap_or("untitled");
+    if !confirm(format_args!("boot {} from nor flash?", title)) {
+        fatal(format_args!("not booting from nor flash"));
+    }
+
+    info!("booting {} from nor flash", title);
+    unsafe { loader::boot_nor(0) }
+}
+
+/// ask a question and wait for a to answer yes or b to answer no
+#[cfg(not(feature = "host"))]
+fn confirm(question: fmt::Arguments) -> bool {
+    println!("{} a: yes, b: no", question);
+    // a key that was already down doesn't answer it
+    with_input(|input| input.clear());
+    loop {
+        VBlankIntrWait();
+        while let Some(event) = with_input(|input| input.next()) {
+            match event {
+                Event::Press(Keys::A) => return true,
+                Event::Press(Keys::B) => return false,
+                _ => (),
+            }
+        }
+    }
+}
+
+/// log an error and stop, keeping the display running so it can be read
+#[cfg(not(feature = "host"))]
+fn fatal(args: fmt::Arguments) -> ! {
+    unsafe {
+        // red+green
+        set_led_control(&mut Hardware, 0b10100000);
+    }
+
+    error!("{}", args);
+
+    // the same hotkey as in games goes back to the menu
+    const RESET: Keys = Keys::L
+        .union(Keys::R)
+        .union(Keys::START)
+        .union(Keys::SELECT);
+    with_input(|input| {
+        input.set_repeat(Repeat::MENU);
+        input.add_chord(RESET);
+    });
+    // l and r look back through whatever scrolled off before this
+    const HALF_PAGE: isize = halfwidth::ROWS as isize / 2;
+    loop {
+        VBlankIntrWait();
+        while let Some(event) = with_input(|input| input.next()) {
+            match event {
+                Event::Chord(RESET) => unsafe { loader::reset() },
+                Event::Press(Keys::L) | Event::Repeat(Keys::L) => unsafe {
+                    painter().scroll_view(HALF_PAGE)
+                },
+                Event::Press(Keys::R) | Event::Repeat(Keys::R) => unsafe {
+                    painter().scroll_view(-HALF_PAGE)
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+/// in iwram, since the sd driver waits with it while ewram is being loaded over
+#[link_section = ".iwram"]
 pub fn delay(count: u32) {
     let mut i = count;
     let i = &mut i as *mut u32;
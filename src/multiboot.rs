@@ -0,0 +1,314 @@
+//! multiboot images, which run from ewram rather than the cart
+//!
+//! we run from ewram ourselves, so the image can't be read through the filesystem into place.
+//! instead the filesystem finds where the file's clusters are on the card, and an iwram routine
+//! reads those sectors straight over us once nothing else is needed
+
+#[cfg(not(feature = "host"))]
+use core::arch::asm;
+
+use ape_fatfs::error::Error;
+use ape_fatfs::file::File;
+use ape_fatfs::fs::{OemCpConverter, ReadWriteSeek};
+use ape_fatfs::time::TimeProvider;
+use embedded_io::blocking::Read;
+#[cfg(not(feature = "host"))]
+use gba::prelude::*;
+
+use crate::delay;
+#[cfg(not(feature = "host"))]
+use crate::ezflash::Hardware;
+use crate::ezflash::{
+    sd_disable, sd_enable, sd_read_request, sd_read_state, set_rompage, wait_sd_response, Bus,
+    ROMPAGE_OS, ROMPAGE_PSRAM,
+};
+use crate::sd::Lba;
+
+/// where multiboot images are built to run, and how much room there is
+pub const EWRAM: usize = 0x2000000;
+pub const MAX_SIZE: u64 = 0x40000;
+/// where games on the cart are built to run
+const ROM: u64 = 0x8000000;
+/// how much of the start of a game to look through for pointers, which covers the startup code
+const SCAN_LEN: usize = 0x1000;
+/// how many separate pieces of the card the file can be in
+const MAX_RUNS: usize = 64;
+const BLOCK_SIZE: u64 = 512;
+
+#[derive(Debug)]
+pub enum MultibootError<E> {
+    Fs(Error<E>),
+    Empty,
+    /// the image doesn't fit in ewram
+    TooLarge {
+        size: u64,
+    },
+    /// the file is split into more pieces than we keep track of
+    Fragmented,
+    /// a cluster doesn't start on a block, which only happens with odd sector sizes
+    Misaligned,
+}
+
+impl<E> From<Error<E>> for MultibootError<E> {
+    fn from(value: Error<E>) -> Self {
+        Self::Fs(value)
+    }
+}
+
+/// whether `name` is a multiboot image, going by the usual ways of naming them
+#[cfg(not(feature = "host"))]
+pub fn is_multiboot(name: &str) -> bool {
+    let name = name.as_bytes();
+    [&b".mb"[..], b"_mb.gba", b".mb.gba"].iter().any(|suffix| {
+        name.len() >= suffix.len() && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+    })
+}
+
+/// whether a game named like any other is really a multiboot image, going by where it expects
+/// to run
+///
+/// the startup code has to know where it is, so a game built for the cart has pointers into
+/// itself at 0x8000000 near the start, where a multiboot image's point into ewram instead
+pub fn runs_from_ewram<R: Read>(rom: &mut R, size: u64) -> Result<bool, R::Error> {
+    if size > MAX_SIZE {
+        return Ok(false);
+    }
+
+    let mut head = [0u8; SCAN_LEN];
+    let mut len = 0;
+    while len < head.len() {
+        let read = rom.read(&mut head[len..])?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+
+    // both start with an arm branch over the header
+    if len < 4 || head[3] != 0xea {
+        return Ok(false);
+    }
+
+    let (mut ewram, mut cart) = (0, 0);
+    for word in head[..len].as_chunks::<4>().0 {
+        let word = u32::from_le_bytes(*word) as u64;
+        if (EWRAM as u64..EWRAM as u64 + size).contains(&word) {
+            ewram += 1;
+        } else if (ROM..ROM + size).contains(&word) {
+            cart += 1;
+        }
+    }
+    Ok(ewram > cart)
+}
+
+/// blocks of the card that hold part of the image, in order
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    lba: Lba,
+    blocks: u32,
+}
+
+/// where a multiboot image is on the card
+pub struct Multiboot {
+    runs: [Run; MAX_RUNS],
+    len: usize,
+    size: u64,
+}
+
+impl Multiboot {
+    /// find the blocks that make up `file`, on a filesystem starting at `start` on the card
+    pub fn find<IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter>(
+        file: &mut File<IO, TP, OCC>,
+        size: u64,
+        start: Lba,
+    ) -> Result<Self, MultibootError<IO::Error>> {
+        if size == 0 {
+            return Err(MultibootError::Empty);
+        }
+        if size > MAX_SIZE {
+            return Err(MultibootError::TooLarge { size });
+        }
+
+        let mut image = Self {
+            runs: [Run { lba: 0, blocks: 0 }; MAX_RUNS],
+            len: 0,
+            size,
+        };
+        for extent in file.extents() {
+            let extent = extent?;
+            if extent.offset % BLOCK_SIZE != 0 {
+                return Err(MultibootError::Misaligned);
+            }
+            let lba = start + (extent.offset / BLOCK_SIZE) as Lba;
+            // the end of the last cluster gets read too, which is still inside ewram
+            let blocks = (extent.size as u64).div_ceil(BLOCK_SIZE) as u32;
+
+            // clusters usually follow on from each other, so they can be read in one go
+            match image.runs[..image.len].last_mut() {
+                Some(run) if run.lba + run.blocks == lba => run.blocks += blocks,
+                _ => {
+                    let run = image
+                        .runs
+                        .get_mut(image.len)
+                        .ok_or(MultibootError::Fragmented)?;
+                    *run = Run { lba, blocks };
+                    image.len += 1;
+                }
+            }
+        }
+
+        Ok(image)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// read the whole image to `dst`, straight from the card a few blocks at a time
+    ///
+    /// this runs while ewram is being overwritten, so it can't call anything outside iwram. it
+    /// only calls `.iwram` fns and the bus, and sticks to plain loops, raw pointers and wrapping
+    /// arithmetic, so there's no bounds check, overflow check or iterator left out of line
+    #[link_section = ".iwram"]
+    pub unsafe fn read<B: Bus>(&self, bus: &mut B, dst: *mut u8) -> bool {
+        set_rompage(bus, ROMPAGE_OS);
+        sd_enable(bus);
+
+        let mut ok = true;
+        let mut dst = dst;
+        let mut index = 0;
+        'runs: while index < self.len {
+            let run = *self.runs.as_ptr().add(index);
+            let mut done = 0;
+            while done < run.blocks {
+                let blocks = if run.blocks - done < 4 {
+                    run.blocks - done
+                } else {
+                    4
+                };
+
+                // try three times to read, like the sd driver
+                let mut tries = 0;
+                loop {
+                    sd_enable(bus);
+                    sd_read_request(bus, run.lba.wrapping_add(done), blocks as u16);
+                    sd_read_state(bus);
+                    if let Ok(()) = wait_sd_response(bus) {
+                        break;
+                    }
+                    tries += 1;
+                    if tries == 3 {
+                        ok = false;
+                        break 'runs;
+                    }
+                    delay(5000);
+                }
+
+                sd_enable(bus);
+                let len = blocks as usize * BLOCK_SIZE as usize;
+                bus.read_sd_buffer(core::slice::from_raw_parts_mut(dst, len));
+                dst = dst.wrapping_add(len);
+                done = done.wrapping_add(blocks);
+            }
+            index = index.wrapping_add(1);
+        }
+
+        sd_disable(bus);
+        set_rompage(bus, ROMPAGE_PSRAM);
+        ok
+    }
+
+    /// read the image over ourselves and start it with everything else reset
+    ///
+    /// if the card fails partway through, there's nothing left to report it with, so this
+    /// resets back into the menu instead
+    #[cfg(not(feature = "host"))]
+    #[link_section = ".iwram"]
+    pub unsafe fn boot(&self) -> ! {
+        IME.write(false);
+
+        if !self.read(&mut Hardware, EWRAM as *mut u8) {
+            crate::loader::reset();
+        }
+
+        // RegisterRamReset everything except ewram, which now holds the image, and iwram
+        asm!("swi #0x01", in("r0") 0b11111100, clobber_abi("C"));
+
+        // have SoftReset jump to ewram, which also clears the registers
+        (0x3007ffa as *mut u8).write_volatile(1);
+        SoftReset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ape_fatfs::fs::{FileSystem, FsOptions};
+    use embedded_io::blocking::Write;
+    use std::{vec, vec::Vec};
+
+    use super::{runs_from_ewram, Multiboot, EWRAM, ROM};
+    use crate::disk::RamDisk;
+    use crate::fixture::{fat_image, START};
+    use crate::fs::{BufferedIo, Pages};
+    use crate::partition::PartitionTable;
+    use crate::sim::Simulated;
+
+    /// a branch over the header, then startup code with a pointer into wherever it runs from
+    fn image(base: u64) -> Vec<u8> {
+        let mut image = vec![0; 0x400];
+        image[..4].copy_from_slice(&0xea00002eu32.to_le_bytes());
+        image[0xe0..0xe4].copy_from_slice(&0xe59f0010u32.to_le_bytes());
+        image[0xf8..0xfc].copy_from_slice(&(base as u32 + 0x100).to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn tells_multiboot_from_cart() {
+        let multiboot = image(EWRAM as u64);
+        let cart = image(ROM);
+        assert!(runs_from_ewram(&mut &multiboot[..], multiboot.len() as u64).unwrap());
+        assert!(!runs_from_ewram(&mut &cart[..], cart.len() as u64).unwrap());
+        // and anything that doesn't start with a branch isn't looked at any further
+        assert!(!runs_from_ewram(&mut &[0u8; 0x400][..], 0x400).unwrap());
+    }
+
+    #[test]
+    fn reads_every_run() {
+        let mut data = fat_image();
+        let expected: Vec<u8> = (0..70_001u32).map(|i| (i * 3 + i / 509) as u8).collect();
+
+        let image = {
+            let mut disk = RamDisk::new(&mut data);
+            let info = *PartitionTable::read(&mut disk)
+                .unwrap()
+                .default_partition()
+                .unwrap();
+            let mut pages = Pages::new();
+            let io = BufferedIo::<512, 2048, 4, _>::new(info.open(&mut disk), &mut pages);
+            let fs = FileSystem::new(io, FsOptions::new()).unwrap();
+            let root = fs.root_dir();
+
+            // writing two files a bit at a time splits them both into pieces
+            let mut game = root.create_file("GAME.MB").unwrap();
+            let mut other = root.create_file("OTHER.BIN").unwrap();
+            for chunk in expected.chunks(5000) {
+                game.write_all(chunk).unwrap();
+                game.flush().unwrap();
+                other.write_all(&[0xee; 5000]).unwrap();
+                other.flush().unwrap();
+            }
+            drop((game, other));
+
+            let mut game = root.open_file("GAME.MB").unwrap();
+            let image = Multiboot::find(&mut game, expected.len() as u64, START).unwrap();
+            assert!(image.len > 1);
+            assert_eq!(image.size(), expected.len() as u64);
+            image
+        };
+
+        let mut sim = Simulated::new(RamDisk::new(&mut data));
+        let mut ewram = vec![0u8; 0x50000];
+        assert!(unsafe { image.read(&mut sim, ewram.as_mut_ptr()) });
+        assert!(ewram[..expected.len()] == expected);
+    }
+}
@@ -0,0 +1,332 @@
+use core::mem::{size_of, transmute};
+use core::ptr::read_unaligned;
+
+use ape_mbr::types::PartitionType;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use embedded_io::blocking::{Read, Seek};
+use embedded_io::SeekFrom;
+use gpt_parser::{GPTHeader, GPTPartition, Uuid};
+use log::warn;
+
+use crate::fs::{BufferedIo, ErrorKind, Pages};
+use crate::sd::{BlockIo, BlockIoError, Lba, Partition};
+
+/// partitions past this are ignored
+const MAX_PARTITIONS: usize = 16;
+/// gpt entries we're willing to read through, which is as many as anything makes
+const MAX_GPT_ENTRIES: u32 = 128;
+/// the fields of a gpt header, `GPTHeader` is padded past them to keep its u64s aligned
+const GPT_HEADER_SIZE: usize = 92;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+// guids are stored mixed-endian on disk, these are the raw bytes
+/// EBD0A0A2-B9E5-4433-87C0-68B6B72699C7
+const GPT_BASIC_DATA: Uuid =
+    unsafe { transmute(*b"\xa2\xa0\xd0\xeb\xe5\xb9\x33\x44\x87\xc0\x68\xb6\xb7\x26\x99\xc7") };
+/// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+const GPT_EFI_SYSTEM: Uuid =
+    unsafe { transmute(*b"\x28\x73\x2a\xc1\x1f\xf8\xd2\x11\xba\x4b\x00\xa0\xc9\x3e\xc9\x3b") };
+
+#[derive(Debug)]
+pub enum PartitionError<E> {
+    Io(ErrorKind<E>),
+    /// the first block doesn't end in 55 aa
+    NoPartitionTable,
+    /// there's a protective mbr, but the gpt header is broken
+    InvalidGpt,
+}
+
+impl<E> From<ErrorKind<E>> for PartitionError<E> {
+    fn from(value: ErrorKind<E>) -> Self {
+        Self::Io(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PartitionKind {
+    Mbr(PartitionType),
+    Gpt(Uuid),
+}
+
+impl PartitionKind {
+    /// whether this partition is likely to hold a fat filesystem
+    pub fn is_fat(&self) -> bool {
+        match self {
+            Self::Mbr(kind) => matches!(
+                kind,
+                PartitionType::Fat12
+                    | PartitionType::Fat16Lt32
+                    | PartitionType::Fat16
+                    | PartitionType::W95Fat32
+                    | PartitionType::W95Fat32Lba
+                    | PartitionType::W95Fat16Lba
+            ),
+            Self::Gpt(kind) => *kind == GPT_BASIC_DATA || *kind == GPT_EFI_SYSTEM,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PartitionInfo {
+    pub start: Lba,
+    /// exclusive
+    pub end: Lba,
+    pub kind: PartitionKind,
+}
+
+impl PartitionInfo {
+    pub fn open<'d, D: BlockIo<512>>(&self, disk: &'d mut D) -> Partition<'d, 512, D>
+    where
+        D::Error: From<BlockIoError>,
+    {
+        Partition::new(disk, self.start, self.end)
+    }
+}
+
+pub struct PartitionTable {
+    partitions: [Option<PartitionInfo>; MAX_PARTITIONS],
+}
+
+impl PartitionTable {
+    /// read the mbr, and the gpt behind it if the mbr is a protective one
+    pub fn read<D: BlockIo<512>>(disk: &mut D) -> Result<Self, PartitionError<D::Error>> {
+        let mut pages = Pages::new();
+        let mut io = BufferedIo::<512, 512, 1, _>::new(disk, &mut pages);
+        let mut table = Self {
+            partitions: [None; MAX_PARTITIONS],
+        };
+
+        let mut mbr = [0u8; 512];
+        io.read_exact(&mut mbr).map_err(ErrorKind::from)?;
+        if mbr[510..] != [0x55, 0xaa] {
+            return Err(PartitionError::NoPartitionTable);
+        }
+
+        let mut records = [(0u8, 0u32, 0u32); 4];
+        for (record, bytes) in records
+            .iter_mut()
+            .zip(mbr[0x1be..0x1fe].as_chunks::<16>().0)
+        {
+            *record = (
+                bytes[4],
+                u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+                u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            );
+        }
+
+        if records
+            .iter()
+            .any(|(kind, _, _)| *kind == PartitionType::GPT as u8)
+        {
+            table.read_gpt(&mut io)?;
+        } else {
+            for (kind, start, count) in records {
+                if kind == PartitionType::Unknown as u8 || count == 0 {
+                    continue;
+                }
+
+                table.push(PartitionInfo {
+                    start,
+                    end: start.saturating_add(count),
+                    kind: PartitionKind::Mbr(
+                        PartitionType::try_from(kind).unwrap_or(PartitionType::Unknown),
+                    ),
+                });
+            }
+        }
+
+        Ok(table)
+    }
+
+    fn read_gpt<IO: Read + Seek<Error = ErrorKind<E>>, E>(
+        &mut self,
+        io: &mut IO,
+    ) -> Result<(), PartitionError<E>> {
+        // the primary header is always at lba 1
+        let mut block = [0u8; 512];
+        io.seek(SeekFrom::Start(512))?;
+        io.read_exact(&mut block).map_err(ErrorKind::from)?;
+
+        let header = unsafe { read_unaligned(block.as_ptr() as *const GPTHeader) };
+        let header_size: u32 = header.header_size_le.into();
+        if !header.verify_signature() || !(GPT_HEADER_SIZE..=512).contains(&(header_size as usize))
+        {
+            return Err(PartitionError::InvalidGpt);
+        }
+
+        // the checksum is calculated with the checksum field zeroed
+        let header_crc: u32 = header.header_crc32.into();
+        block[16..20].fill(0);
+        if CRC32.checksum(&block[..header_size as usize]) != header_crc {
+            return Err(PartitionError::InvalidGpt);
+        }
+
+        let entries_start: u64 = header.part_start_lba.into();
+        let entry_count: u32 = header.num_parts.into();
+        let entry_size: u32 = header.part_size.into();
+        if entry_size < size_of::<GPTPartition>() as u32
+            || entry_size % 128 != 0
+            || entry_count > MAX_GPT_ENTRIES
+        {
+            return Err(PartitionError::InvalidGpt);
+        }
+
+        io.seek(SeekFrom::Start(entries_start * 512))?;
+        let mut digest = CRC32.digest();
+        for _ in 0..entry_count {
+            // entries can be larger than the part we understand, but always in multiples of 128
+            let mut bytes = [0u8; size_of::<GPTPartition>()];
+            io.read_exact(&mut bytes).map_err(ErrorKind::from)?;
+            digest.update(&bytes);
+            let entry = unsafe { read_unaligned(bytes.as_ptr() as *const GPTPartition) };
+
+            for _ in 1..entry_size / 128 {
+                io.read_exact(&mut bytes).map_err(ErrorKind::from)?;
+                digest.update(&bytes);
+            }
+
+            if entry.part_type == Uuid::nil() {
+                continue;
+            }
+
+            let first: u64 = entry.first_lba.into();
+            let last: u64 = entry.last_lba.into();
+            if last < first {
+                warn!("skipping partition that ends before it starts");
+                continue;
+            }
+            // the card interface only takes 32 bit addresses
+            let end = last.checked_add(1).and_then(|end| Lba::try_from(end).ok());
+            let (Ok(start), Some(end)) = (Lba::try_from(first), end) else {
+                warn!("skipping partition past the 32 bit lba limit");
+                continue;
+            };
+
+            self.push(PartitionInfo {
+                start,
+                end,
+                kind: PartitionKind::Gpt(entry.part_type),
+            });
+        }
+
+        if digest.finalize() != header.part_table_crc32.into() {
+            return Err(PartitionError::InvalidGpt);
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, partition: PartitionInfo) {
+        match self.partitions.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(partition),
+            None => warn!("too many partitions, ignoring some"),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PartitionInfo> {
+        self.partitions.iter().flatten()
+    }
+
+    /// the first partition that looks like it's fat
+    pub fn default_partition(&self) -> Option<&PartitionInfo> {
+        self.iter().find(|partition| partition.kind.is_fat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::{PartitionError, PartitionKind, PartitionTable, CRC32, GPT_BASIC_DATA};
+    use crate::disk::RamDisk;
+
+    const BLOCKS: usize = 64;
+    /// four entries of 128 bytes fill the block after the header
+    const ENTRIES: usize = 4;
+
+    /// a protective mbr, then a gpt with `entries` as (type, first lba, last lba)
+    fn gpt_image(entries: &[(&[u8; 16], u64, u64)]) -> Vec<u8> {
+        let mut data = vec![0; BLOCKS * 512];
+        let record = &mut data[0x1be..0x1ce];
+        record[4] = 0xee;
+        record[8..12].copy_from_slice(&1u32.to_le_bytes());
+        record[12..16].copy_from_slice(&(BLOCKS as u32 - 1).to_le_bytes());
+        data[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        for (i, (kind, first, last)) in entries.iter().enumerate() {
+            let entry = &mut data[1024 + i * 128..][..128];
+            entry[..16].copy_from_slice(*kind);
+            entry[16] = i as u8 + 1;
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        let entries_crc = CRC32.checksum(&data[1024..1024 + ENTRIES * 128]);
+
+        let header = &mut data[512..512 + 92];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&1u64.to_le_bytes());
+        header[32..40].copy_from_slice(&(BLOCKS as u64 - 1).to_le_bytes());
+        header[40..48].copy_from_slice(&3u64.to_le_bytes());
+        header[48..56].copy_from_slice(&(BLOCKS as u64 - 2).to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRIES as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let header_crc = CRC32.checksum(header);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+        data
+    }
+
+    fn basic_data() -> [u8; 16] {
+        unsafe { core::mem::transmute(GPT_BASIC_DATA) }
+    }
+
+    #[test]
+    fn gpt_basic_data() {
+        let mut data = gpt_image(&[(&basic_data(), 3, BLOCKS as u64 - 2)]);
+        let table = PartitionTable::read(&mut RamDisk::new(&mut data)).unwrap();
+
+        let info = *table.default_partition().unwrap();
+        assert_eq!((info.start, info.end), (3, BLOCKS as u32 - 1));
+        assert!(matches!(info.kind, PartitionKind::Gpt(kind) if kind == GPT_BASIC_DATA));
+        assert_eq!(table.iter().count(), 1);
+    }
+
+    #[test]
+    fn gpt_bad_header_crc() {
+        let mut data = gpt_image(&[(&basic_data(), 3, BLOCKS as u64 - 2)]);
+        // the last usable lba, which the crc covers
+        data[512 + 48] ^= 1;
+        assert!(matches!(
+            PartitionTable::read(&mut RamDisk::new(&mut data)),
+            Err(PartitionError::InvalidGpt)
+        ));
+    }
+
+    #[test]
+    fn gpt_bad_entries_crc() {
+        let mut data = gpt_image(&[(&basic_data(), 3, BLOCKS as u64 - 2)]);
+        // the first character of the entry's name
+        data[1024 + 56] = b'x';
+        assert!(matches!(
+            PartitionTable::read(&mut RamDisk::new(&mut data)),
+            Err(PartitionError::InvalidGpt)
+        ));
+    }
+
+    #[test]
+    fn gpt_skips_past_32_bits() {
+        let mut data = gpt_image(&[
+            (&basic_data(), 1 << 32, (1 << 32) + 100),
+            (&basic_data(), 3, BLOCKS as u64 - 2),
+        ]);
+        let table = PartitionTable::read(&mut RamDisk::new(&mut data)).unwrap();
+
+        let starts = table.iter().map(|info| info.start).collect::<Vec<_>>();
+        assert_eq!(starts, [3]);
+    }
+}
@@ -0,0 +1,730 @@
+//! applying ips, ups and bps patches to a game as it's loaded
+//!
+//! the patch is streamed off the card, so none of these need the whole patch or game in memory
+
+#[cfg(not(feature = "host"))]
+use ape_fatfs::dir::Dir;
+#[cfg(not(feature = "host"))]
+use ape_fatfs::error::Error;
+#[cfg(not(feature = "host"))]
+use ape_fatfs::file::File;
+#[cfg(not(feature = "host"))]
+use ape_fatfs::fs::{OemCpConverter, ReadWriteSeek};
+#[cfg(not(feature = "host"))]
+use ape_fatfs::time::TimeProvider;
+use crc::{Crc, Digest, CRC_32_ISO_HDLC};
+use embedded_io::blocking::{Read, Seek};
+use embedded_io::SeekFrom;
+
+use crate::ezflash::Bus;
+use crate::loader::{LoadError, Target};
+#[cfg(not(feature = "host"))]
+use crate::path::PathBuf;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// ups and bps both end with the source, target and patch crcs
+const FOOTER_LEN: u64 = 12;
+
+#[cfg(not(feature = "host"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchKind {
+    Ips,
+    Ups,
+    Bps,
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    /// the patch doesn't start with the magic for its format
+    BadHeader,
+    /// the patch ended in the middle of something
+    Truncated,
+    /// the patched game wouldn't fit in psram
+    TooLarge,
+    /// the patch is for a different game, or a different version of it
+    SourceMismatch,
+    /// the patch applied, but didn't produce what it was supposed to
+    TargetMismatch,
+    /// the patch itself is damaged
+    Corrupt,
+}
+
+/// a patch file and what format it's in, if there is one
+#[cfg(not(feature = "host"))]
+pub type Found<'a, IO, TP, OCC> = Option<(PatchKind, File<'a, IO, TP, OCC>)>;
+
+/// look for a patch next to the rom, trying each format in turn
+#[cfg(not(feature = "host"))]
+pub fn find<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter>(
+    root: &Dir<'a, IO, TP, OCC>,
+    rom_path: &str,
+) -> Result<Found<'a, IO, TP, OCC>, Error<IO::Error>> {
+    for (ext, kind) in [
+        ("ips", PatchKind::Ips),
+        ("ups", PatchKind::Ups),
+        ("bps", PatchKind::Bps),
+    ] {
+        let path = PathBuf::for_rom(rom_path, ext).ok_or(Error::InvalidFileNameLength)?;
+        match root.open_file(path.as_str()) {
+            Ok(file) => return Ok(Some((kind, file))),
+            Err(Error::NotFound) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(None)
+}
+
+/// reads a patch a byte at a time without going back to the card for each one, keeping a crc of
+/// everything read
+struct PatchReader<'r, R: Read> {
+    inner: &'r mut R,
+    buf: [u8; 512],
+    pos: usize,
+    len: usize,
+    /// how far into the patch we are
+    offset: u64,
+    digest: Digest<'static, u32>,
+}
+
+impl<'r, R: Read> PatchReader<'r, R> {
+    fn new(inner: &'r mut R) -> Self {
+        Self {
+            inner,
+            buf: [0; 512],
+            pos: 0,
+            len: 0,
+            offset: 0,
+            digest: CRC32.digest(),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError<R::Error>> {
+        if self.pos == self.len {
+            self.len = self.inner.read(&mut self.buf).map_err(LoadError::Io)?;
+            self.pos = 0;
+            if self.len == 0 {
+                return Err(PatchError::Truncated.into());
+            }
+        }
+
+        let byte = self.buf[self.pos];
+        self.digest.update(&[byte]);
+        self.pos += 1;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, dst: &mut [u8]) -> Result<(), LoadError<R::Error>> {
+        for byte in dst {
+            *byte = self.byte()?;
+        }
+        Ok(())
+    }
+
+    fn u32_le(&mut self) -> Result<u32, LoadError<R::Error>> {
+        let mut bytes = [0; 4];
+        self.bytes(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// the variable length numbers ups and bps use
+    fn number(&mut self) -> Result<u64, LoadError<R::Error>> {
+        let mut value = 0u64;
+        let mut shift = 1u64;
+        loop {
+            let byte = self.byte()?;
+            value = (byte as u64 & 0x7f)
+                .checked_mul(shift)
+                .and_then(|part| value.checked_add(part))
+                .ok_or(PatchError::Corrupt)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::Corrupt)?;
+            value = value.checked_add(shift).ok_or(PatchError::Corrupt)?;
+        }
+    }
+
+    fn number_usize(&mut self) -> Result<usize, LoadError<R::Error>> {
+        usize::try_from(self.number()?).map_err(|_| PatchError::Corrupt.into())
+    }
+
+    /// check the crcs at the end of a ups or bps patch, returning the source and target ones
+    fn footer(&mut self) -> Result<(u32, u32), LoadError<R::Error>> {
+        let source = self.u32_le()?;
+        let target = self.u32_le()?;
+        // the patch crc covers everything before itself
+        let expected = self.digest.clone().finalize();
+        if self.u32_le()? != expected {
+            return Err(PatchError::Corrupt.into());
+        }
+
+        Ok((source, target))
+    }
+}
+
+/// how long the patch is, leaving it rewound
+fn patch_len<R: Read + Seek>(patch: &mut R) -> Result<u64, LoadError<R::Error>> {
+    let len = patch.seek(SeekFrom::End(0)).map_err(LoadError::Io)?;
+    patch.seek(SeekFrom::Start(0)).map_err(LoadError::Io)?;
+    Ok(len)
+}
+
+fn check_crc<B: Bus>(target: &mut Target<B>, expected: u32) -> Result<(), PatchError> {
+    let mut digest = CRC32.digest();
+    target.for_each_chunk(|chunk| digest.update(chunk));
+    if digest.finalize() != expected {
+        return Err(PatchError::TargetMismatch);
+    }
+    Ok(())
+}
+
+/// ips patches are just a list of places to overwrite
+pub fn apply_ips<B: Bus, R: Read>(
+    patch: &mut R,
+    target: &mut Target<B>,
+) -> Result<(), LoadError<R::Error>> {
+    let mut patch = PatchReader::new(patch);
+
+    let mut magic = [0; 5];
+    patch.bytes(&mut magic)?;
+    if &magic != b"PATCH" {
+        return Err(PatchError::BadHeader.into());
+    }
+
+    let mut chunk = [0u8; 256];
+    loop {
+        let mut offset = [0; 3];
+        patch.bytes(&mut offset)?;
+        if &offset == b"EOF" {
+            break;
+        }
+        let offset = u32::from_be_bytes([0, offset[0], offset[1], offset[2]]) as usize;
+
+        let mut len = [0; 2];
+        patch.bytes(&mut len)?;
+        let mut len = u16::from_be_bytes(len) as usize;
+
+        if len == 0 {
+            // run length encoded
+            let mut run = [0; 2];
+            patch.bytes(&mut run)?;
+            let run = u16::from_be_bytes(run) as usize;
+            chunk.fill(patch.byte()?);
+
+            let mut pos = 0;
+            while pos < run {
+                let piece = (run - pos).min(chunk.len());
+                target.write(offset + pos, &chunk[..piece])?;
+                pos += piece;
+            }
+        } else {
+            let mut pos = offset;
+            while len > 0 {
+                let piece = len.min(chunk.len());
+                patch.bytes(&mut chunk[..piece])?;
+                target.write(pos, &chunk[..piece])?;
+                pos += piece;
+                len -= piece;
+            }
+        }
+    }
+
+    // some patches say how long the game should end up
+    let mut truncate = [0; 3];
+    match patch.bytes(&mut truncate) {
+        Ok(()) => target
+            .resize(u32::from_be_bytes([0, truncate[0], truncate[1], truncate[2]]) as usize)?,
+        Err(LoadError::Patch(PatchError::Truncated)) => (),
+        Err(e) => return Err(e),
+    }
+
+    Ok(())
+}
+
+/// ups patches xor the game with runs of bytes
+pub fn apply_ups<B: Bus, R: Read + Seek>(
+    patch: &mut R,
+    target: &mut Target<B>,
+) -> Result<(), LoadError<R::Error>> {
+    let end = patch_len(patch)?
+        .checked_sub(FOOTER_LEN)
+        .ok_or(PatchError::Truncated)?;
+    let mut patch = PatchReader::new(patch);
+
+    let mut magic = [0; 4];
+    patch.bytes(&mut magic)?;
+    if &magic != b"UPS1" {
+        return Err(PatchError::BadHeader.into());
+    }
+
+    let source_len = patch.number_usize()?;
+    let target_len = patch.number_usize()?;
+    if source_len != target.size() {
+        return Err(PatchError::SourceMismatch.into());
+    }
+
+    // the footer has to be read first to know whether the source matches, but that'd mean seeking
+    // back and forth, so check the source against a crc of what's loaded instead
+    let mut source_digest = CRC32.digest();
+    target.for_each_chunk(|chunk| source_digest.update(chunk));
+    let source_crc = source_digest.finalize();
+
+    // anything the game grows by starts out as zeroes
+    if target_len > target.size() {
+        target.resize(target_len)?;
+    }
+
+    let mut pos = 0usize;
+    let mut run = [0u8; 256];
+    let mut buf = [0u8; 256];
+    while patch.offset < end {
+        pos = pos
+            .checked_add(patch.number_usize()?)
+            .ok_or(PatchError::Corrupt)?;
+
+        // each run ends with a zero, which leaves its byte alone
+        let mut done = false;
+        while !done {
+            let mut len = 0;
+            while len < run.len() && !done {
+                run[len] = patch.byte()?;
+                done = run[len] == 0;
+                len += 1;
+            }
+
+            let inside = len.min(target_len.saturating_sub(pos));
+            target.read(pos, &mut buf[..inside]);
+            for (byte, xor) in buf[..inside].iter_mut().zip(run) {
+                *byte ^= xor;
+            }
+            target.write(pos, &buf[..inside])?;
+            pos += len;
+        }
+    }
+
+    let (expected_source, expected_target) = patch.footer()?;
+    if source_crc != expected_source {
+        return Err(PatchError::SourceMismatch.into());
+    }
+    target.resize(target_len)?;
+    check_crc(target, expected_target)?;
+
+    Ok(())
+}
+
+/// bps patches build the game out of pieces of the original, the patch, and itself
+pub fn apply_bps<B: Bus, R: Read + Seek>(
+    patch: &mut R,
+    source: &mut R,
+    source_len: usize,
+    target: &mut Target<B>,
+) -> Result<(), LoadError<R::Error>> {
+    let end = patch_len(patch)?
+        .checked_sub(FOOTER_LEN)
+        .ok_or(PatchError::Truncated)?;
+    let mut patch = PatchReader::new(patch);
+
+    let mut magic = [0; 4];
+    patch.bytes(&mut magic)?;
+    if &magic != b"BPS1" {
+        return Err(PatchError::BadHeader.into());
+    }
+
+    if patch.number_usize()? != source_len {
+        return Err(PatchError::SourceMismatch.into());
+    }
+    let target_len = patch.number_usize()?;
+    for _ in 0..patch.number()? {
+        // metadata, which we don't care about
+        patch.byte()?;
+    }
+
+    let mut source_digest = CRC32.digest();
+    let mut buf = [0u8; 512];
+    loop {
+        let len = source.read(&mut buf).map_err(LoadError::Io)?;
+        if len == 0 {
+            break;
+        }
+        source_digest.update(&buf[..len]);
+    }
+    let source_crc = source_digest.finalize();
+
+    let mut out = 0usize;
+    let mut source_rel = 0usize;
+    let mut target_rel = 0usize;
+    while patch.offset < end {
+        let action = patch.number_usize()?;
+        let len = (action >> 2) + 1;
+        if out + len > target_len {
+            return Err(PatchError::Corrupt.into());
+        }
+
+        match action & 3 {
+            // source read, from the same place in the original
+            0 => copy_source(source, source_len, out, out, len, target, &mut buf)?,
+            // target read, straight out of the patch
+            1 => {
+                let mut pos = 0;
+                while pos < len {
+                    let piece = (len - pos).min(buf.len());
+                    patch.bytes(&mut buf[..piece])?;
+                    target.write(out + pos, &buf[..piece])?;
+                    pos += piece;
+                }
+            }
+            // source copy, from anywhere in the original
+            2 => {
+                source_rel = relative(source_rel, patch.number()?)?;
+                copy_source(source, source_len, source_rel, out, len, target, &mut buf)?;
+                source_rel += len;
+            }
+            // target copy, from earlier in the game, and it can overlap what it's writing
+            _ => {
+                target_rel = relative(target_rel, patch.number()?)?;
+                if target_rel >= out {
+                    return Err(PatchError::Corrupt.into());
+                }
+
+                let mut pos = 0;
+                while pos < len {
+                    // only copy as much as has been written already
+                    let piece = (len - pos).min(out + pos - target_rel).min(buf.len());
+                    target.read(target_rel, &mut buf[..piece]);
+                    target.write(out + pos, &buf[..piece])?;
+                    target_rel += piece;
+                    pos += piece;
+                }
+            }
+        }
+        out += len;
+    }
+
+    let (expected_source, expected_target) = patch.footer()?;
+    if source_crc != expected_source {
+        return Err(PatchError::SourceMismatch.into());
+    }
+    target.resize(target_len)?;
+    check_crc(target, expected_target)?;
+
+    Ok(())
+}
+
+/// apply a signed offset, which is stored with the sign in the lowest bit
+fn relative(base: usize, delta: u64) -> Result<usize, PatchError> {
+    let magnitude = usize::try_from(delta >> 1).map_err(|_| PatchError::Corrupt)?;
+    if delta & 1 != 0 {
+        base.checked_sub(magnitude)
+    } else {
+        base.checked_add(magnitude)
+    }
+    .ok_or(PatchError::Corrupt)
+}
+
+fn copy_source<B: Bus, R: Read + Seek>(
+    source: &mut R,
+    source_len: usize,
+    from: usize,
+    to: usize,
+    len: usize,
+    target: &mut Target<B>,
+    buf: &mut [u8],
+) -> Result<(), LoadError<R::Error>> {
+    if from + len > source_len {
+        return Err(PatchError::Corrupt.into());
+    }
+
+    source
+        .seek(SeekFrom::Start(from as u64))
+        .map_err(LoadError::Io)?;
+    let mut pos = 0;
+    while pos < len {
+        let piece = (len - pos).min(buf.len());
+        source.read_exact(&mut buf[..piece])?;
+        target.write(to + pos, &buf[..piece])?;
+        pos += piece;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+    use embedded_io::blocking::{Read, Seek};
+    use embedded_io::{Io, SeekFrom};
+    use std::{vec, vec::Vec};
+
+    use super::{apply_bps, apply_ips, apply_ups, PatchError, PatchReader, CRC32};
+    use crate::disk::RamDisk;
+    use crate::loader::{LoadError, Target};
+    use crate::sim::Simulated;
+
+    /// a patch or game that's already in memory
+    struct Cursor<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl Io for Cursor<'_> {
+        type Error = Infallible;
+    }
+
+    impl Read for Cursor<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let len = buf.len().min(self.data.len().saturating_sub(self.pos));
+            buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    impl Seek for Cursor<'_> {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64, Infallible> {
+            self.pos = match pos {
+                SeekFrom::Start(pos) => pos as usize,
+                SeekFrom::End(delta) => (self.data.len() as i64 + delta) as usize,
+                SeekFrom::Current(delta) => (self.pos as i64 + delta) as usize,
+            };
+            Ok(self.pos as u64)
+        }
+    }
+
+    fn cursor(data: &[u8]) -> Cursor<'_> {
+        Cursor { data, pos: 0 }
+    }
+
+    /// long enough to go past the head buffer into psram
+    fn game() -> Vec<u8> {
+        (0..0x10100u32).map(|i| (i ^ i >> 8) as u8).collect()
+    }
+
+    /// load `game`, run `apply` on it, and read back what that left
+    fn patched(
+        game: &[u8],
+        apply: impl FnOnce(&mut Target<Simulated<RamDisk>>) -> Result<(), LoadError<Infallible>>,
+    ) -> Result<Vec<u8>, LoadError<Infallible>> {
+        let mut sim = Simulated::new(RamDisk::new(&mut []));
+        let mut target = Target::new(&mut sim);
+        target.write(0, game)?;
+        apply(&mut target)?;
+
+        let mut out = vec![0; target.size()];
+        target.read(0, &mut out);
+        Ok(out)
+    }
+
+    /// the variable length numbers ups and bps use
+    fn number(patch: &mut Vec<u8>, mut value: u64) {
+        loop {
+            let low = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                patch.push(0x80 | low);
+                return;
+            }
+            patch.push(low);
+            value -= 1;
+        }
+    }
+
+    /// add the source and target crcs, then the crc of the patch itself
+    fn footer(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+        patch.extend_from_slice(&CRC32.checksum(source).to_le_bytes());
+        patch.extend_from_slice(&CRC32.checksum(target).to_le_bytes());
+        let crc = CRC32.checksum(patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn varints() {
+        let bytes = [0x80, 0x81, 0x00, 0x80, 0x7f, 0x80, 0x00, 0x00, 0x80];
+        let mut bytes = &bytes[..];
+        let mut reader = PatchReader::new(&mut bytes);
+        for expected in [0, 1, 0x80, 0xff, 0x4080] {
+            assert_eq!(reader.number().unwrap(), expected);
+        }
+
+        let mut encoded = Vec::new();
+        number(&mut encoded, 0x4080);
+        assert_eq!(encoded, [0x00, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn ips_records() {
+        let game = game();
+        let mut patch = b"PATCH".to_vec();
+        // across the end of the head buffer
+        patch.extend_from_slice(&[0x00, 0xff, 0xfe, 0x00, 0x04]);
+        patch.extend_from_slice(b"abcd");
+        // a run starting on an odd byte of psram
+        patch.extend_from_slice(&[0x01, 0x00, 0x21, 0x00, 0x00, 0x00, 0x50, 0xee]);
+        // past the end, which grows the game with zeroes up to it
+        patch.extend_from_slice(&[0x01, 0x01, 0x10, 0x00, 0x02]);
+        patch.extend_from_slice(b"zz");
+        patch.extend_from_slice(b"EOF");
+
+        let mut expected = game.clone();
+        expected[0xfffe..0x10002].copy_from_slice(b"abcd");
+        expected[0x10021..0x10071].fill(0xee);
+        expected.resize(0x10110, 0);
+        expected.extend_from_slice(b"zz");
+
+        let out = patched(&game, |target| apply_ips(&mut &patch[..], target)).unwrap();
+        assert!(out == expected);
+    }
+
+    #[test]
+    fn ips_truncate() {
+        let game = game();
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x01, b'x']);
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0x00, 0x80, 0x00]);
+
+        let mut expected = game[..0x8000].to_vec();
+        expected[0x10] = b'x';
+
+        let out = patched(&game, |target| apply_ips(&mut &patch[..], target)).unwrap();
+        assert!(out == expected);
+    }
+
+    #[test]
+    fn ips_cut_short() {
+        // ips has no crc, so the damage it can catch is a record that runs off the end
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x04]);
+        patch.extend_from_slice(b"ab");
+
+        let result = patched(&game(), |target| apply_ips(&mut &patch[..], target));
+        assert!(matches!(
+            result,
+            Err(LoadError::Patch(PatchError::Truncated))
+        ));
+    }
+
+    /// grows the game by four bytes, with hunks either side of the head buffer's end
+    fn ups_patch(game: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut expected = game.to_vec();
+        expected[0x10] ^= 0x01;
+        expected[0x11] ^= 0x02;
+        for byte in &mut expected[0xfffe..0x10002] {
+            *byte ^= 0xff;
+        }
+        expected.extend_from_slice(b"ups!");
+
+        let mut patch = b"UPS1".to_vec();
+        number(&mut patch, game.len() as u64);
+        number(&mut patch, expected.len() as u64);
+        number(&mut patch, 0x10);
+        patch.extend_from_slice(&[0x01, 0x02, 0x00]);
+        // far enough to take three bytes
+        number(&mut patch, 0xfffe - 0x13);
+        patch.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x00]);
+        // every run skips the byte after it
+        number(&mut patch, 0x10100 - 0x10003);
+        patch.extend_from_slice(b"ups!\0");
+        footer(&mut patch, game, &expected);
+
+        (patch, expected)
+    }
+
+    #[test]
+    fn ups_xor_runs() {
+        let game = game();
+        let (patch, expected) = ups_patch(&game);
+        let out = patched(&game, |target| apply_ups(&mut cursor(&patch), target)).unwrap();
+        assert!(out == expected);
+    }
+
+    #[test]
+    fn ups_bad_crc() {
+        let game = game();
+        let (mut patch, _) = ups_patch(&game);
+        *patch.last_mut().unwrap() ^= 1;
+
+        let result = patched(&game, |target| apply_ups(&mut cursor(&patch), target));
+        assert!(matches!(result, Err(LoadError::Patch(PatchError::Corrupt))));
+    }
+
+    /// every kind of action, with copies going both ways and a target copy overlapping itself
+    fn bps_patch(source: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut expected = source[..8].to_vec();
+        expected.extend_from_slice(b"WXYZ");
+        expected.extend_from_slice(&source[40..46]);
+        expected.extend_from_slice(&source[20..24]);
+        for i in 0..12 {
+            expected.push(expected[18 + i]);
+        }
+        for i in 0..4 {
+            expected.push(expected[2 + i]);
+        }
+        expected.extend_from_slice(&source[38..64]);
+        expected.extend_from_slice(&[0x5a; 40]);
+
+        let mut patch = b"BPS1".to_vec();
+        number(&mut patch, source.len() as u64);
+        number(&mut patch, expected.len() as u64);
+        number(&mut patch, 3);
+        patch.extend_from_slice(b"x=1");
+        // source read
+        number(&mut patch, 7 << 2);
+        // target read
+        number(&mut patch, 3 << 2 | 1);
+        patch.extend_from_slice(b"WXYZ");
+        // source copy forwards to 40, then back to 20 from where that left off at 46
+        number(&mut patch, 5 << 2 | 2);
+        number(&mut patch, 40 << 1);
+        number(&mut patch, 3 << 2 | 2);
+        number(&mut patch, 26 << 1 | 1);
+        // target copy from 18 into 22, then back from 30 to 2
+        number(&mut patch, 11 << 2 | 3);
+        number(&mut patch, 18 << 1);
+        number(&mut patch, 3 << 2 | 3);
+        number(&mut patch, 28 << 1 | 1);
+        // the rest of the source where it is, and a target read long enough for a two byte action
+        number(&mut patch, 25 << 2);
+        number(&mut patch, 39 << 2 | 1);
+        patch.extend_from_slice(&[0x5a; 40]);
+        footer(&mut patch, source, &expected);
+
+        (patch, expected)
+    }
+
+    #[test]
+    fn bps_actions() {
+        let source: Vec<u8> = (0..64).collect();
+        let (patch, expected) = bps_patch(&source);
+
+        let out = patched(&[], |target| {
+            apply_bps(
+                &mut cursor(&patch),
+                &mut cursor(&source),
+                source.len(),
+                target,
+            )
+        })
+        .unwrap();
+        assert!(out == expected);
+    }
+
+    #[test]
+    fn bps_bad_crc() {
+        let source: Vec<u8> = (0..64).collect();
+        let (mut patch, _) = bps_patch(&source);
+        *patch.last_mut().unwrap() ^= 1;
+
+        let result = patched(&[], |target| {
+            apply_bps(
+                &mut cursor(&patch),
+                &mut cursor(&source),
+                source.len(),
+                target,
+            )
+        });
+        assert!(matches!(result, Err(LoadError::Patch(PatchError::Corrupt))));
+    }
+}
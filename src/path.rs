@@ -0,0 +1,75 @@
+//! fixed size paths, since there's no allocator
+
+/// longest path we handle, in bytes
+pub const MAX_PATH: usize = 256;
+
+#[derive(Clone)]
+pub struct PathBuf {
+    buf: [u8; MAX_PATH],
+    len: usize,
+}
+
+impl PathBuf {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_PATH],
+            len: 0,
+        }
+    }
+
+    /// append to the path as is, or give up if it doesn't fit
+    pub fn push_str(&mut self, s: &str) -> Option<()> {
+        let end = self.len + s.len();
+        if end > MAX_PATH {
+            return None;
+        }
+
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Some(())
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        let mut path = Self::new();
+        path.push_str(s)?;
+        Some(path)
+    }
+
+    /// the file that goes with a rom, which is `rom_path` with `ext` in place of `.gba`, or on
+    /// the end if it has some other extension
+    pub fn for_rom(rom_path: &str, ext: &str) -> Option<Self> {
+        let stem = match rom_path.rsplit_once('.') {
+            Some((stem, rom_ext)) if rom_ext.eq_ignore_ascii_case("gba") => stem,
+            _ => rom_path,
+        };
+
+        let mut path = Self::from_str(stem)?;
+        path.push_str(".")?;
+        path.push_str(ext)?;
+        Some(path)
+    }
+
+    /// add `name` to the end of the path, with a separator unless the path is empty
+    #[cfg(not(feature = "host"))]
+    pub fn push(&mut self, name: &str) -> Option<()> {
+        if self.len > 0 {
+            self.push_str("/")?;
+        }
+        self.push_str(name)
+    }
+
+    /// drop the last part of the path, or return false if it's already empty
+    #[cfg(not(feature = "host"))]
+    pub fn pop(&mut self) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+        self.len = self.as_str().rfind('/').unwrap_or(0);
+        true
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever built from whole strs
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+}
@@ -0,0 +1,182 @@
+//! the cartridge header at the start of every game
+
+use core::mem::transmute;
+
+#[cfg(feature = "host")]
+use embedded_io::blocking::{Read, ReadExactError};
+
+pub const HEADER_LEN: usize = 0xc0;
+/// where the game's rom shows up
+const ROM: u32 = 0x8000000;
+
+/// the bitmap the bios checks before it'll boot a cart
+const LOGO: [u8; 156] = [
+    0x24, 0xff, 0xae, 0x51, 0x69, 0x9a, 0xa2, 0x21, 0x3d, 0x84, 0x82, 0x0a, 0x84, 0xe4, 0x09, 0xad,
+    0x11, 0x24, 0x8b, 0x98, 0xc0, 0x81, 0x7f, 0x21, 0xa3, 0x52, 0xbe, 0x19, 0x93, 0x09, 0xce, 0x20,
+    0x10, 0x46, 0x4a, 0x4a, 0xf8, 0x27, 0x31, 0xec, 0x58, 0xc7, 0xe8, 0x33, 0x82, 0xe3, 0xce, 0xbf,
+    0x85, 0xf4, 0xdf, 0x94, 0xce, 0x4b, 0x09, 0xc1, 0x94, 0x56, 0x8a, 0xc0, 0x13, 0x72, 0xa7, 0xfc,
+    0x9f, 0x84, 0x4d, 0x73, 0xa3, 0xca, 0x9a, 0x61, 0x58, 0x97, 0xa3, 0x27, 0xfc, 0x03, 0x98, 0x76,
+    0x23, 0x1d, 0xc7, 0x61, 0x03, 0x04, 0xae, 0x56, 0xbf, 0x38, 0x84, 0x00, 0x40, 0xa7, 0x0e, 0xfd,
+    0xff, 0x52, 0xfe, 0x03, 0x6f, 0x95, 0x30, 0xf1, 0x97, 0xfb, 0xc0, 0x85, 0x60, 0xd6, 0x80, 0x25,
+    0xa9, 0x63, 0xbe, 0x03, 0x01, 0x4e, 0x38, 0xe2, 0xf9, 0xa2, 0x34, 0xff, 0xbb, 0x3e, 0x03, 0x44,
+    0x78, 0x00, 0x90, 0xcb, 0x88, 0x11, 0x3a, 0x94, 0x65, 0xc0, 0x7c, 0x63, 0x87, 0xf0, 0x3c, 0xaf,
+    0xd6, 0x25, 0xe4, 0x8b, 0x38, 0x0a, 0xac, 0x72, 0x21, 0xd4, 0xf8, 0x07,
+];
+/// what has to be at 0xb2
+const FIXED: u8 = 0x96;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    /// the first instruction isn't an arm `b`
+    Entry,
+    Logo,
+    Fixed,
+    /// the complement check doesn't add up, which the bios won't boot
+    Complement,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Header {
+    /// an arm `b` past the header
+    pub entry: [u8; 4],
+    pub logo: [u8; 156],
+    /// ascii, padded with zeroes
+    pub title: [u8; 12],
+    /// four letters, the last of which is the region
+    pub game_code: [u8; 4],
+    pub maker_code: [u8; 2],
+    pub fixed: u8,
+    pub unit_code: u8,
+    pub device_type: u8,
+    reserved: [u8; 7],
+    pub version: u8,
+    /// makes the bytes from the title up to here add up to -0x19
+    pub complement: u8,
+    reserved_end: [u8; 2],
+}
+
+impl Header {
+    pub fn from_bytes(bytes: [u8; HEADER_LEN]) -> Self {
+        // only made of bytes, so any bytes will do
+        unsafe { transmute(bytes) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; HEADER_LEN] {
+        unsafe { &*(self as *const Self as *const [u8; HEADER_LEN]) }
+    }
+
+    #[cfg(feature = "host")]
+    pub fn read<R: Read>(rom: &mut R) -> Result<Self, ReadExactError<R::Error>> {
+        let mut bytes = [0; HEADER_LEN];
+        rom.read_exact(&mut bytes)?;
+        Ok(Self::from_bytes(bytes))
+    }
+
+    /// where the game starts running, if it starts with a branch
+    pub fn entry_point(&self) -> Option<u32> {
+        let op = u32::from_le_bytes(self.entry);
+        if op >> 24 != 0xea {
+            return None;
+        }
+
+        // 24 bit signed offset in words, from two instructions ahead
+        let offset = ((op << 8) as i32 >> 6) as u32;
+        Some(ROM.wrapping_add(8).wrapping_add(offset))
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        text(&self.title)
+    }
+
+    #[cfg(any(test, not(feature = "host")))]
+    pub fn game_code(&self) -> Option<&str> {
+        text(&self.game_code)
+    }
+
+    /// what the complement check should be
+    pub fn checksum(&self) -> u8 {
+        self.as_bytes()[0xa0..0xbd]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_sub(*byte))
+            .wrapping_sub(0x19)
+    }
+
+    /// the first thing wrong with the header, if anything is
+    pub fn validate(&self) -> Result<(), HeaderError> {
+        if self.entry_point().is_none() {
+            Err(HeaderError::Entry)
+        } else if self.logo != LOGO {
+            Err(HeaderError::Logo)
+        } else if self.fixed != FIXED {
+            Err(HeaderError::Fixed)
+        } else if self.complement != self.checksum() {
+            Err(HeaderError::Complement)
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cfg(any(test, not(feature = "host")))]
+    pub fn fix_complement(&mut self) {
+        self.complement = self.checksum();
+    }
+}
+
+/// ascii up to the padding, or nothing if it's something else
+fn text(bytes: &[u8]) -> Option<&str> {
+    let len = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    let text = &bytes[..len];
+    if !text
+        .iter()
+        .all(|byte| byte.is_ascii_graphic() || *byte == b' ')
+    {
+        return None;
+    }
+
+    core::str::from_utf8(text).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Header, HeaderError, HEADER_LEN, LOGO, ROM};
+
+    /// a header that boots, with its complement worked out by hand
+    fn header() -> Header {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..4].copy_from_slice(&0xea00002eu32.to_le_bytes());
+        bytes[4..0xa0].copy_from_slice(&LOGO);
+        bytes[0xa0..0xac].copy_from_slice(b"EZFODE TEST\0");
+        bytes[0xac..0xb0].copy_from_slice(b"AEZE");
+        bytes[0xb0..0xb2].copy_from_slice(b"01");
+        bytes[0xb2] = 0x96;
+        bytes[0xbd] = 0xae;
+        Header::from_bytes(bytes)
+    }
+
+    #[test]
+    fn known_good_header() {
+        let header = header();
+        assert_eq!(header.checksum(), 0xae);
+        assert_eq!(header.validate(), Ok(()));
+        assert_eq!(header.entry_point(), Some(ROM + 0xc0));
+        assert_eq!(header.title(), Some("EZFODE TEST"));
+        assert_eq!(header.game_code(), Some("AEZE"));
+    }
+
+    #[test]
+    fn fix_corrupted_complement() {
+        let mut header = header();
+        header.complement ^= 0x40;
+        assert_eq!(header.validate(), Err(HeaderError::Complement));
+
+        // a changed title needs a new complement too
+        header.title[0] = b'Y';
+        header.fix_complement();
+        assert_eq!(header.validate(), Ok(()));
+        assert_eq!(header.complement, 0xae - (b'Y' - b'E'));
+    }
+}
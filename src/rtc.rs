@@ -0,0 +1,253 @@
+//! the cart's s-3511 real time clock, which is talked to a bit at a time over the gpio port
+//!
+//! commands go out most significant bit first and data comes and goes least significant bit
+//! first, all of it in bcd
+
+use core::cell::RefCell;
+use core::fmt;
+
+use ape_fatfs::time::{Date, DateTime as FsDateTime, DefaultTimeProvider, Time, TimeProvider};
+
+#[cfg(any(test, not(feature = "host")))]
+use crate::ezflash::set_rtc_control;
+use crate::ezflash::Bus;
+
+/// the gpio port, which shows up in rom just past the header
+pub const GPIO_DATA: usize = 0x80000c4;
+/// set bits are pins we drive, clear bits are pins we read
+pub const GPIO_DIRECTION: usize = 0x80000c6;
+/// 1 makes the port readable, rather than reading back as rom
+pub const GPIO_CONTROL: usize = 0x80000c8;
+
+// which pin is which
+pub const SCK: u16 = 1 << 0;
+pub const SIO: u16 = 1 << 1;
+pub const CS: u16 = 1 << 2;
+
+/// every command has this in the top nibble, then the register and whether it's a read
+pub const COMMAND: u8 = 0x60;
+pub const COMMAND_READ: u8 = 1;
+pub const REG_RESET: u8 = 0;
+pub const REG_STATUS: u8 = 1;
+pub const REG_DATE_TIME: u8 = 2;
+pub const DATE_TIME_LEN: usize = 7;
+
+/// the clock counts hours 0-23 rather than 1-12
+#[cfg(any(test, not(feature = "host")))]
+pub const STATUS_24H: u8 = 1 << 6;
+/// set when the clock lost power, after which it has to be set again
+pub const STATUS_POWER_LOST: u8 = 1 << 7;
+/// the hour also has the pm flag in it
+const HOUR_MASK: u8 = 0x3f;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    /// only 2000 to 2099, since the clock keeps two digits
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// what the clock is set to when it's lost track
+    #[cfg(any(test, not(feature = "host")))]
+    pub const DEFAULT: Self = Self {
+        year: 2000,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+
+    /// 0 for sunday, which is how the clock counts them
+    #[cfg(any(test, not(feature = "host")))]
+    pub fn weekday(&self) -> u8 {
+        const OFFSETS: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let year = self.year - (self.month < 3) as u16;
+        ((year + year / 4 - year / 100
+            + year / 400
+            + OFFSETS[self.month as usize - 1]
+            + self.day as u16)
+            % 7) as u8
+    }
+
+    fn is_valid(&self) -> bool {
+        (2000..2100).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    fn from_bcd(bytes: [u8; DATE_TIME_LEN]) -> Option<Self> {
+        let mut digits = [0; DATE_TIME_LEN];
+        for (digit, byte) in digits.iter_mut().zip(bytes) {
+            if byte & 0xf > 9 {
+                return None;
+            }
+            *digit = (byte >> 4) * 10 + (byte & 0xf);
+        }
+
+        // the weekday is left out, since it can be worked out
+        let [year, month, day, _, _, minute, second] = digits;
+        let hour = bytes[4] & HOUR_MASK;
+        let time = Self {
+            year: 2000 + year as u16,
+            month,
+            day,
+            hour: (hour >> 4) * 10 + (hour & 0xf),
+            minute,
+            second,
+        };
+        time.is_valid().then_some(time)
+    }
+
+    #[cfg(any(test, not(feature = "host")))]
+    fn to_bcd(self) -> [u8; DATE_TIME_LEN] {
+        let bcd = |value: u8| (value / 10) << 4 | (value % 10);
+        [
+            bcd((self.year - 2000) as u8),
+            bcd(self.month),
+            bcd(self.day),
+            self.weekday(),
+            bcd(self.hour),
+            bcd(self.minute),
+            bcd(self.second),
+        ]
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// the clock lost power, so it has to be set before it means anything
+    Stopped,
+    /// what came back isn't a time, so there probably isn't a clock
+    Invalid,
+}
+
+/// the clock, which can stand in as the filesystem's idea of now
+pub struct Rtc<B: Bus> {
+    bus: RefCell<B>,
+}
+
+impl<B: Bus> Rtc<B> {
+    #[cfg(any(test, not(feature = "host")))]
+    pub fn new(mut bus: B) -> Self {
+        unsafe {
+            set_rtc_control(&mut bus, true);
+            bus.write(GPIO_CONTROL, 1);
+        }
+        Self {
+            bus: RefCell::new(bus),
+        }
+    }
+
+    pub fn read(&self) -> Result<DateTime, RtcError> {
+        let mut status = [0];
+        self.transfer(REG_STATUS, true, &mut status);
+        if status[0] & STATUS_POWER_LOST != 0 {
+            return Err(RtcError::Stopped);
+        }
+
+        let mut bytes = [0; DATE_TIME_LEN];
+        self.transfer(REG_DATE_TIME, true, &mut bytes);
+        DateTime::from_bcd(bytes).ok_or(RtcError::Invalid)
+    }
+
+    /// set the time, which also starts the clock again if it lost power
+    #[cfg(any(test, not(feature = "host")))]
+    pub fn set(&self, time: &DateTime) -> Result<(), RtcError> {
+        if !time.is_valid() {
+            return Err(RtcError::Invalid);
+        }
+
+        // resetting is the only thing that clears the power flag
+        self.transfer(REG_RESET, false, &mut []);
+        self.transfer(REG_STATUS, false, &mut [STATUS_24H]);
+        self.transfer(REG_DATE_TIME, false, &mut time.to_bcd());
+        Ok(())
+    }
+
+    /// send a command for `register`, then read or write `data`
+    fn transfer(&self, register: u8, read: bool, data: &mut [u8]) {
+        let bus = &mut *self.bus.borrow_mut();
+        let command = COMMAND | register << 1 | if read { COMMAND_READ } else { 0 };
+        unsafe {
+            bus.write(GPIO_DATA, SCK);
+            bus.write(GPIO_DATA, SCK | CS);
+            bus.write(GPIO_DIRECTION, SCK | SIO | CS);
+            for bit in (0..8).rev() {
+                clock(bus, command >> bit & 1 != 0);
+            }
+
+            if read {
+                bus.write(GPIO_DIRECTION, SCK | CS);
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                    for bit in 0..8 {
+                        clock(bus, false);
+                        *byte |= ((bus.read(GPIO_DATA) & SIO != 0) as u8) << bit;
+                    }
+                }
+            } else {
+                for byte in data.iter() {
+                    for bit in 0..8 {
+                        clock(bus, byte >> bit & 1 != 0);
+                    }
+                }
+            }
+
+            bus.write(GPIO_DATA, SCK);
+        }
+    }
+}
+
+/// send one bit, which the clock takes on the rising edge. it wants about a microsecond each
+/// way, so each level gets written twice
+unsafe fn clock<B: Bus>(bus: &mut B, bit: bool) {
+    let sio = if bit { SIO } else { 0 };
+    for _ in 0..2 {
+        bus.write(GPIO_DATA, CS | sio);
+    }
+    for _ in 0..2 {
+        bus.write(GPIO_DATA, CS | SCK | sio);
+    }
+}
+
+impl<B: Bus> fmt::Debug for Rtc<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rtc").finish_non_exhaustive()
+    }
+}
+
+impl<B: Bus> TimeProvider for Rtc<B> {
+    fn get_current_date(&self) -> Date {
+        self.get_current_date_time().date
+    }
+
+    fn get_current_date_time(&self) -> FsDateTime {
+        match self.read() {
+            Ok(now) => FsDateTime::new(
+                Date::new(now.year, now.month.into(), now.day.into()),
+                Time::new(now.hour.into(), now.minute.into(), now.second.into(), 0),
+            ),
+            // with no clock, files get the same date they would have anyway
+            Err(_) => DefaultTimeProvider::new().get_current_date_time(),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! keeping `.sav` files on the card in sync with the cart's sram
+//!
+//! sram can't be read back until the game has run, so booting a game leaves a record behind, and
+//! the next boot copies sram out to the save file it names
+
+use ape_fatfs::dir::Dir;
+use ape_fatfs::error::Error;
+use ape_fatfs::fs::{OemCpConverter, ReadWriteSeek};
+use ape_fatfs::time::TimeProvider;
+use embedded_io::blocking::{Read, Write};
+use log::info;
+
+use crate::ezflash::{read_sram, write_sram, Bus, SRAM_SIZE};
+use crate::path::{PathBuf, MAX_PATH};
+use crate::savetype::SaveType;
+
+const RECORD_DIR: &str = "EZFODE";
+/// the size of the save and the path to write it to, on separate lines
+const RECORD: &str = "EZFODE/PENDING.TXT";
+
+#[derive(Debug)]
+pub enum SaveError<E> {
+    Fs(Error<E>),
+    /// the pending save record couldn't be parsed, or asks for more than there is sram
+    BadRecord,
+    /// the save's path doesn't fit in the record
+    PathTooLong,
+}
+
+impl<E> From<Error<E>> for SaveError<E> {
+    fn from(value: Error<E>) -> Self {
+        Self::Fs(value)
+    }
+}
+
+/// copy sram out to the save file of the game that was booted last, if there is one
+pub fn backup_pending<IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter, B: Bus>(
+    root: &Dir<IO, TP, OCC>,
+    bus: &mut B,
+) -> Result<(), SaveError<IO::Error>> {
+    let mut record = match root.open_file(RECORD) {
+        Ok(record) => record,
+        Err(Error::NotFound) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut bytes = [0u8; MAX_PATH + 16];
+    let mut len = 0;
+    loop {
+        let read = record.read(&mut bytes[len..])?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+    }
+    drop(record);
+
+    let (size, path) = core::str::from_utf8(&bytes[..len])
+        .ok()
+        .and_then(|record| record.split_once('\n'))
+        .ok_or(SaveError::BadRecord)?;
+    let size: usize = size.parse().map_err(|_| SaveError::BadRecord)?;
+    if size > SRAM_SIZE {
+        return Err(SaveError::BadRecord);
+    }
+    let path = path.trim_end_matches('\n');
+    let path = PathBuf::from_str(path).ok_or(SaveError::BadRecord)?;
+    info!("backing up save to {}", path.as_str());
+
+    let mut file = root.create_file(path.as_str())?;
+    let mut chunk = [0u8; 512];
+    let mut pos = 0;
+    while pos < size {
+        let len = (size - pos).min(chunk.len());
+        unsafe { read_sram(bus, pos, &mut chunk[..len]) };
+        file.write_all(&chunk[..len])?;
+        pos += len;
+    }
+    file.truncate()?;
+    file.flush()?;
+    drop(file);
+
+    // only forget about it once it's safely on the card
+    root.remove(RECORD)?;
+    Ok(())
+}
+
+/// copy the save file for `rom_path` into sram, and remember to back it up next boot
+pub fn restore<IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter, B: Bus>(
+    root: &Dir<IO, TP, OCC>,
+    bus: &mut B,
+    rom_path: &str,
+    save_type: SaveType,
+) -> Result<(), SaveError<IO::Error>> {
+    if save_type == SaveType::None {
+        return Ok(());
+    }
+
+    let size = save_type.size();
+    let path = PathBuf::for_rom(rom_path, "sav").ok_or(SaveError::PathTooLong)?;
+
+    let mut chunk = [0u8; 512];
+    let mut pos = 0;
+    match root.open_file(path.as_str()) {
+        Ok(mut file) => {
+            info!("restoring save from {}", path.as_str());
+            while pos < size {
+                let len = file.read(&mut chunk[..(size - pos).min(512)])?;
+                if len == 0 {
+                    break;
+                }
+                unsafe { write_sram(bus, pos, &chunk[..len]) };
+                pos += len;
+            }
+        }
+        Err(Error::NotFound) => info!("starting a new save"),
+        Err(e) => return Err(e.into()),
+    }
+
+    // whatever's left is blank, which is all ones for flash and eeprom
+    chunk.fill(0xff);
+    while pos < size {
+        let len = (size - pos).min(chunk.len());
+        unsafe { write_sram(bus, pos, &chunk[..len]) };
+        pos += len;
+    }
+
+    root.create_dir(RECORD_DIR)?;
+    let mut record = root.create_file(RECORD)?;
+    record.truncate()?;
+    record.write_all(itoa::Buffer::new().format(size).as_bytes())?;
+    record.write_all(b"\n")?;
+    record.write_all(path.as_str().as_bytes())?;
+    record.write_all(b"\n")?;
+    record.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ape_fatfs::fs::{FileSystem, FsOptions};
+    use embedded_io::blocking::{Read, Write};
+    use std::vec::Vec;
+
+    use super::{backup_pending, restore, SaveError, RECORD};
+    use crate::disk::RamDisk;
+    use crate::ezflash::SRAM_SIZE;
+    use crate::fixture::fat_image;
+    use crate::fs::{BufferedIo, Pages};
+    use crate::partition::PartitionTable;
+    use crate::savetype::SaveType;
+    use crate::sim::Simulated;
+
+    fn read_all<R: Read>(file: &mut R) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut chunk = [0; 512];
+        loop {
+            let len = file.read(&mut chunk).unwrap();
+            if len == 0 {
+                return bytes;
+            }
+            bytes.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    #[test]
+    fn restore_then_back_up() {
+        let mut data = fat_image();
+        let mut disk = RamDisk::new(&mut data);
+        let info = *PartitionTable::read(&mut disk)
+            .unwrap()
+            .default_partition()
+            .unwrap();
+        let mut pages = Pages::new();
+        let io = BufferedIo::<512, 2048, 4, _>::new(info.open(&mut disk), &mut pages);
+        let fs = FileSystem::new(io, FsOptions::new()).unwrap();
+        let root = fs.root_dir();
+
+        // a save shorter than the game's sram, which happens with some emulators' saves
+        let save: Vec<u8> = (0..1000u32).map(|i| (i * 5 + i / 7) as u8).collect();
+        root.create_dir("GAMES").unwrap();
+        let mut file = root.create_file("GAMES/GAME.sav").unwrap();
+        file.write_all(&save).unwrap();
+        file.flush().unwrap();
+        drop(file);
+
+        // the bus only needs a card for loading games, which this doesn't do
+        let mut sim = Simulated::new(RamDisk::new(&mut []));
+        sim.sram().fill(0);
+        restore(&root, &mut sim, "GAMES/GAME.GBA", SaveType::Sram).unwrap();
+        assert!(sim.sram()[..1000] == save);
+        assert!(sim.sram()[1000..0x8000].iter().all(|&byte| byte == 0xff));
+        // and nothing past the save is touched
+        assert!(sim.sram()[0x8000..].iter().all(|&byte| byte == 0));
+
+        let record = read_all(&mut root.open_file(RECORD).unwrap());
+        assert_eq!(record, b"32768\nGAMES/GAME.sav\n");
+
+        // the game plays, then the next boot copies all of its sram out
+        sim.sram()[..0x8000].fill(0x5a);
+        backup_pending(&root, &mut sim).unwrap();
+        let saved = read_all(&mut root.open_file("GAMES/GAME.sav").unwrap());
+        assert!(saved.len() == 0x8000 && saved.iter().all(|&byte| byte == 0x5a));
+        assert!(root.open_file(RECORD).is_err());
+
+        // with no record left, there's nothing to do
+        backup_pending(&root, &mut sim).unwrap();
+    }
+
+    #[test]
+    fn record_larger_than_sram() {
+        let mut data = fat_image();
+        let mut disk = RamDisk::new(&mut data);
+        let info = *PartitionTable::read(&mut disk)
+            .unwrap()
+            .default_partition()
+            .unwrap();
+        let mut pages = Pages::new();
+        let io = BufferedIo::<512, 2048, 4, _>::new(info.open(&mut disk), &mut pages);
+        let fs = FileSystem::new(io, FsOptions::new()).unwrap();
+        let root = fs.root_dir();
+
+        root.create_dir("EZFODE").unwrap();
+        let mut record = root.create_file(RECORD).unwrap();
+        write!(record, "{}\nGAME.sav\n", SRAM_SIZE + 1).unwrap();
+        record.flush().unwrap();
+        drop(record);
+
+        let mut sim = Simulated::new(RamDisk::new(&mut []));
+        assert!(matches!(
+            backup_pending(&root, &mut sim),
+            Err(SaveError::BadRecord)
+        ));
+        // the record is kept, and nothing is written
+        assert!(root.open_file(RECORD).is_ok());
+        assert!(root.open_file("GAME.sav").is_err());
+    }
+}
@@ -0,0 +1,726 @@
+//! making games that save to eeprom or flash save to the cart's sram instead
+//!
+//! the cart only has sram, so the save library's routines get swapped for ones that do the same
+//! thing to sram. there are a lot of slightly different builds of those libraries, so rather than
+//! matching their code exactly they're found by the addresses and tables they can't do without.
+//! nothing is written over unless it starts and ends the way every version's functions do
+
+use core::ops::Range;
+
+use log::{info, warn};
+
+use crate::ezflash::{Bus, PSRAM_SIZE};
+use crate::loader::Target;
+use crate::patch::PatchError;
+use crate::savetype::SaveType;
+
+/// where the game's rom shows up
+const ROM: u32 = 0x8000000;
+
+/// the flash command address, which everything in the flash libraries that talks to the chip loads
+const FLASH_COMMAND: u32 = 0xe005555;
+/// where eeprom shows up, which is only the very end of the cart for 32MB games
+const EEPROM: u32 = 0xd000000;
+const EEPROM_LARGE: u32 = 0xdffff00;
+/// the dma that reads a block back from eeprom, 4 junk bits and 64 data bits a halfword each
+const EEPROM_READ_DMA: u32 = 0x80000044;
+
+/// how far behind a literal the instructions loading it can be
+const LDR_RANGE: usize = 1024;
+/// how far ahead of its first literal load a library function can start
+const MAX_PROLOGUE: usize = 0x200;
+/// how far from the library its own calls to itself can be
+const CALL_RANGE: usize = 0x1000;
+
+/// a flash chip's description, from its size to its id
+const FLASH_TYPE_LEN: usize = 22;
+/// offset of the chip id in a flash chip's description
+const FLASH_TYPE_ID: usize = 20;
+/// the routines for a chip sit in front of its description, nearest last, with the table of
+/// timeouts in between. older libraries don't have a byte at a time routine
+const FLASH_SETUP: [(usize, usize); 5] = [
+    (8, DONE),
+    (12, ERASE_SECTOR),
+    (16, ERASE_CHIP),
+    (20, PROGRAM_SECTOR),
+    (24, PROGRAM_BYTE),
+];
+
+const MAX_LITERALS: usize = 16;
+const MAX_FLASH_TYPES: usize = 8;
+
+// where each routine starts in ROUTINES
+const SET_BANK: usize = 0x00;
+const PROGRAM_SECTOR: usize = 0x5c;
+const ERASE_SECTOR: usize = 0x72;
+const ERASE_CHIP: usize = 0x88;
+const PROGRAM_BYTE: usize = 0x9a;
+const DONE: usize = 0xa6;
+const EEPROM_READ: usize = 0xb6;
+const EEPROM_WRITE: usize = 0xca;
+const EEPROM_VERIFY: usize = 0xde;
+
+/// thumb stand ins for the library routines, which get copied to the end of the game
+///
+/// flash sectors are 4k, with sectors past the first 64k in the second sram page. eeprom blocks
+/// are 8 bytes, and all of eeprom fits in the first page
+#[rustfmt::skip]
+const ROUTINES: [u16; 127] = [
+    // set_bank(bank), keeps r1-r3
+    0xb50e, // push {r1, r2, r3, lr}
+    0x4908, // ldr r1, =0x9fe0000
+    0x4a08, // ldr r2, =0xd200
+    0x800a, // strh r2, [r1]
+    0x2180, // movs r1, #128
+    0x0509, // lsls r1, r1, #20
+    0x4b07, // ldr r3, =0x1500
+    0x800b, // strh r3, [r1]
+    0x4907, // ldr r1, =0x8020000
+    0x800a, // strh r2, [r1]
+    0x4907, // ldr r1, =0x8040000
+    0x800b, // strh r3, [r1]
+    0x4907, // ldr r1, =0x9c00000
+    0x8008, // strh r0, [r1]
+    0x4907, // ldr r1, =0x9fc0000
+    0x800b, // strh r3, [r1]
+    0xbd0e, // pop {r1, r2, r3, pc}
+    0x0000, // (padding)
+    0x0000, 0x09fe, // .word 0x09fe0000
+    0xd200, 0x0000, // .word 0x0000d200
+    0x1500, 0x0000, // .word 0x00001500
+    0x0000, 0x0802, // .word 0x08020000
+    0x0000, 0x0804, // .word 0x08040000
+    0x0000, 0x09c0, // .word 0x09c00000
+    0x0000, 0x09fc, // .word 0x09fc0000
+    // sector_addr(sector) -> address, switching to its bank, keeps r1-r2
+    0xb500, // push {lr}
+    0x0400, // lsls r0, r0, #16
+    0x0c00, // lsrs r0, r0, #16
+    0xb401, // push {r0}
+    0x0900, // lsrs r0, r0, #4
+    0xf7ff, 0xffd9, // bl set_bank
+    0xbc01, // pop {r0}
+    0x0700, // lsls r0, r0, #28
+    0x0c00, // lsrs r0, r0, #16
+    0x23e0, // movs r3, #224
+    0x051b, // lsls r3, r3, #20
+    0x18c0, // adds r0, r0, r3
+    0xbd00, // pop {pc}
+    // program_sector(sector, src) -> 0
+    0xb500, // push {lr}
+    0xf7ff, 0xffef, // bl sector_addr
+    0x2201, // movs r2, #1
+    0x0312, // lsls r2, r2, #12
+    // 1:
+    0x3a01, // subs r2, #1
+    0x5c8b, // ldrb r3, [r1, r2]
+    0x5483, // strb r3, [r0, r2]
+    0xd1fb, // bne 1b
+    0x2000, // movs r0, #0
+    0xbd00, // pop {pc}
+    // erase_sector(sector) -> 0
+    0xb500, // push {lr}
+    0xf7ff, 0xffe4, // bl sector_addr
+    0x21ff, // movs r1, #255
+    0x2201, // movs r2, #1
+    0x0312, // lsls r2, r2, #12
+    // 1:
+    0x3a01, // subs r2, #1
+    0x5481, // strb r1, [r0, r2]
+    0xd1fc, // bne 1b
+    0x2000, // movs r0, #0
+    0xbd00, // pop {pc}
+    // erase_chip() -> 0
+    0xb510, // push {r4, lr}
+    0x2420, // movs r4, #32
+    // 1:
+    0x3c01, // subs r4, #1
+    0x0020, // movs r0, r4
+    0xf7ff, 0xffef, // bl erase_sector
+    0x2c00, // cmp r4, #0
+    0xd1f9, // bne 1b
+    0xbd10, // pop {r4, pc}
+    // program_byte(sector, offset, byte) -> 0
+    0xb500, // push {lr}
+    0xf7ff, 0xffd0, // bl sector_addr
+    0x5442, // strb r2, [r0, r1]
+    0x2000, // movs r0, #0
+    0xbd00, // pop {pc}
+    // done() -> 0, for waiting on a write that's already finished
+    0x2000, // movs r0, #0
+    0x4770, // bx lr
+    // eeprom_addr(block) -> address, keeps r1-r2
+    0x0400, // lsls r0, r0, #16
+    0x0b40, // lsrs r0, r0, #13
+    0x23e0, // movs r3, #224
+    0x051b, // lsls r3, r3, #20
+    0x18c0, // adds r0, r0, r3
+    0x4770, // bx lr
+    // eeprom_read(block, dst) -> 0
+    0xb500, // push {lr}
+    0xf7ff, 0xfff7, // bl eeprom_addr
+    0x2208, // movs r2, #8
+    // 1:
+    0x3a01, // subs r2, #1
+    0x5c83, // ldrb r3, [r0, r2]
+    0x548b, // strb r3, [r1, r2]
+    0xd1fb, // bne 1b
+    0x2000, // movs r0, #0
+    0xbd00, // pop {pc}
+    // eeprom_write(block, src) -> 0
+    0xb500, // push {lr}
+    0xf7ff, 0xffed, // bl eeprom_addr
+    0x2208, // movs r2, #8
+    // 1:
+    0x3a01, // subs r2, #1
+    0x5c8b, // ldrb r3, [r1, r2]
+    0x5483, // strb r3, [r0, r2]
+    0xd1fb, // bne 1b
+    0x2000, // movs r0, #0
+    0xbd00, // pop {pc}
+    // eeprom_verify(block, src) -> 0 if they match
+    0xb510, // push {r4, lr}
+    0xf7ff, 0xffe3, // bl eeprom_addr
+    0x2208, // movs r2, #8
+    // 1:
+    0x3a01, // subs r2, #1
+    0x5c83, // ldrb r3, [r0, r2]
+    0x5c8c, // ldrb r4, [r1, r2]
+    0x42a3, // cmp r3, r4
+    0xd103, // bne 2f
+    0x2a00, // cmp r2, #0
+    0xd1f8, // bne 1b
+    0x2000, // movs r0, #0
+    0xbd10, // pop {r4, pc}
+    // 2:
+    0x2080, // movs r0, #128
+    0x0200, // lsls r0, r0, #8
+    0xbd10, // pop {r4, pc}
+];
+
+/// a place to keep what's been found, since there's no allocator
+pub struct List<const N: usize>([Option<usize>; N]);
+
+impl<const N: usize> List<N> {
+    pub const fn new() -> Self {
+        Self([None; N])
+    }
+
+    pub fn push(&mut self, item: usize) {
+        if self.contains(item) {
+            return;
+        }
+        match self.0.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(item),
+            None => warn!("too many candidates to look at, ignoring some"),
+        }
+    }
+
+    pub fn contains(&self, item: usize) -> bool {
+        self.iter().any(|found| found == item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().flatten().copied()
+    }
+}
+
+/// point the save library's routines at sram, if the game has one that needs it
+pub fn apply<B: Bus>(target: &mut Target<B>, save_type: SaveType) -> Result<(), PatchError> {
+    let is_literal: fn(u32) -> bool = match save_type {
+        SaveType::Eeprom => |value| value == EEPROM || value == EEPROM_LARGE,
+        SaveType::Flash64K | SaveType::Flash128K => |value| value == FLASH_COMMAND,
+        SaveType::None | SaveType::Sram => return Ok(()),
+    };
+
+    let mut literals = List::<MAX_LITERALS>::new();
+    let mut flash_types = List::<MAX_FLASH_TYPES>::new();
+    let size = target.size() & !3;
+    scan(target, 0..size, 4, FLASH_TYPE_LEN + 2, |pos, bytes| {
+        if bytes.len() < 4 {
+            return;
+        }
+        if is_literal(word(bytes, 0)) {
+            literals.push(pos);
+        } else if save_type != SaveType::Eeprom && is_flash_type(bytes) {
+            flash_types.push(pos);
+        }
+    });
+
+    if literals.iter().next().is_none() {
+        warn!("couldn't find the save library, so the game won't be able to save");
+        return Ok(());
+    }
+
+    let base = (target.size() + 3) & !3;
+    if base + ROUTINES.len() * 2 > PSRAM_SIZE {
+        warn!("no room after the game for the save routines, so it won't be able to save");
+        return Ok(());
+    }
+    let mut routines = [0u8; ROUTINES.len() * 2];
+    for (bytes, op) in routines.as_chunks_mut::<2>().0.iter_mut().zip(ROUTINES) {
+        *bytes = op.to_le_bytes();
+    }
+    let size = target.size();
+    target.write(base, &routines)?;
+    let routine = |offset: usize| (ROM + (base + offset) as u32) | 1;
+
+    let patched = match save_type {
+        SaveType::Eeprom => patch_eeprom(target, &literals, routine)?,
+        _ => patch_flash(target, &literals, &flash_types, routine)?,
+    };
+    if patched == 0 {
+        // nothing points at the routines, so leave the game as it was
+        target.resize(size)?;
+        warn!("couldn't match the save library's functions, so the game won't be able to save");
+        return Ok(());
+    }
+    info!("pointed {} save routines at sram", patched);
+    Ok(())
+}
+
+/// the eeprom library has a function each for reading, writing and checking a block. the ones
+/// that read load the read dma, and otherwise they're in that order
+fn patch_eeprom<B: Bus>(
+    target: &mut Target<B>,
+    literals: &List<MAX_LITERALS>,
+    routine: impl Fn(usize) -> u32,
+) -> Result<usize, PatchError> {
+    let mut functions = List::<MAX_LITERALS>::new();
+    let mut readers = List::<MAX_LITERALS>::new();
+    let mut writers = List::<MAX_LITERALS>::new();
+    for literal in literals.iter() {
+        let Some(start) =
+            function_start(target, literal).filter(|&start| is_function(target, start, literal))
+        else {
+            continue;
+        };
+        functions.push(start);
+        if loads(target, start..literal, EEPROM_READ_DMA) {
+            readers.push(start);
+        } else {
+            writers.push(start);
+        }
+    }
+
+    let (read, write, verify) = match (readers.iter().next(), writers.iter().next()) {
+        (Some(read), Some(write)) => (Some(read), Some(write), readers.iter().nth(1)),
+        _ => {
+            let mut starts = functions.iter();
+            (starts.next(), starts.next(), starts.next())
+        }
+    };
+
+    let mut patched = 0;
+    for (start, offset) in [
+        (read, EEPROM_READ),
+        (write, EEPROM_WRITE),
+        (verify, EEPROM_VERIFY),
+    ] {
+        if let Some(start) = start {
+            write_stub(target, start, JUMP, routine(offset))?;
+            patched += 1;
+        }
+    }
+    Ok(patched)
+}
+
+/// the flash library picks a table of routines by the chip's id, so every table gets pointed at
+/// the sram routines, and reading the id gives back one it knows. the only other thing that
+/// still talks to the chip after that is switching banks on 128k chips
+fn patch_flash<B: Bus>(
+    target: &mut Target<B>,
+    literals: &List<MAX_LITERALS>,
+    flash_types: &List<MAX_FLASH_TYPES>,
+    routine: impl Fn(usize) -> u32,
+) -> Result<usize, PatchError> {
+    let mut patched = 0;
+    let mut id = None;
+    for flash_type in flash_types.iter() {
+        let mut setup = [0u8; 24];
+        let Some(from) = flash_type.checked_sub(setup.len()) else {
+            continue;
+        };
+        target.read(from, &mut setup);
+        let slot = |back: usize| word(&setup, setup.len() - back);
+        let size = target.size();
+        let in_game =
+            |back: usize| is_thumb_pointer(slot(back)) && ((slot(back) - ROM) as usize) < size;
+        // everything but the byte at a time routine has to be there, or this wasn't a table
+        if !FLASH_SETUP[..4].iter().all(|&(back, _)| in_game(back)) {
+            continue;
+        }
+
+        for &(back, offset) in &FLASH_SETUP {
+            if in_game(back) {
+                target.write(flash_type - back, &routine(offset).to_le_bytes())?;
+                patched += 1;
+            }
+        }
+
+        // the table the library falls back to when it doesn't know the chip has no id
+        let mut chip = [0u8; 2];
+        target.read(flash_type + FLASH_TYPE_ID, &mut chip);
+        if chip != [0; 2] {
+            id = id.or(Some(u16::from_le_bytes(chip)));
+        }
+    }
+    let Some(id) = id else {
+        warn!("couldn't find the flash chip tables, so the game won't be able to save");
+        return Ok(patched);
+    };
+
+    let mut functions = List::<MAX_LITERALS>::new();
+    for literal in literals.iter() {
+        let Some(start) =
+            function_start(target, literal).filter(|&start| is_function(target, start, literal))
+        else {
+            continue;
+        };
+        if functions.contains(start) {
+            continue;
+        }
+        functions.push(start);
+
+        if switches_bank(target, start..literal) {
+            write_stub(target, start, JUMP, routine(SET_BANK))?;
+        } else {
+            write_stub(target, start, RETURN, id.into())?;
+        }
+        patched += 1;
+    }
+    Ok(patched)
+}
+
+/// `ldr r3, [pc]; bx r3`, which is free to use as nothing takes a fourth argument
+const JUMP: [u16; 2] = [0x4b00, 0x4718];
+/// `ldr r0, [pc]; bx lr`
+const RETURN: [u16; 2] = [0x4800, 0x4770];
+/// `mov r8, r8`
+const NOP: u16 = 0x46c0;
+
+/// overwrite the start of a function with two instructions using a literal, which has to be
+/// word aligned
+fn write_stub<B: Bus>(
+    target: &mut Target<B>,
+    start: usize,
+    ops: [u16; 2],
+    literal: u32,
+) -> Result<(), PatchError> {
+    let mut stub = [0u8; 10];
+    let mut len = 0;
+    let mut push = |bytes: &[u8]| {
+        stub[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    };
+    if start & 2 != 0 {
+        push(&NOP.to_le_bytes());
+    }
+    push(&ops[0].to_le_bytes());
+    push(&ops[1].to_le_bytes());
+    push(&literal.to_le_bytes());
+    target.write(start, &stub[..len])
+}
+
+/// find where the function that `literal` is in the literal pool of starts
+///
+/// that's whichever comes last before the first load of it, a push or a call from nearby, since
+/// small functions don't always push anything
+fn function_start<B: Bus>(target: &mut Target<B>, literal: usize) -> Option<usize> {
+    let from = literal.saturating_sub(LDR_RANGE);
+    let mut first_load = None;
+    scan(target, from..literal, 2, 0, |pos, bytes| {
+        if first_load.is_none() && ldr_literal(pos, bytes) == Some(literal) {
+            first_load = Some(pos);
+        }
+    });
+    let first_load = first_load?;
+
+    let prologue = first_load.saturating_sub(MAX_PROLOGUE);
+    let mut start = None;
+    scan(target, prologue..first_load, 2, 0, |pos, bytes| {
+        // push {..} or push {.., lr}
+        if bytes.len() >= 2 && half(bytes, 0) & 0xfe00 == 0xb400 {
+            start = Some(pos);
+        }
+    });
+
+    let nearby = first_load.saturating_sub(CALL_RANGE)..(literal + CALL_RANGE).min(target.size());
+    scan(target, nearby, 2, 2, |pos, bytes| {
+        if let Some(to) = call_target(pos, bytes) {
+            if (prologue..=first_load).contains(&to) && !start.is_some_and(|start| to <= start) {
+                start = Some(to);
+            }
+        }
+    });
+
+    start
+}
+
+/// whether the code from `start` up to its literal pool at `literal` looks like a library function,
+/// which in every version starts with a `push {.., lr}` and returns before its literal pool
+fn is_function<B: Bus>(target: &mut Target<B>, start: usize, literal: usize) -> bool {
+    let mut first = [0u8; 2];
+    target.read(start, &mut first);
+    if half(&first, 0) & 0xff00 != 0xb500 {
+        warn!(
+            "save library candidate at {:#x} doesn't push lr, leaving it",
+            start
+        );
+        return false;
+    }
+
+    // pop {.., pc} or bx rm
+    let mut returns = false;
+    scan(target, start..literal, 2, 0, |_, bytes| {
+        returns |= bytes.len() >= 2
+            && (half(bytes, 0) & 0xff00 == 0xbd00 || half(bytes, 0) & 0xff87 == 0x4700);
+    });
+    if !returns {
+        warn!(
+            "save library candidate at {:#x} never returns, leaving it",
+            start
+        );
+    }
+    returns
+}
+
+/// where a `bl` at `pos` goes, if that's what's there
+fn call_target(pos: usize, bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let (high, low) = (half(bytes, 0), half(bytes, 2));
+    if high & 0xf800 != 0xf000 || low & 0xf800 != 0xf800 {
+        return None;
+    }
+
+    // 22 bit signed offset in halfwords, split across the two
+    let offset = ((((high as i32) << 21) >> 9) | ((low as i32 & 0x7ff) << 1)) as isize;
+    pos.checked_add_signed(4 + offset)
+}
+
+/// the flash bank switch is the only one that sends command 0xb0, with a `movs rd, #0xb0`
+fn switches_bank<B: Bus>(target: &mut Target<B>, code: Range<usize>) -> bool {
+    let mut found = false;
+    scan(target, code, 2, 0, |_, bytes| {
+        found |= bytes.len() >= 2 && half(bytes, 0) & 0xf8ff == 0x20b0;
+    });
+    found
+}
+
+/// whether any of `code` loads `value` out of a literal pool
+fn loads<B: Bus>(target: &mut Target<B>, code: Range<usize>, value: u32) -> bool {
+    let mut literals = List::<MAX_LITERALS>::new();
+    scan(target, code, 2, 0, |pos, bytes| {
+        if let Some(literal) = ldr_literal(pos, bytes) {
+            literals.push(literal);
+        }
+    });
+
+    let found = literals.iter().any(|literal| {
+        let mut bytes = [0u8; 4];
+        literal + 4 <= target.size() && {
+            target.read(literal, &mut bytes);
+            word(&bytes, 0) == value
+        }
+    });
+    found
+}
+
+/// where the literal loaded by an `ldr rd, [pc, #imm]` at `pos` is, if that's what's there
+pub fn ldr_literal(pos: usize, bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < 2 {
+        return None;
+    }
+    let op = half(bytes, 0);
+    (op >> 11 == 0b01001).then_some(((pos + 4) & !3) + (op as usize & 0xff) * 4)
+}
+
+/// a chip's size, then its 4k sectors' size, shift, count and first sector
+fn is_flash_type(bytes: &[u8]) -> bool {
+    if bytes.len() < FLASH_TYPE_LEN {
+        return false;
+    }
+
+    let size = word(bytes, 0);
+    (size == 0x10000 || size == 0x20000)
+        && word(bytes, 4) == 0x1000
+        && bytes[8] == 12
+        && half(bytes, 10) as u32 == size >> 12
+        && half(bytes, 12) == 0
+}
+
+fn is_thumb_pointer(value: u32) -> bool {
+    value & 0xfe000001 == ROM | 1
+}
+
+/// call `f` at every `step` in `range` with the game from there on, with at least `ahead` more
+/// bytes after the step unless the game ends first
+pub fn scan<B: Bus>(
+    target: &mut Target<B>,
+    range: Range<usize>,
+    step: usize,
+    ahead: usize,
+    mut f: impl FnMut(usize, &[u8]),
+) {
+    let mut window = [0u8; 1024];
+    let stride = window.len() - ahead.div_ceil(step) * step;
+    let mut pos = range.start;
+    while pos < range.end {
+        let len = window.len().min(target.size() - pos);
+        target.read(pos, &mut window[..len]);
+        for at in (0..stride.min(range.end - pos)).step_by(step) {
+            f(pos + at, &window[at..len]);
+        }
+        pos += stride;
+    }
+}
+
+pub fn word(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn half(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::{
+        apply, DONE, EEPROM, EEPROM_READ, EEPROM_READ_DMA, EEPROM_VERIFY, EEPROM_WRITE, ERASE_CHIP,
+        ERASE_SECTOR, FLASH_COMMAND, JUMP, PROGRAM_BYTE, PROGRAM_SECTOR, RETURN, ROM, ROUTINES,
+        SET_BANK,
+    };
+    use crate::disk::RamDisk;
+    use crate::loader::Target;
+    use crate::savetype::SaveType;
+    use crate::sim::Simulated;
+
+    /// the routines go straight after the game
+    const GAME_LEN: usize = 0x2000;
+
+    fn put(game: &mut [u8], at: usize, code: &[u16]) {
+        for (i, op) in code.iter().enumerate() {
+            game[at + i * 2..at + i * 2 + 2].copy_from_slice(&op.to_le_bytes());
+        }
+    }
+
+    fn put_word(game: &mut [u8], at: usize, value: u32) {
+        game[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn word(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn routine(offset: usize) -> u32 {
+        (ROM + (GAME_LEN + offset) as u32) | 1
+    }
+
+    /// the stub that replaces a function starting on a word
+    fn stub(ops: [u16; 2], literal: u32) -> Vec<u8> {
+        let mut stub = Vec::new();
+        stub.extend_from_slice(&ops[0].to_le_bytes());
+        stub.extend_from_slice(&ops[1].to_le_bytes());
+        stub.extend_from_slice(&literal.to_le_bytes());
+        stub
+    }
+
+    fn patched(game: &[u8], save_type: SaveType) -> Vec<u8> {
+        let mut sim = Simulated::new(RamDisk::new(&mut []));
+        let mut target = Target::new(&mut sim);
+        target.write(0, game).unwrap();
+        apply(&mut target, save_type).unwrap();
+
+        let mut out = vec![0; target.size()];
+        target.read(0, &mut out);
+        out
+    }
+
+    /// a read that loads the dma and eeprom's address, then returns
+    fn eeprom_reader(game: &mut [u8], at: usize) {
+        // push {r4, lr}; ldr r0, =dma; ldr r1, =eeprom; movs r0, #0; pop {r4, pc}
+        put(game, at, &[0xb510, 0x4802, 0x4902, 0x2000, 0xbd10]);
+        put_word(game, at + 0xc, EEPROM_READ_DMA);
+        put_word(game, at + 0x10, EEPROM);
+    }
+
+    #[test]
+    fn eeprom_library() {
+        let mut game = vec![0; GAME_LEN];
+        eeprom_reader(&mut game, 0x1000);
+        // push {r4-r7, lr}; ldr r1, =eeprom; movs r0, #0; pop {r4-r7, pc}
+        put(&mut game, 0x1100, &[0xb5f0, 0x4901, 0x2000, 0xbdf0]);
+        put_word(&mut game, 0x1108, EEPROM);
+        eeprom_reader(&mut game, 0x1200);
+        // and the address in some data nothing loads
+        put_word(&mut game, 0x1800, EEPROM);
+
+        let out = patched(&game, SaveType::Eeprom);
+        assert_eq!(out.len(), GAME_LEN + ROUTINES.len() * 2);
+        assert_eq!(out[0x1000..0x1008], stub(JUMP, routine(EEPROM_READ)));
+        assert_eq!(out[0x1100..0x1108], stub(JUMP, routine(EEPROM_WRITE)));
+        assert_eq!(out[0x1200..0x1208], stub(JUMP, routine(EEPROM_VERIFY)));
+        assert_eq!(out[0x1008..0x1100], game[0x1008..0x1100]);
+        assert_eq!(word(&out, 0x1800), EEPROM);
+        assert_eq!(out[GAME_LEN..GAME_LEN + 2], ROUTINES[0].to_le_bytes());
+    }
+
+    #[test]
+    fn flash_library() {
+        let mut game = vec![0; GAME_LEN];
+        // reading the id: push {lr}; ldr r1, =command; movs r0, #0; pop {pc}
+        put(&mut game, 0x1000, &[0xb500, 0x4901, 0x2000, 0xbd00]);
+        put_word(&mut game, 0x1008, FLASH_COMMAND);
+        // switching banks: push {lr}; movs r0, #0xb0; ldr r1, =command; pop {pc}
+        put(&mut game, 0x1100, &[0xb500, 0x20b0, 0x4901, 0xbd00]);
+        put_word(&mut game, 0x110c, FLASH_COMMAND);
+
+        // a 128k macronix chip's routines and description
+        let table = 0x1800;
+        for (i, to) in [0x1301, 0x1401, 0x1501, 0x1601, 0x1701]
+            .into_iter()
+            .enumerate()
+        {
+            put_word(&mut game, table - 24 + i * 4, ROM + to);
+        }
+        // the timeouts, which aren't code
+        put_word(&mut game, table - 4, ROM + 0x1780);
+        put_word(&mut game, table, 0x20000);
+        put_word(&mut game, table + 4, 0x1000);
+        game[table + 8] = 12;
+        put(&mut game, table + 10, &[0x20, 0]);
+        put(&mut game, table + 20, &[0x09c2]);
+
+        let out = patched(&game, SaveType::Flash128K);
+        assert_eq!(out.len(), GAME_LEN + ROUTINES.len() * 2);
+        assert_eq!(out[0x1000..0x1008], stub(RETURN, 0x09c2));
+        assert_eq!(out[0x1100..0x1108], stub(JUMP, routine(SET_BANK)));
+        for (back, offset) in [
+            (24, PROGRAM_BYTE),
+            (20, PROGRAM_SECTOR),
+            (16, ERASE_CHIP),
+            (12, ERASE_SECTOR),
+            (8, DONE),
+        ] {
+            assert_eq!(word(&out, table - back), routine(offset), "{back}");
+        }
+        assert_eq!(word(&out, table - 4), ROM + 0x1780);
+    }
+
+    #[test]
+    fn leaves_unknown_code_alone() {
+        let mut game = vec![0; GAME_LEN];
+        // loads eeprom's address, but without pushing lr the way the library does:
+        // push {r4}; ldr r1, =eeprom; pop {r4}; bx lr
+        put(&mut game, 0x1000, &[0xb410, 0x4901, 0xbc10, 0x4770]);
+        put_word(&mut game, 0x1008, EEPROM);
+        put_word(&mut game, 0x1800, EEPROM);
+
+        let out = patched(&game, SaveType::Eeprom);
+        assert!(out == game);
+    }
+}
@@ -0,0 +1,172 @@
+//! working out what kind of save a game uses from the nintendo library strings in it
+
+#[cfg(feature = "host")]
+use embedded_io::blocking::Read;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveType {
+    None,
+    /// 32k of sram or fram
+    Sram,
+    /// the library doesn't say whether it's the 512 byte or the 8k part, so assume 8k
+    Eeprom,
+    Flash64K,
+    Flash128K,
+}
+
+impl SaveType {
+    /// size of the save in bytes
+    #[cfg(any(test, not(feature = "host")))]
+    pub fn size(self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Sram => 0x8000,
+            Self::Eeprom => 0x2000,
+            Self::Flash64K => 0x10000,
+            Self::Flash128K => 0x20000,
+        }
+    }
+}
+
+// all of these are followed by a three digit version
+const MARKERS: [(&[u8], SaveType); 6] = [
+    (b"SRAM_V", SaveType::Sram),
+    (b"SRAM_F_V", SaveType::Sram),
+    (b"EEPROM_V", SaveType::Eeprom),
+    (b"FLASH_V", SaveType::Flash64K),
+    (b"FLASH512_V", SaveType::Flash64K),
+    (b"FLASH1M_V", SaveType::Flash128K),
+];
+/// length of the longest marker
+const MAX_LEN: usize = 10;
+
+/// finds markers in a rom that's fed in a piece at a time
+pub struct Scanner {
+    /// the end of what's been fed so far, for markers split across pieces
+    tail: [u8; MAX_LEN - 1],
+    tail_len: usize,
+    found: Option<SaveType>,
+}
+
+impl Scanner {
+    pub const fn new() -> Self {
+        Self {
+            tail: [0; MAX_LEN - 1],
+            tail_len: 0,
+            found: None,
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.found.is_some() {
+            return;
+        }
+
+        let mut seam = [0u8; 2 * (MAX_LEN - 1)];
+        let head = bytes.len().min(MAX_LEN - 1);
+        seam[..self.tail_len].copy_from_slice(&self.tail[..self.tail_len]);
+        seam[self.tail_len..self.tail_len + head].copy_from_slice(&bytes[..head]);
+        self.found = find(&seam[..self.tail_len + head]).or_else(|| find(bytes));
+
+        // keep the last few bytes of everything fed so far
+        let seam = &seam[..self.tail_len + head];
+        let keep = MAX_LEN - 1;
+        if bytes.len() >= keep {
+            self.tail.copy_from_slice(&bytes[bytes.len() - keep..]);
+            self.tail_len = keep;
+        } else {
+            let start = seam.len().saturating_sub(keep);
+            self.tail_len = seam.len() - start;
+            self.tail[..self.tail_len].copy_from_slice(&seam[start..]);
+        }
+    }
+
+    #[cfg(feature = "host")]
+    pub fn done(&self) -> bool {
+        self.found.is_some()
+    }
+
+    pub fn save_type(&self) -> SaveType {
+        self.found.unwrap_or(SaveType::None)
+    }
+}
+
+fn find(bytes: &[u8]) -> Option<SaveType> {
+    // every marker ends in _V, so only look closer around those
+    for (i, pair) in bytes.windows(2).enumerate() {
+        if pair != b"_V" {
+            continue;
+        }
+
+        let before = &bytes[..i + 2];
+        if let Some((_, kind)) = MARKERS.iter().find(|(marker, _)| before.ends_with(marker)) {
+            return Some(*kind);
+        }
+    }
+
+    None
+}
+
+/// scan a whole rom, stopping at the first marker
+#[cfg(feature = "host")]
+pub fn detect<R: Read>(rom: &mut R) -> Result<SaveType, R::Error> {
+    let mut scanner = Scanner::new();
+    let mut buf = [0u8; 512];
+
+    while !scanner.done() {
+        let len = rom.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        scanner.feed(&buf[..len]);
+    }
+
+    Ok(scanner.save_type())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+
+    use super::{SaveType, Scanner};
+
+    #[test]
+    fn marker_split_across_feeds() {
+        let mut rom = vec![0xff; 0x400];
+        rom[0x1fb..0x1fb + 12].copy_from_slice(b"FLASH1M_V103");
+
+        // every split through the marker, with the second half in a bigger piece
+        for split in 0x1fb..0x1fb + 10 {
+            let mut scanner = Scanner::new();
+            scanner.feed(&rom[..split]);
+            scanner.feed(&rom[split..]);
+            assert_eq!(
+                scanner.save_type(),
+                SaveType::Flash128K,
+                "split at {split:#x}"
+            );
+        }
+
+        // and a byte at a time, where the marker only ever shows up in the tail
+        let mut scanner = Scanner::new();
+        for byte in &rom {
+            scanner.feed(core::slice::from_ref(byte));
+        }
+        assert_eq!(scanner.save_type(), SaveType::Flash128K);
+    }
+
+    #[test]
+    fn unmarked_rom() {
+        let mut rom = vec![0; 0x1000];
+        // near misses: no version marker, and a marker with a piece missing
+        rom[0x100..0x108].copy_from_slice(b"EEPROM_X");
+        rom[0x200..0x207].copy_from_slice(b"LASH1M_");
+        rom[0x300..0x304].copy_from_slice(b"AM_V");
+
+        let mut scanner = Scanner::new();
+        for piece in rom.chunks(512) {
+            scanner.feed(piece);
+        }
+        assert_eq!(scanner.save_type(), SaveType::None);
+    }
+}
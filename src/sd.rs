@@ -1,12 +1,10 @@
-use core::ffi::c_void;
 use core::fmt;
 
-use gba::prelude::BACKDROP_COLOR;
-use gba::video::Color;
-
 use crate::delay;
-use crate::dma::dma_copy;
-use crate::ezflash::{sd_disable, sd_enable, sd_read_state, set_rompage, wait_sd_response};
+use crate::ezflash::{
+    sd_disable, sd_enable, sd_read_request, sd_read_state, sd_response, sd_write_request,
+    set_rompage, wait_sd_response, wait_sd_write_busy, Bus, ROMPAGE_OS, ROMPAGE_PSRAM,
+};
 
 pub type Lba = u32;
 
@@ -15,87 +13,199 @@ where
     Self::Error: fmt::Debug,
 {
     type Error;
+    /// size of the device in blocks, if it can tell
+    fn block_count(&self) -> Option<Lba>;
     fn read_blocks(&mut self, start_lba: Lba, buffer: &mut [u8]) -> Result<(), Self::Error>;
     fn write_blocks(&mut self, start_lba: Lba, buffer: &[u8]) -> Result<(), Self::Error>;
 }
 
 #[derive(Debug)]
-pub enum BlockIoError {}
+pub enum BlockIoError {
+    /// the card stayed busy after a write, so it wasn't safe to retry
+    Timeout { lba: Lba },
+    /// every attempt at transferring the chunk starting at `lba` timed out
+    RetriesExhausted { lba: Lba },
+    /// the transfer would go past the end of the device
+    OutOfRange { lba: Lba, count: u32 },
+    /// the buffer isn't a whole number of blocks, or isn't halfword aligned for dma
+    Misaligned,
+}
+
+pub struct SdCard<B: Bus> {
+    bus: B,
+}
+
+impl<B: Bus> SdCard<B> {
+    pub fn new(bus: B) -> Self {
+        Self { bus }
+    }
+
+    #[cfg(test)]
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// check that `buffer` can be transferred with dma, and return how many blocks it holds
+    #[link_section = ".iwram"]
+    fn check_buffer(start_lba: Lba, buffer: &[u8]) -> Result<u32, BlockIoError> {
+        // 2 ^ 9 = 512
+        if buffer.len() & 511 != 0 || buffer.as_ptr() as usize & 1 != 0 {
+            return Err(BlockIoError::Misaligned);
+        }
+
+        let count = (buffer.len() >> 9) as u32;
+        if start_lba.checked_add(count).is_none() {
+            return Err(BlockIoError::OutOfRange {
+                lba: start_lba,
+                count,
+            });
+        }
+
+        Ok(count)
+    }
+
+    #[link_section = ".iwram"]
+    unsafe fn read_chunks(
+        &mut self,
+        start_lba: Lba,
+        buffer: &mut [u8],
+        count: u32,
+    ) -> Result<(), BlockIoError> {
+        let bus = &mut self.bus;
+
+        'chunks: for i in (0..count).step_by(4) {
+            // read at most 4 blocks at a time
+            let blocks = 4.min(count - i) as u16;
+
+            // try three times to read
+            for _ in 0..3 {
+                sd_enable(bus);
+                sd_read_request(bus, start_lba + i, blocks);
 
-pub struct SdCard;
+                sd_read_state(bus);
+                if wait_sd_response(bus).is_ok() {
+                    sd_enable(bus);
+                    // successful read!
+                    let start = i as usize * 512;
+                    bus.read_sd_buffer(&mut buffer[start..start + blocks as usize * 512]);
+
+                    // keep copying chunks
+                    continue 'chunks;
+                } else {
+                    // read timed out, try again
+                    delay(5000);
+                }
+            }
+
+            // oh no! we couldn't read!
+            return Err(BlockIoError::RetriesExhausted { lba: start_lba + i });
+        }
+
+        Ok(())
+    }
+
+    #[link_section = ".iwram"]
+    unsafe fn write_chunks(
+        &mut self,
+        start_lba: Lba,
+        buffer: &[u8],
+        count: u32,
+    ) -> Result<(), BlockIoError> {
+        let bus = &mut self.bus;
+
+        'chunks: for i in (0..count).step_by(4) {
+            // write at most 4 blocks at a time
+            let blocks = 4.min(count - i) as u16;
+
+            // try three times to write
+            for _ in 0..3 {
+                sd_enable(bus);
+
+                // stage the data in the sd buffer, then tell the card where it goes
+                let start = i as usize * 512;
+                bus.write_sd_buffer(&buffer[start..start + blocks as usize * 512]);
+                sd_write_request(bus, start_lba + i, blocks);
+
+                if wait_sd_write_busy(bus).is_ok() {
+                    // successful write!
+                    continue 'chunks;
+                }
+
+                // write timed out, give the card a moment before trying again
+                delay(5000);
+                if sd_response(bus) != 0 {
+                    // restaging the buffer while the card is still programming would corrupt it
+                    return Err(BlockIoError::Timeout { lba: start_lba + i });
+                }
+            }
 
-impl SdCard {
-    pub fn partition(&mut self, start: Lba, end: Lba) -> Partition<'_, 512, Self> {
-        Partition {
-            disk: self,
-            start,
-            end,
+            // oh no! we couldn't write!
+            return Err(BlockIoError::RetriesExhausted { lba: start_lba + i });
         }
+
+        // let the card finish committing the last chunk
+        delay(3000);
+
+        Ok(())
     }
 }
 
-impl BlockIo<512> for SdCard {
+impl<B: Bus> BlockIo<512> for SdCard<B> {
     type Error = BlockIoError;
 
+    fn block_count(&self) -> Option<Lba> {
+        // the ez-flash doesn't tell us how big the card is, reads past the end just fail
+        None
+    }
+
     #[link_section = ".iwram"]
     fn read_blocks(&mut self, start_lba: Lba, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let count = Self::check_buffer(start_lba, buffer)?;
+
         unsafe {
-            set_rompage(0x8000); // OS mode
-            sd_enable();
-
-            // we can't overrun, and we need whole blocks
-            // 2 ^ 9 = 512
-            let count = (buffer.len() >> 9) as u32;
-            'chunks: for i in (0..count).step_by(4) {
-                // read at most 4 blocks at a time
-                let blocks = 4.min(count - i) as u16;
-                // low and high 16 bits of the address
-                let addr_l = (start_lba + i) as u16;
-                let addr_h = ((start_lba + i) >> 16) as u16;
-
-                // try three times to read
-                for _ in 0..2 {
-                    sd_enable();
-
-                    (0x9fe0000 as *mut u16).write_volatile(0xd200);
-                    (0x8000000 as *mut u16).write_volatile(0x1500);
-                    (0x8020000 as *mut u16).write_volatile(0xd200);
-                    (0x8040000 as *mut u16).write_volatile(0x1500);
-                    (0x9600000 as *mut u16).write_volatile(addr_l);
-                    (0x9620000 as *mut u16).write_volatile(addr_h);
-                    (0x9640000 as *mut u16).write_volatile(blocks);
-                    (0x9fc0000 as *mut u16).write_volatile(0x1500);
-
-                    sd_read_state();
-                    if wait_sd_response().is_ok() {
-                        sd_enable();
-                        // successful read!
-                        let src = 0x9e00000 as *mut c_void;
-                        let dst = &mut buffer[i as usize * 512] as *mut u8 as *mut c_void;
-                        dma_copy(src, dst, blocks as u32 * 512);
-
-                        // keep copying chunks
-                        continue 'chunks;
-                    } else {
-                        // read timed out, try again
-                        delay(5000);
-                    }
-                }
+            set_rompage(&mut self.bus, ROMPAGE_OS);
+            sd_enable(&mut self.bus);
 
-                // oh no! we couldn't read!
-                BACKDROP_COLOR.write(Color::BLUE);
-                loop {}
-            }
+            let result = self.read_chunks(start_lba, buffer, count);
 
-            sd_disable();
-            set_rompage(0x200); // game mode
+            sd_disable(&mut self.bus);
+            set_rompage(&mut self.bus, ROMPAGE_PSRAM);
+
+            result
         }
+    }
 
-        Ok(())
+    #[link_section = ".iwram"]
+    fn write_blocks(&mut self, start_lba: Lba, buffer: &[u8]) -> Result<(), Self::Error> {
+        let count = Self::check_buffer(start_lba, buffer)?;
+
+        unsafe {
+            set_rompage(&mut self.bus, ROMPAGE_OS);
+            sd_enable(&mut self.bus);
+
+            let result = self.write_chunks(start_lba, buffer, count);
+
+            sd_disable(&mut self.bus);
+            set_rompage(&mut self.bus, ROMPAGE_PSRAM);
+
+            result
+        }
     }
+}
 
-    fn write_blocks(&mut self, start_lba: Lba, src: &[u8]) -> Result<(), Self::Error> {
-        todo!()
+impl<const BS: usize, D: BlockIo<BS>> BlockIo<BS> for &mut D {
+    type Error = D::Error;
+
+    fn block_count(&self) -> Option<Lba> {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&mut self, start_lba: Lba, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        (**self).read_blocks(start_lba, buffer)
+    }
+
+    fn write_blocks(&mut self, start_lba: Lba, buffer: &[u8]) -> Result<(), Self::Error> {
+        (**self).write_blocks(start_lba, buffer)
     }
 }
 
@@ -105,16 +215,42 @@ pub struct Partition<'d, const BS: usize, D: BlockIo<BS>> {
     end: Lba,
 }
 
-impl<'d, const BS: usize, D: BlockIo<BS>> BlockIo<BS> for Partition<'d, BS, D> {
+impl<'d, const BS: usize, D: BlockIo<BS>> Partition<'d, BS, D> {
+    /// `end` is exclusive
+    pub fn new(disk: &'d mut D, start: Lba, end: Lba) -> Self {
+        Self { disk, start, end }
+    }
+
+    /// translate `start_lba` to the disk, if `len` bytes starting there fit in the partition
+    fn translate(&self, start_lba: Lba, len: usize) -> Result<Lba, BlockIoError> {
+        let count = len.div_ceil(BS) as u32;
+        match start_lba.checked_add(count) {
+            Some(end) if end <= self.end.saturating_sub(self.start) => Ok(self.start + start_lba),
+            _ => Err(BlockIoError::OutOfRange {
+                lba: start_lba,
+                count,
+            }),
+        }
+    }
+}
+
+impl<'d, const BS: usize, D: BlockIo<BS>> BlockIo<BS> for Partition<'d, BS, D>
+where
+    D::Error: From<BlockIoError>,
+{
     type Error = D::Error;
 
+    fn block_count(&self) -> Option<Lba> {
+        Some(self.end.saturating_sub(self.start))
+    }
+
     fn read_blocks(&mut self, start_lba: Lba, dst: &mut [u8]) -> Result<(), Self::Error> {
-        let count = dst.len() / BS;
-        self.disk.read_blocks(start_lba + self.start, dst)
+        let lba = self.translate(start_lba, dst.len())?;
+        self.disk.read_blocks(lba, dst)
     }
 
     fn write_blocks(&mut self, start_lba: Lba, src: &[u8]) -> Result<(), Self::Error> {
-        let count = src.len() / BS;
-        self.disk.write_blocks(start_lba + self.start, src)
+        let lba = self.translate(start_lba, src.len())?;
+        self.disk.write_blocks(lba, src)
     }
 }
@@ -0,0 +1,596 @@
+//! a simulated ez-flash, for running the driver on a host
+//!
+//! it decodes the same register writes the hardware sees, so it catches
+//! mistakes like forgetting the unlock sequence or touching the card outside OS mode
+
+use crate::ezflash::{
+    Bus, SdControl, FINISH, LED_CTRL, NOR_CMD_1, NOR_CMD_2, NOR_PAGE_SHIFT, NOR_RESET,
+    NOR_SECTOR_SIZE, NOR_SIZE, NOR_WINDOW, NOR_WINDOW_SIZE, PSRAMPAGE, PSRAM_PAGE_SHIFT,
+    PSRAM_SIZE, PSRAM_WINDOW, PSRAM_WINDOW_SIZE, RAMPAGE, ROMPAGE, ROMPAGE_OS, ROMPAGE_PSRAM,
+    RTC_CTRL, SD_ADDR_H, SD_ADDR_L, SD_BLOCKS, SD_BUF, SD_CTRL, SRAM_SIZE, SRAM_WINDOW,
+    SRAM_WINDOW_SIZE, UNLOCK,
+};
+use crate::rtc::{
+    COMMAND, COMMAND_READ, CS, DATE_TIME_LEN, GPIO_CONTROL, GPIO_DATA, GPIO_DIRECTION,
+    REG_DATE_TIME, REG_RESET, REG_STATUS, SCK, SIO, STATUS_POWER_LOST,
+};
+use crate::sd::{BlockIo, Lba};
+use std::{vec, vec::Vec};
+
+/// what reading the start of the sd buffer window returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SdBufMode {
+    /// the data from the last read
+    Data,
+    /// 0xeee1 until a read is done
+    ReadState,
+    /// nonzero until a write is done
+    WriteState,
+}
+
+/// how far through a command the nor chip is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NorState {
+    Read,
+    /// had the first and second unlock writes
+    Unlocked1,
+    Unlocked2,
+    /// reads give the chip's ids
+    Autoselect,
+    /// the next write is the data
+    Program,
+    Erase,
+    EraseUnlocked1,
+    EraseUnlocked2,
+}
+
+/// a spansion part like the one on the cart
+const NOR_ID: (u16, u16) = (0x0001, 0x227e);
+
+/// the clock on the gpio port, which keeps whatever time it was set to rather than ticking
+struct Clock {
+    status: u8,
+    date_time: [u8; DATE_TIME_LEN],
+    /// the command, once all of it has been clocked in
+    command: Option<u8>,
+    /// bits clocked in or out since the chip was selected, counting the command
+    bits: usize,
+    /// the data clocked in so far, or still to clock out
+    data: [u8; DATE_TIME_LEN],
+    /// what the chip is putting out on sio
+    sio: bool,
+}
+
+impl Clock {
+    fn new() -> Self {
+        Self {
+            // as if the battery was just put in
+            status: STATUS_POWER_LOST,
+            date_time: [0, 1, 1, 6, 0, 0, 0],
+            command: None,
+            bits: 0,
+            data: [0; DATE_TIME_LEN],
+            sio: false,
+        }
+    }
+
+    fn select(&mut self) {
+        self.command = None;
+        self.bits = 0;
+    }
+
+    fn register_len(command: u8) -> usize {
+        match command >> 1 & 7 {
+            REG_STATUS => 1,
+            REG_DATE_TIME => DATE_TIME_LEN,
+            _ => 0,
+        }
+    }
+
+    /// the chip puts out the next bit as the clock falls
+    fn falling(&mut self) {
+        if let Some(command) = self.command {
+            let bit = self.bits - 8;
+            if command & COMMAND_READ != 0 && bit < Self::register_len(command) * 8 {
+                self.sio = self.data[bit / 8] >> (bit % 8) & 1 != 0;
+            }
+        }
+    }
+
+    /// and takes in a bit as it rises, most significant first for commands and least for data
+    fn rising(&mut self, sio: bool) {
+        let Some(command) = self.command else {
+            self.data[0] = self.data[0] << 1 | sio as u8;
+            self.bits += 1;
+            if self.bits == 8 {
+                self.start(self.data[0]);
+            }
+            return;
+        };
+
+        let bit = self.bits - 8;
+        let len = Self::register_len(command);
+        self.bits += 1;
+        if command & COMMAND_READ != 0 || bit >= len * 8 {
+            return;
+        }
+
+        if bit % 8 == 0 {
+            self.data[bit / 8] = 0;
+        }
+        self.data[bit / 8] |= (sio as u8) << (bit % 8);
+        if bit + 1 == len * 8 {
+            match command >> 1 & 7 {
+                REG_STATUS => self.status = self.data[0] & !STATUS_POWER_LOST,
+                _ => self.date_time = self.data,
+            }
+        }
+    }
+
+    fn start(&mut self, command: u8) {
+        assert_eq!(command & 0xf0, COMMAND, "bad rtc command {:#x}", command);
+        self.command = Some(command);
+        match (command >> 1 & 7, command & COMMAND_READ != 0) {
+            (REG_RESET, _) => {
+                *self = Self {
+                    status: 0,
+                    ..Self::new()
+                }
+            }
+            (REG_STATUS, true) => self.data[0] = self.status,
+            (REG_DATE_TIME, true) => self.data = self.date_time,
+            _ => (),
+        }
+    }
+}
+
+pub struct Simulated<D: BlockIo<512>> {
+    disk: D,
+    /// how much of the unlock sequence has been written
+    unlock: usize,
+    /// register writes since the unlock, applied when the transaction finishes
+    pending: [(usize, u16); 4],
+    pending_len: usize,
+    pub rompage: u16,
+    pub psrampage: u16,
+    pub rampage: u16,
+    pub led: u16,
+    sd_control: u16,
+    sd_lba: Lba,
+    sd_buf: [u8; 2048],
+    sd_buf_mode: SdBufMode,
+    /// whether the last transfer went through
+    sd_ok: bool,
+    psram: Vec<u8>,
+    sram: Vec<u8>,
+    nor: Vec<u8>,
+    nor_state: NorState,
+    pub rtc: u16,
+    gpio_control: u16,
+    gpio_direction: u16,
+    gpio_pins: u16,
+    clock: Clock,
+}
+
+impl<D: BlockIo<512>> Simulated<D> {
+    pub fn new(disk: D) -> Self {
+        Self {
+            disk,
+            unlock: 0,
+            pending: [(0, 0); 4],
+            pending_len: 0,
+            rompage: ROMPAGE_PSRAM,
+            psrampage: 0,
+            rampage: 0,
+            led: 0,
+            sd_control: SdControl::Disable as u16,
+            sd_lba: 0,
+            sd_buf: [0; 2048],
+            sd_buf_mode: SdBufMode::Data,
+            sd_ok: false,
+            psram: vec![0; PSRAM_SIZE],
+            sram: vec![0xff; SRAM_SIZE],
+            nor: vec![0xff; NOR_SIZE],
+            nor_state: NorState::Read,
+            rtc: 0,
+            gpio_control: 0,
+            gpio_direction: 0,
+            gpio_pins: 0,
+            clock: Clock::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn disk(&mut self) -> &mut D {
+        &mut self.disk
+    }
+
+    #[cfg(test)]
+    pub fn psram(&self) -> &[u8] {
+        &self.psram
+    }
+
+    #[cfg(test)]
+    pub fn sram(&mut self) -> &mut [u8] {
+        &mut self.sram
+    }
+
+    #[cfg(test)]
+    pub fn nor(&self) -> &[u8] {
+        &self.nor
+    }
+
+    /// where `addr` is in nor, if nor is paged in there
+    fn nor_offset(&self, addr: usize) -> Option<usize> {
+        if self.rompage >= ROMPAGE_PSRAM
+            || !(NOR_WINDOW..NOR_WINDOW + NOR_WINDOW_SIZE).contains(&addr)
+        {
+            return None;
+        }
+
+        let offset = ((self.rompage as usize) << NOR_PAGE_SHIFT) + addr - NOR_WINDOW;
+        assert!(offset < NOR_SIZE, "nor access past the end");
+        Some(offset)
+    }
+
+    fn nor_write(&mut self, offset: usize, value: u16) {
+        use NorState::*;
+
+        // the chip only looks at the low address lines for commands
+        let command = (offset & 0xffe, value);
+        self.nor_state = match (self.nor_state, command) {
+            (Program, _) => {
+                // programming can only clear bits
+                self.nor[offset] &= value as u8;
+                self.nor[offset + 1] &= (value >> 8) as u8;
+                Read
+            }
+            (_, (_, NOR_RESET)) => Read,
+            (Read | Autoselect, (NOR_CMD_1, 0xaa)) => Unlocked1,
+            (Unlocked1, (NOR_CMD_2, 0x55)) => Unlocked2,
+            (Unlocked2, (NOR_CMD_1, 0x90)) => Autoselect,
+            (Unlocked2, (NOR_CMD_1, 0xa0)) => Program,
+            (Unlocked2, (NOR_CMD_1, 0x80)) => Erase,
+            (Erase, (NOR_CMD_1, 0xaa)) => EraseUnlocked1,
+            (EraseUnlocked1, (NOR_CMD_2, 0x55)) => EraseUnlocked2,
+            (EraseUnlocked2, (_, 0x30)) => {
+                let sector = offset & !(NOR_SECTOR_SIZE - 1);
+                self.nor[sector..sector + NOR_SECTOR_SIZE].fill(0xff);
+                Read
+            }
+            // anything else is ignored, and drops the chip back to reading
+            _ => Read,
+        };
+    }
+
+    fn nor_read(&mut self, offset: usize) -> u16 {
+        match (self.nor_state, offset & 0xffe) {
+            (NorState::Autoselect, 0) => NOR_ID.0,
+            (NorState::Autoselect, 2) => NOR_ID.1,
+            // everything finishes instantly, so there's never a status to read
+            _ => u16::from_le_bytes([self.nor[offset & !1], self.nor[offset | 1]]),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn clock(&self) -> [u8; DATE_TIME_LEN] {
+        self.clock.date_time
+    }
+
+    fn gpio_write(&mut self, addr: usize, value: u16) {
+        match addr {
+            GPIO_DIRECTION => self.gpio_direction = value,
+            GPIO_CONTROL => self.gpio_control = value,
+            GPIO_DATA => {
+                let (old, new) = (self.gpio_pins, value & self.gpio_direction);
+                self.gpio_pins = new;
+                if new & CS == 0 {
+                    self.clock.select();
+                } else if old & CS == 0 {
+                    // only just selected, so this isn't a clock edge
+                } else if old & SCK == 0 && new & SCK != 0 {
+                    self.clock.rising(new & SIO != 0);
+                } else if old & SCK != 0 && new & SCK == 0 {
+                    self.clock.falling();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn sram_range(&self, addr: usize, len: usize) -> core::ops::Range<usize> {
+        assert!(
+            addr >= SRAM_WINDOW && addr + len <= SRAM_WINDOW + SRAM_WINDOW_SIZE,
+            "sram access outside the window"
+        );
+
+        let start = self.rampage as usize * SRAM_WINDOW_SIZE + addr - SRAM_WINDOW;
+        start..start + len
+    }
+
+    fn apply(&mut self, addr: usize, value: u16) {
+        match addr {
+            ROMPAGE => self.rompage = value,
+            PSRAMPAGE => self.psrampage = value,
+            RAMPAGE => self.rampage = value,
+            LED_CTRL => self.led = value,
+            RTC_CTRL => self.rtc = value,
+            SD_CTRL => {
+                self.sd_control = value;
+                self.sd_buf_mode = if value == SdControl::ReadState as u16 {
+                    SdBufMode::ReadState
+                } else {
+                    SdBufMode::Data
+                };
+            }
+            SD_ADDR_L => self.sd_lba = (self.sd_lba & 0xffff0000) | value as Lba,
+            SD_ADDR_H => self.sd_lba = (self.sd_lba & 0xffff) | (value as Lba) << 16,
+            SD_BLOCKS => self.transfer(value),
+            _ => panic!("write to unknown register {:#x}", addr),
+        }
+    }
+
+    fn transfer(&mut self, blocks: u16) {
+        assert_eq!(self.rompage, ROMPAGE_OS, "sd transfer outside OS mode");
+        assert_ne!(
+            self.sd_control,
+            SdControl::Disable as u16,
+            "sd transfer with the card disabled"
+        );
+
+        let count = (blocks & 0x7fff) as usize;
+        assert!((1..=4).contains(&count), "bad block count {}", count);
+
+        let data = &mut self.sd_buf[..count * 512];
+        if blocks & 0x8000 != 0 {
+            self.sd_ok = self.disk.write_blocks(self.sd_lba, data).is_ok();
+            self.sd_buf_mode = SdBufMode::WriteState;
+        } else {
+            self.sd_ok = self.disk.read_blocks(self.sd_lba, data).is_ok();
+        }
+    }
+}
+
+impl<D: BlockIo<512>> Bus for Simulated<D> {
+    unsafe fn write(&mut self, addr: usize, value: u16) {
+        if self.unlock < UNLOCK.len() {
+            if (addr, value) == UNLOCK[self.unlock] {
+                self.unlock += 1;
+            } else {
+                // anything else is just a write to rom, and breaks the sequence
+                self.unlock = ((addr, value) == UNLOCK[0]) as usize;
+                if self.rtc != 0 && (GPIO_DATA..=GPIO_CONTROL).contains(&addr) {
+                    self.gpio_write(addr, value);
+                } else if let Some(offset) = self.nor_offset(addr) {
+                    self.nor_write(offset, value);
+                }
+            }
+        } else if (addr, value) == FINISH {
+            for i in 0..self.pending_len {
+                let (addr, value) = self.pending[i];
+                self.apply(addr, value);
+            }
+            self.unlock = 0;
+            self.pending_len = 0;
+        } else {
+            self.pending[self.pending_len] = (addr, value);
+            self.pending_len += 1;
+        }
+    }
+
+    unsafe fn read(&mut self, addr: usize) -> u16 {
+        if self.rtc != 0 && self.gpio_control != 0 && addr == GPIO_DATA {
+            let sio = if self.gpio_direction & SIO == 0 && self.clock.sio {
+                SIO
+            } else {
+                0
+            };
+            return self.gpio_pins | sio;
+        }
+        if let Some(offset) = self.nor_offset(addr) {
+            return self.nor_read(offset);
+        }
+
+        match (addr, self.sd_buf_mode) {
+            (SD_BUF, SdBufMode::ReadState) if !self.sd_ok => 0xeee1,
+            (SD_BUF, SdBufMode::ReadState) => 0,
+            (SD_BUF, SdBufMode::WriteState) => !self.sd_ok as u16,
+            (SD_BUF, SdBufMode::Data) => u16::from_le_bytes([self.sd_buf[0], self.sd_buf[1]]),
+            _ => 0,
+        }
+    }
+
+    unsafe fn read_sd_buffer(&mut self, dst: &mut [u8]) {
+        assert_eq!(
+            self.sd_buf_mode,
+            SdBufMode::Data,
+            "sd buffer isn't readable"
+        );
+        dst.copy_from_slice(&self.sd_buf[..dst.len()]);
+    }
+
+    unsafe fn write_sd_buffer(&mut self, src: &[u8]) {
+        self.sd_buf[..src.len()].copy_from_slice(src);
+    }
+
+    unsafe fn write_psram(&mut self, addr: usize, src: &[u8]) {
+        assert_eq!(self.rompage, ROMPAGE_OS, "psram write outside OS mode");
+        assert!(src.len() <= 0x10000, "psram write too long for one dma");
+        assert!(
+            addr >= PSRAM_WINDOW && addr + src.len() <= PSRAM_WINDOW + PSRAM_WINDOW_SIZE,
+            "psram write outside the window"
+        );
+
+        let start = ((self.psrampage as usize) << PSRAM_PAGE_SHIFT) + addr - PSRAM_WINDOW;
+        self.psram[start..start + src.len()].copy_from_slice(src);
+    }
+
+    unsafe fn read_psram(&mut self, addr: usize, dst: &mut [u8]) {
+        assert_eq!(self.rompage, ROMPAGE_OS, "psram read outside OS mode");
+        assert!(dst.len() <= 0x10000, "psram read too long for one dma");
+        assert!(
+            addr >= PSRAM_WINDOW && addr + dst.len() <= PSRAM_WINDOW + PSRAM_WINDOW_SIZE,
+            "psram read outside the window"
+        );
+
+        let start = ((self.psrampage as usize) << PSRAM_PAGE_SHIFT) + addr - PSRAM_WINDOW;
+        dst.copy_from_slice(&self.psram[start..start + dst.len()]);
+    }
+
+    unsafe fn read_sram(&mut self, addr: usize, dst: &mut [u8]) {
+        let range = self.sram_range(addr, dst.len());
+        dst.copy_from_slice(&self.sram[range]);
+    }
+
+    unsafe fn write_sram(&mut self, addr: usize, src: &[u8]) {
+        let range = self.sram_range(addr, src.len());
+        self.sram[range].copy_from_slice(src);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::Simulated;
+    use crate::disk::RamDisk;
+    use crate::ezflash::{
+        erase_nor, program_nor, read_nor, read_psram, verify_nor, write_psram, NorError,
+        NOR_SECTOR_SIZE, NOR_WINDOW_SIZE, PSRAM_WINDOW_SIZE, ROMPAGE_PSRAM,
+    };
+    use crate::rtc::{DateTime, Rtc, RtcError};
+    use crate::sd::{BlockIo, BlockIoError, SdCard};
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 + i / 509) as u8 ^ seed).collect()
+    }
+
+    /// a u16 buffer as bytes, which is always halfword aligned
+    fn as_bytes(buffer: &mut [u16]) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr().cast(), buffer.len() * 2) }
+    }
+
+    #[test]
+    fn sd_round_trip() {
+        let mut data = vec![0; 16 * 512];
+        let mut card = SdCard::new(Simulated::new(RamDisk::new(&mut data)));
+
+        // more than two chunks' worth, so the last one is short
+        let written = pattern(9 * 512, 0x5a);
+        card.write_blocks(3, &written).unwrap();
+        let mut read = vec![0; written.len()];
+        card.read_blocks(3, &mut read).unwrap();
+        assert!(read == written);
+        assert_eq!(card.bus().rompage, ROMPAGE_PSRAM);
+
+        let mut block = vec![0; 512];
+        card.bus().disk().read_blocks(11, &mut block).unwrap();
+        assert!(block == written[8 * 512..]);
+        card.bus().disk().read_blocks(12, &mut block).unwrap();
+        assert!(block.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn sd_errors() {
+        let mut data = vec![0; 16 * 512];
+        let mut card = SdCard::new(Simulated::new(RamDisk::new(&mut data)));
+
+        let mut buffer = vec![0u16; 513];
+        let odd = &mut as_bytes(&mut buffer)[1..513];
+        assert!(matches!(
+            card.read_blocks(0, odd),
+            Err(BlockIoError::Misaligned)
+        ));
+
+        let mut blocks = vec![0; 4 * 512];
+        assert!(matches!(
+            card.read_blocks(14, &mut blocks),
+            Err(BlockIoError::RetriesExhausted { lba: 14 })
+        ));
+    }
+
+    #[test]
+    fn rtc_set_and_read() {
+        let mut sim = Simulated::new(RamDisk::new(&mut []));
+        let time = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 45,
+            second: 30,
+        };
+
+        {
+            let rtc = Rtc::new(&mut sim);
+            assert_eq!(rtc.read(), Err(RtcError::Stopped));
+            rtc.set(&time).unwrap();
+            assert_eq!(rtc.read(), Ok(time));
+        }
+
+        // in bcd, with thursday worked out for the weekday
+        assert_eq!(sim.clock(), [0x24, 0x02, 0x29, 4, 0x13, 0x45, 0x30]);
+
+        let rtc = Rtc::new(&mut sim);
+        assert_eq!(rtc.read(), Ok(time));
+        assert_eq!(
+            rtc.set(&DateTime { month: 13, ..time }),
+            Err(RtcError::Invalid)
+        );
+    }
+
+    #[test]
+    fn psram_paging() {
+        let mut sim = Simulated::new(RamDisk::new(&mut []));
+
+        // across the end of a window, and longer than one dma
+        let offset = PSRAM_WINDOW_SIZE - 0x18000;
+        let written = pattern(0x30000, 0xa5);
+        unsafe { write_psram(&mut sim, offset, &written) };
+        assert!(sim.psram()[offset..offset + written.len()] == written);
+        assert!(sim.psram()[..offset].iter().all(|&b| b == 0));
+        assert_eq!(sim.rompage, ROMPAGE_PSRAM);
+
+        let mut read = vec![0; written.len() - 1];
+        unsafe { read_psram(&mut sim, offset + 1, &mut read) };
+        assert!(read == written[1..]);
+    }
+
+    #[test]
+    fn nor_burn() {
+        let mut sim = Simulated::new(RamDisk::new(&mut []));
+
+        // across the end of a window, with an odd byte at the end
+        let offset = NOR_WINDOW_SIZE - 0x300;
+        let written = pattern(0x501, 0x3c);
+        unsafe {
+            program_nor(&mut sim, offset - 2, &[0x12, 0x34]).unwrap();
+            erase_nor(&mut sim, offset, written.len()).unwrap();
+            program_nor(&mut sim, offset, &written).unwrap();
+            verify_nor(&mut sim, offset, &written).unwrap();
+        }
+        assert!(sim.nor()[offset..offset + written.len()] == written);
+        // the erase took the whole sector before it with it
+        let sector = offset & !(NOR_SECTOR_SIZE - 1);
+        assert!(sim.nor()[sector..offset].iter().all(|&b| b == 0xff));
+        assert_eq!(sim.rompage, ROMPAGE_PSRAM);
+
+        let mut read = vec![0; written.len() - 2];
+        unsafe { read_nor(&mut sim, offset + 2, &mut read) };
+        assert!(read == written[2..]);
+
+        // writing only clears bits, so setting them back takes an erase
+        let mut changed = written.clone();
+        changed[0x400] = 0xff;
+        unsafe {
+            assert!(matches!(
+                program_nor(&mut sim, offset, &changed),
+                Err(NorError::Timeout { offset: at }) if at == offset + 0x400
+            ));
+            assert!(matches!(
+                verify_nor(&mut sim, offset, &changed),
+                Err(NorError::Mismatch { offset: at }) if at == offset + 0x400
+            ));
+        }
+    }
+}